@@ -1,15 +1,108 @@
 /// All functions related to user input / user output
 mod menu;
 
+/// Upgrades of saved states written by older versions of the application
+mod migrations;
+
 /// The business login of the application
 mod model;
 
+/// Summaries computed from the state of the application
+mod reports;
+
 /// How to pretty print classes to the user. Used in `menu`
 mod view;
 
+/// The options given on the command line
+struct Args {
+    /// The data file given with `--file <path>` or the `CORONA_FILE` environment variable, the
+    /// argument wins when both are given
+    file: Option<String>,
+    /// Whether `--force-new` was given to start a new store when the data file cannot be loaded
+    force_new: bool,
+}
+
+/// Read the options given on the command line
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut file = None;
+    let mut force_new = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => match args.next() {
+                Some(path) => file = Some(path),
+                None => return Err("--file needs a path".to_owned()),
+            },
+            "--force-new" => force_new = true,
+            _ => match arg.strip_prefix("--file=") {
+                Some(path) => file = Some(path.to_owned()),
+                None => return Err(format!("unknown argument {arg}")),
+            },
+        }
+    }
+    Ok(Args {
+        file: file.or_else(|| std::env::var("CORONA_FILE").ok()),
+        force_new,
+    })
+}
+
 /// Entry point of the application
+///
+/// The state is kept in `corona.toml` in the current directory unless another file is given,
+/// see [`Args`], with the users, the catalog and the orders in their own files next to it, like
+/// `corona.users.toml`. If that file exists but cannot be loaded the application stops instead
+/// of starting an empty store that would replace it, unless `--force-new` is given. Diagnostics
+/// go to stderr through `log`, filtered by the `RUST_LOG` environment variable, only errors are
+/// shown when it is not set.
+///
+/// Ctrl-C would kill the process without saving. The menus own the application while they run,
+/// so the Ctrl-C handler cannot reach it. Instead, the menus checkpoint the state before every
+/// command and the handler saves the last checkpoint before exiting.
 fn main() {
-    let mut app = model::CoronaApplication::load();
+    env_logger::init();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            eprintln!("Usage: corona [--file <path>] [--force-new]");
+            std::process::exit(2);
+        }
+    };
+    if let Some(path) = args.file {
+        model::set_data_file(path);
+    }
+    let path = model::data_file();
+    if model::data_exists() {
+        println!("Loading data from {path}");
+    } else {
+        println!("No data file at {path}, starting with an empty store");
+    }
+    let mut app = match model::CoronaApplication::load() {
+        Ok(app) => app,
+        Err(err) if args.force_new => {
+            println!("Could not load {path}, {err}");
+            println!("Starting with an empty store, the old file is kept as {path}.bak on save.");
+            model::CoronaApplication::new_store()
+        }
+        Err(err) => {
+            eprintln!("Error: could not load {path}, {err}");
+            eprintln!("Fix the file, or start with an empty store with --force-new.");
+            std::process::exit(1);
+        }
+    };
+    let handler = ctrlc::set_handler(|| {
+        // Ctrl-C while a password is typed would leave the terminal without echo
+        menu::set_echo(true);
+        if let Err(err) = model::CoronaApplication::save_checkpoint() {
+            println!("Failed to save, {err}.");
+        }
+        std::process::exit(130);
+    });
+    if handler.is_err() {
+        println!("Ctrl-C will not save the application.");
+    }
     menu::main(&mut app);
-    app.save();
+    if let Err(err) = app.save() {
+        println!("Failed to save, {err}.");
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{model::*, view::*};
+use crate::{model::*, reports, view::*};
 use std::{io::Write, str::FromStr};
 
 /// Reads a line from stdin with the given prompt
@@ -10,18 +10,108 @@ use std::{io::Write, str::FromStr};
 /// let line = read_line("Name: ");
 /// ```
 fn read_line(prompt: &str) -> String {
+    read_line_timeout(prompt, None).unwrap_or_default()
+}
+
+/// Reads a password from stdin with the given prompt without showing what is typed
+///
+/// Showing typed characters is turned off with `stty`, which only works in a terminal. When
+/// stdin is not a terminal, like when commands are piped in, the password is read like any
+/// other line and a warning is printed once.
+fn read_password(prompt: &str) -> String {
+    use std::io::IsTerminal;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    if !std::io::stdin().is_terminal() || !set_echo(false) {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            eprintln!("Warning: input is not a terminal, passwords will not be hidden.");
+        }
+        return read_line(prompt);
+    }
+    let password = read_line(prompt);
+    set_echo(true);
+    println!();
+    password
+}
+
+/// Turns showing typed characters in the terminal on or off, returns false if it failed
+pub(crate) fn set_echo(on: bool) -> bool {
+    std::process::Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Reads a line from stdin with the given prompt, giving up after `timeout`
+///
+/// Reading from stdin blocks and cannot be interrupted, so a single reader thread reads all lines
+/// and sends them over a channel. Waiting for a line is then just `recv_timeout` on the channel.
+/// A line that is typed after a timeout is not lost, it is returned by the next read.
+///
+/// Returns `None` if no line was entered in time. `None` as timeout waits forever. At the end of
+/// stdin an empty line is returned, like [`read_line`] always did.
+fn read_line_timeout(prompt: &str, timeout: Option<std::time::Duration>) -> Option<String> {
+    use std::sync::{mpsc, Mutex, OnceLock};
+
+    static LINES: OnceLock<Mutex<mpsc::Receiver<String>>> = OnceLock::new();
+
     print!("{prompt}");
     std::io::stdout().flush().ok();
 
-    let mut line = String::new();
-    std::io::stdin().read_line(&mut line).ok();
-    line.pop();
-    line
+    let lines = LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || loop {
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    line.pop();
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Mutex::new(receiver)
+    });
+    let lines = lines.lock().unwrap();
+    let line = match timeout {
+        Some(timeout) => lines.recv_timeout(timeout),
+        None => lines
+            .recv()
+            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+    };
+    match line {
+        Ok(line) => Some(line),
+        Err(mpsc::RecvTimeoutError::Timeout) => None,
+        Err(mpsc::RecvTimeoutError::Disconnected) => Some(String::new()),
+    }
+}
+
+/// Asks the user a yes or no question until they answer with one of them
+///
+/// The following example asks the user whether to continue
+/// ```rust
+/// if read_yes_no("Continue? (y/n): ") { ... }
+/// ```
+fn read_yes_no(prompt: &str) -> bool {
+    loop {
+        match read_line(prompt).trim() {
+            "y" | "yes" => break true,
+            "n" | "no" => break false,
+            _ => {}
+        }
+    }
 }
 
 /// Reads a line from stdin and parses it to type `T` with the given prompt
 ///
-/// If parsing fails the prompt is repeated until a valid value is passed in.
+/// If parsing fails a message saying what is expected is printed and the prompt is repeated
+/// until a valid value is passed in.
 ///
 /// The following example asks the user for their age
 /// ```rust
@@ -29,24 +119,55 @@ fn read_line(prompt: &str) -> String {
 /// ```
 fn read_value<T: FromStr>(prompt: &str) -> T {
     loop {
-        if let Ok(result) = read_line(prompt).parse() {
+        if let Ok(result) = read_line(prompt).trim().parse() {
             break result;
         }
+        println!("{}", invalid_value_message::<T>());
+    }
+}
+
+/// The message printed when a value of type `T` typed by the user cannot be parsed
+fn invalid_value_message<T>() -> &'static str {
+    match std::any::type_name::<T>() {
+        "f32" | "f64" => "Invalid input, please enter a valid number.",
+        "u8" | "u16" | "u32" | "u64" | "usize" => {
+            "Invalid input, please enter a whole number that is not negative."
+        }
+        "i8" | "i16" | "i32" | "i64" | "isize" => "Invalid input, please enter a whole number.",
+        _ => "Invalid input, please try again.",
+    }
+}
+
+/// Parses a command argument to type `T`.
+///
+/// If parsing fails an error naming the argument is printed and `None` is returned.
+///
+/// The following example parses the order ID of the `order deliver <id>` command
+/// ```rust
+/// let order_id: Option<u64> = parse_arg(id, "order ID");
+/// ```
+fn parse_arg<T: FromStr>(arg: &str, name: &str) -> Option<T> {
+    let result = arg.parse().ok();
+    if result.is_none() {
+        println!("Invalid {name}: {arg}");
     }
+    result
 }
 
 /// Display the register user menu.
 ///
-/// Asks the user for their username, password and email and creates the user.
+/// Asks the user for their username, password, email and phone number and creates the user.
 ///
 /// If creating the user failed an error is printed to stdout.
 fn register(user_manager: &mut UserManager) {
     let username = read_line("Username: ");
-    let password = read_line("Password: ");
+    let password = read_password("Password: ");
     let email = read_line("Email: ");
+    let phone = read_line("Phone: ");
 
-    if !user_manager.add_user(username, password, email) {
-        println!("Cannot create user.");
+    match user_manager.add_user(username.clone(), password, email, phone) {
+        Ok(()) => audit(&username, "user registered"),
+        Err(err) => println!("Cannot create user: {err}."),
     }
 }
 
@@ -59,39 +180,522 @@ fn register(user_manager: &mut UserManager) {
 /// otherwise an error is printed to stdout.
 fn login(app: &mut CoronaApplication) {
     let username = read_line("Username : ");
-    let password = read_line("Password: ");
+    let password = read_password("Password: ");
+    let last_login = app.user_manager.find(&username).and_then(User::last_login);
 
-    if let Some(user) = app.user_manager.user_login_mut(username, password) {
-        logged_in_menu(user, &mut app.catalog, &mut app.order_manager);
-    } else {
-        println!("Unautherized.");
+    match app.user_manager.user_login_mut(username.clone(), password) {
+        Ok(user) => {
+            let username = user.username().to_owned();
+            log::info!("{username} logged in as {}", user.role());
+            audit(&username, "login succeeded");
+            app.activity_log
+                .log_activity(&username, ActivityKind::Login, String::new());
+            match last_login {
+                Some(last_login) => {
+                    println!(
+                        "Welcome back, last login: {}",
+                        last_login.format("%Y-%m-%d %H:%M")
+                    )
+                }
+                None => println!("Welcome, first login!"),
+            }
+            if let Some(user) = app.user_manager.find_mut(&username) {
+                if user.must_change_password() && !forced_password_change(user) {
+                    audit(&username, "logged out without changing the password");
+                    println!("You were logged out, the password must be changed to continue.");
+                    return;
+                }
+            }
+            app.expire_unpaid_orders();
+            if let Some(user) = app.user_manager.find_mut(&username) {
+                let unread = user.unread_notifications();
+                if unread > 0 {
+                    println!("You have {unread} new message(s), type inbox to read them.");
+                }
+                if user.has_role(STAFF) {
+                    report_reorder(&app.catalog, false);
+                }
+            }
+            logged_in_menu(app, &username);
+        }
+        Err(LoginError::Unauthorized) => {
+            log::warn!("failed login as {username}");
+            audit(&username, "login failed");
+            println!("Wrong username or password.");
+        }
+        Err(LoginError::TooManyAttempts) => {
+            log::warn!("login as {username} refused, the account is locked");
+            audit(&username, "login refused after too many attempts");
+            if app.user_manager.login_lock_minutes() == 0 {
+                println!("Account locked after too many failed logins, ask an admin to unlock it.");
+            } else {
+                println!(
+                    "Account temporarily locked after too many failed logins, try again later."
+                );
+            }
+        }
+        Err(LoginError::Deactivated) => {
+            log::warn!("login as {username} refused, the account is deactivated");
+            audit(&username, "login refused, account deactivated");
+            println!("Account disabled. Contact support.");
+        }
+    }
+}
+
+/// Display the forgot password menu.
+///
+/// Asks the user for their username and email and mails a one-time reset code if they match an
+/// account, then asks for the code and the new password. The same messages are printed whether
+/// the account exists or not, so the menu cannot be used to find out which accounts exist.
+fn forgot_password(user_manager: &mut UserManager, mailer: &dyn Mailer) {
+    let username = read_line("Username: ");
+    let email = read_line("Email: ");
+    if let Some(code) = user_manager.request_password_reset(&username, &email) {
+        let body = format!("Your reset code is {code}.");
+        if let Err(err) = mailer.send(&email, "Password reset", &body) {
+            log::error!("could not mail the reset code of {username}: {err}");
+        }
+    }
+    println!("If the username and email match an account, a reset code was sent to the email.");
+    reset_password(user_manager, &username);
+}
+
+/// Display the reset password menu, for users who got a reset code from an admin.
+fn reset_password_menu(user_manager: &mut UserManager) {
+    let username = read_line("Username: ");
+    reset_password(user_manager, &username);
+}
+
+/// Asks for a reset code and a new password and resets the password of the user.
+///
+/// The same message is printed for a wrong code, an expired code and an unknown user.
+fn reset_password(user_manager: &mut UserManager, username: &str) {
+    let code = read_password("Reset code: ");
+    let password = read_password("New password: ");
+    match user_manager.reset_password(username, &code, &password) {
+        Ok(()) => {
+            audit(username, "password reset");
+            println!("Password changed, you can login now.");
+        }
+        Err(ResetError::Password(err)) => {
+            println!("Sorry, {err}, the password was not changed.")
+        }
+        Err(ResetError::InvalidCode) => println!("Invalid or expired reset code."),
+    }
+}
+
+/// Makes a one-time reset code for a user who forgot their password and prints it for the admin
+/// to pass on.
+fn user_reset(user_manager: &mut UserManager, actor: &str, username: &str) {
+    match user_manager.issue_reset_code(username) {
+        Some(code) => {
+            audit(actor, &format!("password reset code issued for {username}"));
+            println!(
+                "Reset code for {username}: {code} (valid for {} hours)",
+                UserManager::RESET_CODE_HOURS
+            );
+            println!("They can set a new password with the reset command before logging in.");
+        }
+        None => println!("User not found."),
+    }
+}
+
+/// Sets a new password for a user, for example one who is locked out, and lets them log in again.
+fn user_reset_password(user_manager: &mut UserManager, actor: &str, username: &str) {
+    let Some(user) = user_manager.find_mut(username) else {
+        println!("User {username} does not exist.");
+        return;
+    };
+    let password = read_password(&format!("New password for {username}: "));
+    if read_password("Repeat new password: ") != password {
+        println!("The passwords do not match, the password was not changed.");
+        return;
+    }
+    if !read_yes_no(&format!("Set the new password of {username}? (y/n): ")) {
+        println!("The password was not changed.");
+        return;
+    }
+    if let Err(err) = user.set_password(&password) {
+        println!("Sorry, {err}, the password was not changed.");
+        return;
     }
+    user_manager.unlock(username);
+    audit(actor, &format!("password of {username} reset"));
+    println!("The password of {username} was changed, they can log in with it now.");
 }
 
 /// Asks user to add item to the catalog.
-fn product_add(catalog: &mut Catalog) {
+fn product_add(catalog: &mut Catalog, actor: &str) {
     let code = read_line("Code: ");
     let name = read_line("Name: ");
-    let unit_price = read_value("Unit price: ");
+    let unit_price = loop {
+        let unit_price = read_value("Unit price: ");
+        if is_valid_amount(unit_price) {
+            break round_money(unit_price);
+        }
+        println!("The price must be positive.");
+    };
+    let unit = read_line("Unit (kg, each, litre, ...; leave blank for each): ");
+    let weight_grams = loop {
+        let weight = read_line("Weight of one unit in grams (leave blank for none): ");
+        if weight.trim().is_empty() {
+            break None;
+        }
+        match weight.trim().parse() {
+            Ok(weight) => break Some(weight),
+            Err(_) => println!("Invalid weight: {weight}"),
+        }
+    };
+    let image_url = loop {
+        let url = read_line("Image URL (leave blank for none): ");
+        if url.trim().is_empty() {
+            break None;
+        }
+        if is_valid_url(url.trim()) {
+            break Some(url.trim().to_owned());
+        }
+        println!("Invalid URL, it must start with http:// or https://.");
+    };
+    let barcode = loop {
+        let barcode = read_line("Barcode (leave blank for none): ");
+        let barcode = barcode.trim();
+        if barcode.is_empty() {
+            break None;
+        }
+        match catalog.find(barcode) {
+            Some(product) => println!("This barcode is already used by [{}].", product.code()),
+            None => break Some(barcode.to_owned()),
+        }
+    };
+    let stock = loop {
+        let stock = read_line("Stock (leave blank to not track it): ");
+        if stock.trim().is_empty() {
+            break None;
+        }
+        match stock.trim().parse() {
+            Ok(stock) => break Some(stock),
+            Err(_) => println!("Invalid stock: {stock}"),
+        }
+    };
 
-    catalog.add_product(Product::new(code, name, unit_price));
+    let mut product = Product::new(code, name, unit_price);
+    if !unit.trim().is_empty() {
+        product.set_unit(unit.trim().to_owned());
+    }
+    product.set_weight_grams(weight_grams);
+    product.set_image_url(image_url);
+    product.set_barcode(barcode);
+    product.set_stock(stock);
+    audit(actor, &format!("product added [{}]", product.code()));
+    catalog.add_product(product);
 }
 
 /// Asks user to remove item from the catalog.
-fn product_remove(catalog: &mut Catalog) {
+fn product_remove(catalog: &mut Catalog, actor: &str) {
     let code = read_line("Code: ");
-    catalog.remove_product(&code);
+    if catalog.remove_product(&code) {
+        audit(actor, &format!("product removed [{code}]"));
+    }
+}
+
+/// Adds to the stock of a product, or sets it with `--set`, and prints the new stock.
+fn product_restock(catalog: &mut Catalog, actor: &str, code: &str, quantity: &str, set: bool) {
+    let Some(quantity) = parse_arg::<i64>(quantity, "quantity") else {
+        return;
+    };
+    match catalog.restock(code, quantity, set) {
+        Ok(stock) => {
+            audit(actor, &format!("product [{code}] restocked to {stock}"));
+            println!("Stock of [{code}]: {stock}");
+        }
+        Err(err) => println!("Sorry, {err}."),
+    }
+}
+
+/// Marks a product as exempt from tax or not.
+fn product_tax_exempt(catalog: &mut Catalog, actor: &str, code: &str, exempt: &str) {
+    let exempt = match exempt {
+        "yes" | "on" => true,
+        "no" | "off" => false,
+        _ => {
+            println!("Say yes or no.");
+            return;
+        }
+    };
+    if !catalog.set_tax_exempt(code, exempt) {
+        println!("No such product.");
+        return;
+    }
+    let state = if exempt { "exempt" } else { "taxable" };
+    audit(actor, &format!("product [{code}] made tax {state}"));
+    println!("[{code}] is now tax {state}.");
+}
+
+/// Changes the unit price of a product, the old price is kept in its price history.
+fn product_price(catalog: &mut Catalog, actor: &str, code: &str, price: &str) {
+    let Some(price) = parse_arg::<f64>(price, "price") else {
+        return;
+    };
+    if !is_valid_amount(price) {
+        println!("The price must be positive.");
+        return;
+    }
+    let price = round_money(price);
+    match catalog.set_price(code, price) {
+        Some(old) => {
+            audit(
+                actor,
+                &format!(
+                    "product [{code}] price changed from {} to {}",
+                    format_money(old),
+                    format_money(price)
+                ),
+            );
+            println!(
+                "[{code}] now costs {} instead of {}.",
+                format_price(price),
+                format_price(old)
+            );
+        }
+        None => println!("No such product."),
+    }
+}
+
+/// Highlights a product at the top of the catalog or stops doing so.
+fn product_feature(catalog: &mut Catalog, actor: &str, code: &str, featured: bool) {
+    if !catalog.set_featured(code, featured) {
+        println!("No such product.");
+        return;
+    }
+    if featured {
+        audit(actor, &format!("product [{code}] featured"));
+        println!("[{code}] is shown at the top of the catalog.");
+    } else {
+        audit(actor, &format!("product [{code}] no longer featured"));
+        println!("[{code}] is no longer featured.");
+    }
+}
+
+/// Prints the orders created between two days given as YYYY-MM-DD, both included.
+fn order_list_between(order_manager: &OrderManager, from: &str, to: &str) {
+    let (Some(from), Some(to)) = (
+        parse_arg::<chrono::NaiveDate>(from, "date (use YYYY-MM-DD)"),
+        parse_arg::<chrono::NaiveDate>(to, "date (use YYYY-MM-DD)"),
+    ) else {
+        return;
+    };
+    if from > to {
+        println!("The first date {from} is after the last date {to}.");
+        return;
+    }
+    let orders = order_manager.orders_between(from, to);
+    if orders.is_empty() {
+        println!("No orders were made from {from} to {to}.");
+        return;
+    }
+    println!("{} order(s) from {from} to {to}:", orders.len());
+    orders.into_iter().for_each(View::view);
+}
+
+/// Prints the taxable and tax exempt subtotals of the items of all paid orders.
+fn report_tax(order_manager: &OrderManager) {
+    let (taxable, exempt) = order_manager
+        .orders()
+        .iter()
+        .filter(|order| order.is_paid())
+        .map(Order::tax_subtotals)
+        .fold(
+            (0.0, 0.0),
+            |(taxable, exempt), (order_taxable, order_exempt)| {
+                (taxable + order_taxable, exempt + order_exempt)
+            },
+        );
+    println!("Items of paid orders, before discounts and fees:");
+    println!("  taxable: {}", format_price(taxable));
+    println!("  exempt: {}", format_price(exempt));
+}
+
+/// Sets or clears the stock at which a product should be ordered again.
+fn product_reorder_point(catalog: &mut Catalog, actor: &str, code: &str, point: &str) {
+    let point = match point {
+        "none" => None,
+        point => match parse_arg(point, "reorder point") {
+            Some(point) => Some(point),
+            None => return,
+        },
+    };
+    if !catalog.set_reorder_point(code, point) {
+        println!("No such product.");
+        return;
+    }
+    match point {
+        Some(point) => {
+            audit(
+                actor,
+                &format!("product [{code}] reorder point set to {point}"),
+            );
+            println!("[{code}] should be reordered at a stock of {point}.");
+        }
+        None => {
+            audit(actor, &format!("product [{code}] reorder point cleared"));
+            println!("[{code}] has no reorder point.");
+        }
+    }
+}
+
+/// Prints the products at or below their reorder point, out of stock ones first, each group
+/// sorted by how far below the point they are.
+///
+/// Prints nothing when no product needs to be reordered, unless `always` is set.
+fn report_reorder(catalog: &Catalog, always: bool) {
+    let products = catalog.to_reorder();
+    if products.is_empty() {
+        if always {
+            println!("No product needs to be reordered.");
+        }
+        return;
+    }
+    let (out_of_stock, low): (Vec<_>, Vec<_>) = products
+        .into_iter()
+        .partition(|(product, _)| product.is_out_of_stock());
+    println!("Products to reorder:");
+    for (title, group) in [("Out of stock", out_of_stock), ("Low on stock", low)] {
+        if group.is_empty() {
+            continue;
+        }
+        println!("  {title}:");
+        for (product, below) in group {
+            println!(
+                "    [{}] {}: {} left, {below} below the reorder point of {}",
+                product.code(),
+                product.name(),
+                product.stock().unwrap_or_default(),
+                product.reorder_point().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// Prints the products that have at most the given stock left, 5 unless given.
+fn report_stock(catalog: &Catalog, threshold: Option<&str>) {
+    let threshold = match threshold {
+        Some(threshold) => match parse_arg(threshold, "stock threshold") {
+            Some(threshold) => threshold,
+            None => return,
+        },
+        None => 5,
+    };
+    println!("Products with {threshold} or less in stock:");
+    for product in catalog.low_stock(threshold) {
+        println!(
+            "  [{}] {}: {}",
+            product.code(),
+            product.name(),
+            product.stock().unwrap_or_default()
+        );
+    }
+}
+
+/// Shows the products of the catalog with a unit price between the bounds, either can be left
+/// out.
+fn product_list_by_price(catalog: &Catalog, min: Option<&str>, max: Option<&str>) {
+    let parse_bound = |bound: Option<&str>| match bound {
+        Some(bound) => parse_arg::<f64>(bound, "price").map(Some),
+        None => Some(None),
+    };
+    let (Some(min), Some(max)) = (parse_bound(min), parse_bound(max)) else {
+        return;
+    };
+    let products = catalog.products_by_price(min, max);
+    if products.is_empty() {
+        println!("No products in this price range.");
+        return;
+    }
+    print_catalog_products(catalog, &products);
+}
+
+/// Shows the catalog followed by the discontinued products.
+fn product_list_all(catalog: &Catalog) {
+    catalog.view();
+    println!("Discontinued:");
+    for product in catalog.discontinued_products() {
+        println!("  [{}] {}", product.code(), product.name());
+    }
+}
+
+/// Shows all details of a product of the catalog.
+fn product_show(catalog: &Catalog, code: &str) {
+    match catalog.find(code) {
+        Some(product) => product.view(),
+        None => println!("No such product."),
+    }
+}
+
+/// Prints two products next to each other, either can be given by code or barcode.
+fn product_compare(catalog: &Catalog, first: &str, second: &str) {
+    match (catalog.find(first), catalog.find(second)) {
+        (Some(first), Some(second)) => print_product_comparison(first, second),
+        (None, _) => println!("No such product: {first}"),
+        (_, None) => println!("No such product: {second}"),
+    }
 }
 
 /// Asks user to add item to the cart.
-fn cart_add(user: &mut User, catalog: &mut Catalog) {
-    let item_index: usize = read_value("Item Index: ");
-    if let Some(product) = catalog.products().get(item_index - 1) {
-        let quantity = read_value("Quntity: ");
-        user.cart_mut().add_item(product, quantity);
-        println!("Item added to cart.");
+///
+/// The item, either its index in the catalog, its product code or its barcode, and the quantity
+/// can be given as arguments like `cart add 2 3` or `cart add A1 3`, otherwise they are asked.
+/// Barcodes are looked up before indexes since they are often made of digits too. The quantity
+/// is capped to what is left in stock.
+fn cart_add(user: &mut User, catalog: &mut Catalog, item: Option<&str>, quantity: Option<&str>) {
+    let product = match item {
+        Some(item) => catalog
+            .product_by_barcode(item)
+            .or_else(|| match item.parse::<usize>() {
+                Ok(item_index) => item_index
+                    .checked_sub(1)
+                    .and_then(|i| catalog.products().get(i).copied()),
+                Err(_) => catalog.product(item),
+            }),
+        None => {
+            let item_index: usize = read_value("Item Index: ");
+            item_index
+                .checked_sub(1)
+                .and_then(|i| catalog.products().get(i).copied())
+        }
+    };
+    if let Some(product) = product {
+        let available = catalog.available(product.code(), user.cart());
+        if product.is_out_of_stock() {
+            println!("Sorry, {} is out of stock.", product.name());
+            return;
+        }
+        if available <= 0.0 {
+            println!(
+                "Sorry, all the stock of {} is already in your cart.",
+                product.name()
+            );
+            return;
+        }
+        let mut quantity: f64 = match quantity {
+            Some(quantity) => match parse_arg(quantity, "quantity") {
+                Some(quantity) => quantity,
+                None => return,
+            },
+            None => read_value("Quntity: "),
+        };
+        if quantity > available {
+            println!("Only {available} more available, adding {available}.");
+            quantity = available;
+        }
+        match user
+            .cart_mut()
+            .add_item(product, quantity, catalog.max_line_quantity())
+        {
+            Ok(()) => println!("Item added to cart."),
+            Err(err) => println!("Sorry, {err}."),
+        }
     } else {
-        println!("Sorry, there is no item with this index.");
+        println!("Sorry, there is no item with this index or code.");
     }
 }
 
@@ -101,113 +705,2772 @@ fn cart_remove(user: &mut User) {
     user.cart_mut().remove_item(&code);
 }
 
-/// Display options to create an order.
-fn checkout(user: &mut User, order_manager: &mut OrderManager) {
-    let delivery_address = read_line("Delivery address: ");
-    order_manager.checkout(user, delivery_address).view();
+/// Shows the cart of any user with the product codes, so an admin can help them fix it.
+fn cart_show_of(user_manager: &UserManager, username: &str) {
+    let Some(user) = user_manager.find(username) else {
+        println!("User not found.");
+        return;
+    };
+    println!("Cart of {}:", user.username());
+    for item in user.cart().iter() {
+        println!(
+            "  {}x [{}] {} - {}",
+            item.quantity(),
+            item.code(),
+            item.name(),
+            format_price(item.total_price())
+        );
+    }
+    println!("Total: {}", format_price(user.cart().total_price()));
 }
 
-/// Asks user for how to pay and closes order.
-fn pay(user: &User, order_manager: &mut OrderManager) {
-    let order_id = read_value("Order ID: ");
-    if let Some(order) = order_manager
-        .orders_mut()
-        .iter_mut()
-        .find(move |order| order.order_id() == order_id && order.username() == user.username())
-    {
-        order.view();
-        let total_price = order.total_price();
-        let payment = match read_line("Payment method: ").as_str() {
-            "cash" | "pay on delivery" => {
-                let amount: f64 = read_value("Amount: ");
-                if amount < total_price {
-                    println!("Sorry, not enough money.");
-                    return;
-                }
-                if amount > total_price {
-                    println!("Return: {:.2} EGP", amount - total_price);
-                }
-                OrderPayment::Cash
-            }
-            "credit" | "credit card" => {
-                let card_number = read_line("Card number: ");
-                if card_number.len() != 16 {
-                    println!("Sorry, card number invalid.");
-                    return;
-                };
-
-                let amount: f64 = read_value("Amount in card: ");
-                if amount < total_price {
-                    println!("Sorry, not enough money in card.");
-                    return;
-                }
+/// Removes an item from the cart of any user on behalf of an admin.
+fn cart_fix_remove(user_manager: &mut UserManager, actor: &str, username: &str, code: &str) {
+    let Some(user) = user_manager.find_mut(username) else {
+        println!("User not found.");
+        return;
+    };
+    if !user.cart().iter().any(|item| item.code() == code) {
+        println!("[{code}] is not in the cart of {}.", user.username());
+        return;
+    }
+    user.cart_mut().remove_item(code);
+    audit(
+        actor,
+        &format!("removed [{code}] from the cart of {}", user.username()),
+    );
+    println!("[{code}] removed from the cart of {}.", user.username());
+}
 
-                OrderPayment::CreditCard { card_number }
-            }
-            _ => {
-                println!("This payment method is not available. Aborting.");
-                return;
-            }
-        };
-        if order.close(payment) {
-            println!("Order payed successfully.");
-        } else {
-            println!("Order already closed.");
+/// Asks user for an optional delivery date and the part of the day to deliver in.
+///
+/// The date is asked again until it is blank (no delivery window) or a valid date that is not
+/// in the past.
+fn read_delivery_window() -> Option<DeliveryWindow> {
+    let today = chrono::Local::now().date_naive();
+    let date = loop {
+        let date = read_line("Delivery date (YYYY-MM-DD, leave blank for any): ");
+        if date.trim().is_empty() {
+            return None;
+        }
+        match date.trim().parse() {
+            Ok(date) if date >= today => break date,
+            Ok(_) => println!("The delivery date cannot be in the past."),
+            Err(_) => println!("Invalid date: {date}"),
         }
+    };
+    let slot = read_value("Delivery slot (morning/afternoon/evening): ");
+    DeliveryWindow::new(date, slot, today)
+}
+
+/// Marks a product of the catalog as favorite.
+fn favorite_add(user: &mut User, catalog: &Catalog, code: &str) {
+    if catalog.product(code).is_none() {
+        println!("Sorry, there is no product with this code.");
+    } else if user.add_favorite(code) {
+        println!("Product added to favorites.");
     } else {
-        println!("Order not found. Aborting.");
+        println!("Product is already a favorite.");
     }
 }
 
-/// Lists orders for current user.
-fn list_orders_for_user(order_manager: &OrderManager, user: &User) {
-    order_manager
-        .orders()
-        .iter()
-        .filter(|order| order.username() == user.username())
-        .for_each(View::view);
+/// Lists the favorites of the user with their current name and price.
+fn list_favorites(user: &User, catalog: &Catalog) {
+    println!("Favorites:");
+    for code in user.favorites() {
+        match catalog.product(code) {
+            Some(product) => println!(
+                "  [{code}] {} - {}",
+                product.name(),
+                format_price(product.unit_price())
+            ),
+            None => println!("  [{code}] (no longer available)"),
+        }
+    }
 }
 
-/// Menu for logged in users.
-fn logged_in_menu(user: &mut User, catalog: &mut Catalog, order_manager: &mut OrderManager) {
-    let prompt = format!("({}) >>> ", user.username());
+/// Adds all favorites of the user to the cart.
+fn order_favorites(user: &mut User, catalog: &Catalog) {
+    for code in user.add_favorites_to_cart(catalog) {
+        println!("Skipped [{code}]: no longer available or out of stock.");
+    }
+    user.cart().view();
+}
+
+/// Asks user how many of their loyalty points to redeem on the order of their cart.
+///
+/// The question is asked again until the answer is blank (no points) or a number of points the
+/// user can redeem.
+fn read_points_to_redeem(user: &User) -> u64 {
+    let max_points = user.max_redeemable_points();
+    if max_points == 0 {
+        return 0;
+    }
+    let prompt = format!(
+        "Redeem points ({} available, {POINTS_PER_EGP} points = {}, at most {max_points}): ",
+        user.points(),
+        format_price(1.0)
+    );
     loop {
-        match read_line(&prompt).as_str() {
-            "product add" if user.is_admin() => product_add(catalog),
-            "product remove" if user.is_admin() => product_remove(catalog),
-            "product list" | "product ls" | "catalog" | "products" => catalog.view(),
-            "cart add" | "add" => cart_add(user, catalog),
-            "cart remove" => cart_remove(user),
-            "cart list" | "cart ls" | "cart" => user.cart().view(),
-            "order list" | "order ls" | "orders" if user.is_admin() => order_manager.view(),
-            "order list" | "order ls" | "orders" => list_orders_for_user(order_manager, user),
-            "order" | "checkout" => checkout(user, order_manager),
-            "pay" => pay(user, order_manager),
-            "q" | "quit" | "exit" | "logout" => break,
-            "" => {}
-            _ => {
-                println!("I don't understand what you are saying!!!");
-            }
+        let points = read_line(&prompt);
+        if points.trim().is_empty() {
+            break 0;
+        }
+        match points.trim().parse() {
+            Ok(points) if points <= max_points => break points,
+            Ok(_) => println!("You cannot redeem more than {max_points} points."),
+            Err(_) => println!("Invalid number of points: {points}"),
         }
     }
 }
 
-/// Menu for users not logged in.
-pub(crate) fn main(app: &mut CoronaApplication) {
+/// Prints what an order of the cart would cost, without creating the order.
+///
+/// The optional gift wrap and loyalty points are shown separately since they are chosen at
+/// checkout.
+fn quote(user: &User) {
+    if user.cart().iter().len() == 0 {
+        println!("Your cart is empty.");
+        return;
+    }
+    println!("Quote for your cart:");
+    for item in user.cart().iter() {
+        println!(
+            "  - {}x {} [{}] = {}",
+            item.quantity(),
+            item.name(),
+            item.code(),
+            format_price(item.total_price())
+        );
+    }
+    println!("{}", user.cart().quote(false, 0));
+    println!(
+        "With gift wrap: {}",
+        format_price(user.cart().quote(true, 0).total)
+    );
+    let max_points = user.max_redeemable_points();
+    if max_points > 0 {
+        println!(
+            "With {max_points} loyalty points redeemed: {}",
+            format_price(user.cart().quote(false, max_points).total)
+        );
+    }
+}
+
+/// Display options to create an order.
+///
+/// The items of the order are taken out of the stock of the catalog.
+fn checkout(
+    user: &mut User,
+    catalog: &mut Catalog,
+    order_manager: &mut OrderManager,
+    activity_log: &mut ActivityLog,
+) {
+    if order_manager.has_too_many_open_orders(user) {
+        log::debug!(
+            "checkout of {} refused, too many open orders",
+            user.username()
+        );
+        println!(
+            "You can have at most {} unpaid order(s), please pay them before ordering again.",
+            order_manager.max_open_orders()
+        );
+        return;
+    }
+    if let Err(code) = catalog.take_stock(user.cart()) {
+        log::debug!(
+            "checkout of {} refused, [{code}] is out of stock",
+            user.username()
+        );
+        println!("Not enough stock left of [{code}], please update your cart.");
+        return;
+    }
+    let delivery_address = read_delivery_address(user);
+    let notes = read_line("Notes (leave blank for none): ");
+    let notes = Some(notes).filter(|notes| !notes.trim().is_empty());
+    let delivery_window = read_delivery_window();
+    let gift_wrap = read_yes_no(&format!(
+        "Gift wrap for {}? (y/n): ",
+        format_price(Order::GIFT_WRAP_FEE)
+    ));
+    let gift_message = read_line("Gift message (leave blank for none): ");
+    let gift_message = Some(gift_message).filter(|message| !message.trim().is_empty());
+    let points_redeemed = read_points_to_redeem(user);
+
+    let details = CheckoutDetails {
+        delivery_address,
+        notes,
+        delivery_window,
+        gift_wrap,
+        gift_message,
+        points_redeemed,
+    };
+    let order = order_manager.checkout(user, details);
+    log::info!(
+        "{} checked out order #{} for {}",
+        user.username(),
+        order.order_id(),
+        format_price(order.total_price())
+    );
+    audit(
+        user.username(),
+        &format!("order #{} created", order.order_id()),
+    );
+    activity_log.log_activity(
+        user.username(),
+        ActivityKind::Checkout,
+        format!(
+            "order #{} for {}",
+            order.order_id(),
+            format_price(order.total_price())
+        ),
+    );
+    order.view();
+    print_suggestions(catalog, order);
+}
+
+/// Prints a few other products of the catalog the user might also like after an order.
+///
+/// Nothing is printed for small catalogs, where the user has already seen every product.
+fn print_suggestions(catalog: &Catalog, order: &Order) {
+    const MIN_CATALOG_SIZE: usize = 5;
+    const SUGGESTIONS: usize = 3;
+
+    if catalog.products().len() < MIN_CATALOG_SIZE {
+        return;
+    }
+    let bought: Vec<&str> = order.items().iter().map(OrderItem::code).collect();
+    let suggestions = catalog.suggestions(&bought, SUGGESTIONS);
+    if suggestions.is_empty() {
+        return;
+    }
+    println!("You might also like:");
+    for product in suggestions {
+        println!(
+            "  - {} [{}] {}",
+            product.name(),
+            product.code(),
+            format_price(product.unit_price())
+        );
+    }
+}
+
+/// Asks user to pick one of their saved addresses or to type a new one.
+///
+/// The choice is asked again until it is a valid index.
+fn read_delivery_address(user: &User) -> String {
+    if user.addresses().is_empty() {
+        return read_line("Delivery address: ");
+    }
+    list_addresses(user);
     loop {
-        match read_line(">>> ").as_str() {
-            "register" => register(&mut app.user_manager),
-            "login" => login(app),
-            "save" => {
-                if app.save().is_none() {
-                    println!("Failed to save.");
-                }
-            }
-            "q" | "quit" | "exit" => break,
-            "" => {}
-            _ => {
-                println!("I don't understand what you are saying!!!")
-            }
+        let index = read_line("Delivery address (index from the list, 0 for another address): ");
+        let Ok(index) = index.trim().parse::<usize>() else {
+            println!("Invalid address index: {index}");
+            continue;
+        };
+        if index == 0 {
+            return read_line("Delivery address: ");
+        }
+        match user.addresses().get(index - 1) {
+            Some(address) => return address.delivery_address(),
+            None => println!("Sorry, there is no address with this index."),
+        }
+    }
+}
+
+/// Asks user for a new address and saves it in their address book.
+fn address_add(user: &mut User) {
+    let label = read_line("Label (like home or work): ");
+    let street = read_line("Street: ");
+    let city = read_line("City: ");
+    match Address::new(&label, &street, &city) {
+        Some(address) => {
+            user.add_address(address);
+            println!("Address saved.");
+        }
+        None => println!("Sorry, the label, street and city must all be filled in."),
+    }
+}
+
+/// Lists the addresses in the address book of the user.
+fn list_addresses(user: &User) {
+    println!("Addresses:");
+    for (i, address) in user.addresses().iter().enumerate() {
+        println!("{:>3}. {address}", i + 1);
+    }
+}
+
+/// Removes an address of the user by its index in the list.
+fn address_remove(user: &mut User, index: &str) {
+    let Some(index) = parse_arg::<usize>(index, "address index") else {
+        return;
+    };
+    if index > 0 && user.remove_address(index - 1) {
+        println!("Address removed.");
+    } else {
+        println!("Sorry, there is no address with this index.");
+    }
+}
+
+/// Asks user for the number and expiry date of a card.
+///
+/// The card is asked again until it is valid and not expired. Return the normalized card number,
+/// expiry month and expiry year, or `None` if the user typed "cancel".
+fn read_card() -> Option<(String, u8, u16)> {
+    loop {
+        let card_number = read_line("Card number (or cancel): ");
+        if card_number.trim() == "cancel" {
+            return None;
+        }
+        if let Err(err) = validate_card_number(&card_number) {
+            println!("Sorry, card number invalid: {err}.");
+            continue;
+        };
+        let card_number = normalize_card_number(&card_number);
+
+        let expiry = read_line("Expiry date (MM/YY): ");
+        if expiry.trim() == "cancel" {
+            return None;
+        }
+        let Some((expiry_month, expiry_year)) = parse_card_expiry(&expiry) else {
+            println!("Sorry, expiry date invalid.");
+            continue;
+        };
+        if is_card_expired(expiry_month, expiry_year, chrono::Local::now().date_naive()) {
+            println!("Sorry, this card is expired.");
+            continue;
         }
+
+        return Some((card_number, expiry_month, expiry_year));
+    }
+}
+
+/// Asks user for a new card and a label and saves it in their profile.
+fn card_add(user: &mut User) {
+    let Some((card_number, expiry_month, expiry_year)) = read_card() else {
+        return;
+    };
+    let label = read_line("Label (leave blank for none): ");
+    let label = Some(label).filter(|label| !label.trim().is_empty());
+    user.add_saved_card(SavedCard::new(
+        card_number,
+        expiry_month,
+        expiry_year,
+        label,
+    ));
+    println!("Card saved.");
+}
+
+/// Lists the saved cards of the user with only the last digits of their numbers.
+fn list_saved_cards(user: &User) {
+    println!("Saved cards:");
+    for (i, card) in user.saved_cards().iter().enumerate() {
+        println!("{:>3}. {card}", i + 1);
+    }
+}
+
+/// Removes a saved card of the user by its index in the list.
+fn card_remove(user: &mut User, index: &str) {
+    let Some(index) = parse_arg::<usize>(index, "card index") else {
+        return;
+    };
+    if index > 0 && user.remove_saved_card(index - 1) {
+        println!("Card removed.");
+    } else {
+        println!("Sorry, there is no card with this index.");
+    }
+}
+
+/// Asks user to choose one of their saved cards or to type a new one.
+///
+/// A new card can be saved to the profile. The choice is asked again until it is a usable card.
+/// Return the card number, expiry month and expiry year, or `None` if the user typed "cancel".
+fn choose_card(user: &mut User) -> Option<(String, u8, u16)> {
+    if !user.saved_cards().is_empty() {
+        list_saved_cards(user);
+        loop {
+            let index = read_line("Card (index from the list, 0 for a new card, or cancel): ");
+            if index.trim() == "cancel" {
+                return None;
+            }
+            let Ok(index) = index.trim().parse::<usize>() else {
+                println!("Invalid card index: {index}");
+                continue;
+            };
+            if index == 0 {
+                break;
+            }
+            let Some(card) = user.saved_cards().get(index - 1) else {
+                println!("Sorry, there is no card with this index.");
+                continue;
+            };
+            let today = chrono::Local::now().date_naive();
+            if is_card_expired(card.expiry_month(), card.expiry_year(), today) {
+                println!("Sorry, this card is expired.");
+                continue;
+            }
+            return Some((
+                card.card_number().to_owned(),
+                card.expiry_month(),
+                card.expiry_year(),
+            ));
+        }
+    }
+
+    let (card_number, expiry_month, expiry_year) = read_card()?;
+    if read_yes_no("Save this card? (y/n): ") {
+        let label = read_line("Label (leave blank for none): ");
+        let label = Some(label).filter(|label| !label.trim().is_empty());
+        user.add_saved_card(SavedCard::new(
+            card_number.clone(),
+            expiry_month,
+            expiry_year,
+            label,
+        ));
+    }
+    Some((card_number, expiry_month, expiry_year))
+}
+
+/// Asks user for a gift card code and looks it up.
+///
+/// Return `Ok(None)` if no code was entered, or `Err(())` after printing why the gift card cannot
+/// be used.
+fn read_gift_card(gift_card_manager: &mut GiftCardManager) -> Result<Option<&mut GiftCard>, ()> {
+    let code = read_line("Gift card code (leave blank for none): ");
+    if code.trim().is_empty() {
+        return Ok(None);
+    }
+    match gift_card_manager.find_mut(code.trim()) {
+        None => {
+            println!("Sorry, unknown gift card.");
+            Err(())
+        }
+        Some(gift_card) if gift_card.is_used_up() => {
+            println!("Sorry, this gift card is used up.");
+            Err(())
+        }
+        Some(gift_card) => Ok(Some(gift_card)),
+    }
+}
+
+/// Asks user for an optional promo code and applies it to the order.
+///
+/// The order is paid without a discount if the code cannot be used. The use of the code is only
+/// counted by `settle_promo` once the order is paid. Return true if the code was applied.
+fn read_promo_code(promo_code_manager: &PromoCodeManager, order: &mut Order) -> bool {
+    let code = read_line("Promo code (leave blank for none): ");
+    if code.trim().is_empty() {
+        return false;
+    }
+    match promo_code_manager.apply(code.trim(), order) {
+        Ok(discount) => {
+            println!("Promo code applied: -{}.", format_price(discount));
+            true
+        }
+        Err(err) => {
+            println!("Sorry, {err}.");
+            false
+        }
+    }
+}
+
+/// Counts the use of a promo code applied while paying, or takes it off the order again if the
+/// order was not paid.
+fn settle_promo(
+    promo_code_manager: &mut PromoCodeManager,
+    order: &mut Order,
+    applied: bool,
+    paid: bool,
+) {
+    if !applied {
+        return;
+    }
+    if paid {
+        promo_code_manager.redeem(order);
+    } else {
+        order.remove_promo();
+        println!("The promo code was not used.");
+    }
+}
+
+/// Why a payment method did not pay
+enum PaymentAbort {
+    /// The user typed "cancel" while entering the payment details
+    Cancelled,
+    /// The payment method was refused, the reason was printed
+    Declined,
+}
+
+/// Asks the payment processor to authorize a payment and prints the outcome.
+fn authorize(
+    payment_processor: &mut dyn PaymentProcessor,
+    card_registry: &CardRegistry,
+    payment: &OrderPayment,
+    amount: f64,
+) -> Result<(), PaymentAbort> {
+    match payment_processor.authorize(payment, amount, card_registry) {
+        Ok(auth_id) => {
+            println!(
+                "Payment of {amount} authorized ({auth_id}).",
+                amount = format_price(amount)
+            );
+            Ok(())
+        }
+        Err(err) => {
+            println!("Sorry, {err}.");
+            Err(PaymentAbort::Declined)
+        }
+    }
+}
+
+/// Takes the amount from the wallet of the user.
+fn pay_with_wallet(
+    user: &mut User,
+    payment_processor: &mut dyn PaymentProcessor,
+    card_registry: &CardRegistry,
+    amount: f64,
+) -> Result<OrderPayment, PaymentAbort> {
+    if to_cents(user.wallet_balance()) < to_cents(amount) {
+        let missing = round_money(amount - user.wallet_balance());
+        println!(
+            "Sorry, not enough money in wallet, {missing} missing.",
+            missing = format_price(missing)
+        );
+        return Err(PaymentAbort::Declined);
+    }
+    authorize(
+        payment_processor,
+        card_registry,
+        &OrderPayment::Wallet,
+        amount,
+    )?;
+    user.debit_wallet(amount).ok();
+    Ok(OrderPayment::Wallet)
+}
+
+/// Asks user for a card until the payment processor accepts one.
+fn pay_with_card(
+    user: &mut User,
+    payment_processor: &mut dyn PaymentProcessor,
+    card_registry: &CardRegistry,
+    amount: f64,
+) -> Result<OrderPayment, PaymentAbort> {
+    loop {
+        let (card_number, expiry_month, expiry_year) =
+            choose_card(user).ok_or(PaymentAbort::Cancelled)?;
+        let payment = OrderPayment::CreditCard {
+            card_number,
+            expiry_month,
+            expiry_year,
+        };
+        if authorize(payment_processor, card_registry, &payment, amount).is_ok() {
+            return Ok(payment);
+        }
+        println!("Try another card or type cancel to choose another payment method.");
+    }
+}
+
+/// How the user chose to pay an order
+enum PaymentPlan {
+    /// Paid now with the given legs, the second value is the money taken from the wallet
+    Now(Vec<PaymentLeg>, f64),
+    /// Paid in cash when the order is delivered
+    OnDelivery,
+}
+
+/// Asks user how to pay the given amount, possibly split across several payment methods.
+///
+/// The user is asked for a payment method and an amount until the whole amount is covered or
+/// they cancel. Wallet payments are taken from the wallet right away and given back if the user
+/// cancels. Paying on delivery is only possible for the whole order, when `allow_on_delivery`.
+/// Return `None` if cancelled.
+fn read_payment_legs(
+    user: &mut User,
+    payment_processor: &mut dyn PaymentProcessor,
+    card_registry: &CardRegistry,
+    amount_due: f64,
+    allow_on_delivery: bool,
+) -> Option<PaymentPlan> {
+    let mut legs = Vec::new();
+    let mut paid_from_wallet = 0.0;
+    let mut remaining = amount_due;
+    while to_cents(remaining) > 0 {
+        println!(
+            "Remaining: {remaining}",
+            remaining = format_price(remaining)
+        );
+        let method =
+            read_line("Payment method (wallet, cash, credit card, pay on delivery or cancel): ");
+        if method == "cancel" {
+            user.credit_wallet(paid_from_wallet);
+            println!("Payment cancelled.");
+            return None;
+        }
+        if method == "pay on delivery" {
+            if allow_on_delivery && legs.is_empty() {
+                return Some(PaymentPlan::OnDelivery);
+            }
+            println!("Paying on delivery must cover the whole order.");
+            continue;
+        }
+        if !matches!(
+            method.as_str(),
+            "wallet" | "cash" | "credit" | "credit card"
+        ) {
+            println!("This payment method is not available.");
+            continue;
+        }
+
+        let amount = read_line(&format!(
+            "Amount (leave blank for {remaining}): ",
+            remaining = format_price(remaining)
+        ));
+        let mut amount = if amount.trim().is_empty() {
+            remaining
+        } else {
+            match amount.trim().parse::<f64>() {
+                Ok(amount) if amount > 0.0 => round_money(amount),
+                _ => {
+                    println!("Invalid amount: {amount}");
+                    continue;
+                }
+            }
+        };
+        if to_cents(amount) > to_cents(remaining) {
+            if method == "cash" {
+                println!("Return: {}", format_price(round_money(amount - remaining)));
+                amount = remaining;
+            } else {
+                println!(
+                    "The amount is more than the remaining {remaining}.",
+                    remaining = format_price(remaining)
+                );
+                continue;
+            }
+        }
+
+        let payment = match method.as_str() {
+            "wallet" => pay_with_wallet(user, payment_processor, card_registry, amount),
+            "credit" | "credit card" => {
+                pay_with_card(user, payment_processor, card_registry, amount)
+            }
+            _ => authorize(
+                payment_processor,
+                card_registry,
+                &OrderPayment::Cash,
+                amount,
+            )
+            .map(|()| OrderPayment::Cash),
+        };
+        let payment = match payment {
+            Ok(payment) => payment,
+            Err(PaymentAbort::Cancelled) => continue,
+            Err(PaymentAbort::Declined) => {
+                println!("Choose another payment method or type cancel.");
+                continue;
+            }
+        };
+        if matches!(payment, OrderPayment::Wallet) {
+            paid_from_wallet = round_money(paid_from_wallet + amount);
+        }
+        legs.push(PaymentLeg::new(payment, amount));
+        remaining = round_money(remaining - amount);
+    }
+    Some(PaymentPlan::Now(legs, paid_from_wallet))
+}
+
+/// Prints and records a paid order: its receipt, the transactions and the loyalty points earned.
+fn record_payment(
+    order: &Order,
+    user: &mut User,
+    receipt_manager: &mut ReceiptManager,
+    transaction_log: &mut TransactionLog,
+    activity_log: &mut ActivityLog,
+    actor: &str,
+) {
+    let receipt = receipt_manager.issue_for_order(order);
+    log::info!(
+        "order #{} of {} paid by {} for {}",
+        order.order_id(),
+        user.username(),
+        receipt.payment(),
+        format_price(receipt.total())
+    );
+    receipt.view();
+    audit(
+        actor,
+        &format!("order #{} paid by {}", order.order_id(), receipt.payment()),
+    );
+    activity_log.log_activity(
+        actor,
+        ActivityKind::Payment,
+        format!("order #{} paid by {}", order.order_id(), receipt.payment()),
+    );
+    transaction_log.record_order_payment(order);
+    let points = order.points_earned();
+    user.add_points(points);
+    println!("{} earned {points} loyalty points.", user.username());
+}
+
+/// Asks user for how to pay and closes order.
+///
+/// A gift card can pay all or part of the order, the rest can be split across other payment
+/// methods.
+#[allow(clippy::too_many_arguments)]
+fn pay(
+    user: &mut User,
+    order_manager: &mut OrderManager,
+    card_registry: &CardRegistry,
+    gift_card_manager: &mut GiftCardManager,
+    promo_code_manager: &mut PromoCodeManager,
+    receipt_manager: &mut ReceiptManager,
+    transaction_log: &mut TransactionLog,
+    activity_log: &mut ActivityLog,
+    payment_processor: &mut dyn PaymentProcessor,
+) {
+    let order_id: u64 = read_value("Order ID: ");
+    let spent_this_month = order_manager.spent_in_month(user.username(), chrono::Local::now());
+    if let Some(order) = order_manager
+        .orders_mut()
+        .iter_mut()
+        .find(|order| order.order_id() == order_id && order.username() == user.username())
+    {
+        order.view();
+        if let OrderState::AwaitingDelivery = order.state() {
+            println!("Order will be paid on delivery.");
+            return;
+        }
+        if let OrderState::Cancelled { .. } = order.state() {
+            println!("Order was cancelled.");
+            return;
+        }
+        if !order.is_open() {
+            println!("Order already closed.");
+            return;
+        }
+        let promo_applied = order.promo().is_none() && read_promo_code(promo_code_manager, order);
+        let paid = 'pay: {
+            println!("Order #{} - {}", order.order_id(), order.totals());
+            if !read_yes_no("Proceed to pay this amount? (y/n): ") {
+                println!("Payment cancelled.");
+                break 'pay false;
+            }
+            let tip = loop {
+                let tip = read_line("Add a tip? (amount or enter for none): ");
+                if tip.trim().is_empty() {
+                    break 0.0;
+                }
+                match tip.trim().parse::<f64>() {
+                    Ok(tip) if tip >= 0.0 && tip.is_finite() => break tip,
+                    _ => println!("Invalid tip, it must be a number of at least 0."),
+                }
+            };
+            order.set_tip(tip);
+            if tip > 0.0 {
+                println!("Order #{} - {}", order.order_id(), order.totals());
+            }
+            if let Some(budget) = user.monthly_budget() {
+                let spent = spent_this_month + order.total_price();
+                if to_cents(spent) > to_cents(budget) {
+                    println!(
+                        "This brings your spending this month to {spent}, over your budget of {budget}.",
+                        spent = format_price(spent),
+                        budget = format_price(budget)
+                    );
+                    if !read_yes_no("Pay anyway? (y/n): ") {
+                        println!("Payment cancelled.");
+                        break 'pay false;
+                    }
+                }
+            }
+            let Ok(gift_card) = read_gift_card(gift_card_manager) else {
+                break 'pay false;
+            };
+            let gift_card_amount = gift_card.as_ref().map_or(0.0, |gift_card| {
+                gift_card.balance().min(order.total_price())
+            });
+            let amount_due = round_money(order.total_price() - gift_card_amount);
+            if gift_card.is_some() {
+                println!(
+                    "Gift card pays {gift_card_amount}, {amount_due} remaining.",
+                    gift_card_amount = format_price(gift_card_amount),
+                    amount_due = format_price(amount_due)
+                );
+            }
+
+            let (payment, paid_from_wallet) = if to_cents(amount_due) == 0 {
+                (OrderPayment::GiftCard, 0.0)
+            } else {
+                let plan = read_payment_legs(
+                    user,
+                    payment_processor,
+                    card_registry,
+                    amount_due,
+                    gift_card.is_none(),
+                );
+                match plan {
+                    None => break 'pay false,
+                    Some(PaymentPlan::OnDelivery) => {
+                        order.await_delivery();
+                        audit(
+                            user.username(),
+                            &format!("order #{} to be paid on delivery", order.order_id()),
+                        );
+                        println!(
+                            "Order will be paid in cash on delivery: {amount_due}.",
+                            amount_due = format_price(amount_due)
+                        );
+                        break 'pay true;
+                    }
+                    Some(PaymentPlan::Now(mut legs, paid_from_wallet)) => {
+                        let payment = if legs.len() == 1 {
+                            legs.remove(0).into_payment()
+                        } else {
+                            OrderPayment::Split { legs }
+                        };
+                        (payment, paid_from_wallet)
+                    }
+                }
+            };
+            let closed = match gift_card {
+                Some(gift_card) => order.close_with_gift_card(payment, gift_card, gift_card_amount),
+                None => order.close(payment),
+            };
+            if closed {
+                if to_cents(paid_from_wallet) > 0 {
+                    println!("Wallet balance: {}", format_price(user.wallet_balance()));
+                }
+                println!("Order payed successfully.");
+                let username = user.username().to_owned();
+                record_payment(
+                    order,
+                    user,
+                    receipt_manager,
+                    transaction_log,
+                    activity_log,
+                    &username,
+                );
+            } else {
+                user.credit_wallet(paid_from_wallet);
+                println!("Order already closed.");
+            }
+            closed
+        };
+        settle_promo(promo_code_manager, order, promo_applied, paid);
+    } else {
+        println!("Order not found. Aborting.");
+    }
+}
+
+/// Asks admin for the value of a new gift card and prints its code.
+fn giftcard_create(gift_card_manager: &mut GiftCardManager, value: &str) {
+    let Some(value) = parse_arg::<f64>(value, "value") else {
+        return;
+    };
+    if !is_valid_amount(value) {
+        println!("The value must be positive.");
+        return;
+    }
+    let gift_card = gift_card_manager.create(value);
+    println!(
+        "Gift card {} created with {}.",
+        gift_card.code(),
+        format_price(gift_card.initial_value())
+    );
+}
+
+/// Asks admin for the details of a new promo code.
+fn promo_add(promo_code_manager: &mut PromoCodeManager) {
+    let code = read_line("Code: ");
+    let code = code.trim();
+    if code.is_empty() || code.contains(char::is_whitespace) {
+        println!("The code must be a single word.");
+        return;
+    }
+    let percent_off: u8 = read_value("Percent off (1-100): ");
+    let expires_on = loop {
+        let date = read_line("Last day it can be used (YYYY-MM-DD, leave blank for never): ");
+        if date.trim().is_empty() {
+            break None;
+        }
+        match date.trim().parse::<chrono::NaiveDate>() {
+            Ok(date) => break Some(date),
+            Err(_) => println!("Invalid date."),
+        }
+    };
+    let max_uses = loop {
+        let max_uses = read_line("Maximum number of uses (leave blank for unlimited): ");
+        if max_uses.trim().is_empty() {
+            break None;
+        }
+        match max_uses.trim().parse::<u32>() {
+            Ok(max_uses) => break Some(max_uses),
+            Err(_) => println!("Invalid number."),
+        }
+    };
+    if promo_code_manager.add(code, percent_off, expires_on, max_uses) {
+        println!("Promo code {} added.", code.to_uppercase());
+    } else {
+        println!("Promo code already exists or the percentage is not between 1 and 100.");
+    }
+}
+
+/// Lists orders for current user.
+fn list_orders_for_user(order_manager: &OrderManager, user: &User) {
+    order_manager
+        .orders()
+        .iter()
+        .filter(|order| order.username() == user.username())
+        .for_each(View::view);
+}
+
+/// Shows the orders assigned to a courier that still have to be delivered.
+fn deliveries(order_manager: &OrderManager, courier: &str) {
+    order_manager
+        .orders()
+        .iter()
+        .filter(|order| order.courier() == Some(courier))
+        .filter(|order| {
+            matches!(
+                order.state(),
+                OrderState::AwaitingDelivery | OrderState::Closed { .. }
+            )
+        })
+        .for_each(View::view);
+}
+
+/// Check if an order, given by the ID typed in a command, is assigned to the courier.
+fn is_assigned_to(order_manager: &OrderManager, order_id: &str, courier: &str) -> bool {
+    order_id
+        .parse()
+        .ok()
+        .and_then(|order_id| order_manager.order(order_id))
+        .is_some_and(|order| order.courier() == Some(courier))
+}
+
+/// Gives the delivery of an order to a courier and lets them know.
+fn order_assign(
+    order_manager: &mut OrderManager,
+    user_manager: &mut UserManager,
+    actor: &str,
+    order_id: &str,
+    courier: &str,
+) {
+    let Some(order_id) = parse_arg::<u64>(order_id, "order ID") else {
+        return;
+    };
+    let Some(courier) = user_manager.find_mut(courier) else {
+        println!("User not found.");
+        return;
+    };
+    if courier.role() != Role::Courier {
+        println!("{} is not a courier.", courier.username());
+        return;
+    }
+    let Some(order) = order_manager
+        .orders_mut()
+        .iter_mut()
+        .find(|order| order.order_id() == order_id)
+    else {
+        println!("Order not found.");
+        return;
+    };
+    if !order.assign_courier(courier.username()) {
+        println!("Only orders that still have to be delivered can be assigned.");
+        return;
+    }
+    courier.notify(format!(
+        "Order #{order_id} was assigned to you for delivery."
+    ));
+    audit(
+        actor,
+        &format!("order #{order_id} assigned to {}", courier.username()),
+    );
+    println!(
+        "Order #{order_id} will be delivered by {}.",
+        courier.username()
+    );
+}
+
+/// Asks admin to mark a paid order as delivered.
+fn order_deliver(order_manager: &mut OrderManager, order_id: &str) {
+    let Some(order_id) = parse_arg::<u64>(order_id, "order ID") else {
+        return;
+    };
+    match order_manager
+        .orders_mut()
+        .iter_mut()
+        .find(|order| order.order_id() == order_id)
+    {
+        Some(order) => {
+            if order.deliver() {
+                println!("Order marked as delivered.");
+            } else {
+                println!("Only paid orders can be delivered.");
+            }
+        }
+        None => println!("Order not found."),
+    }
+}
+
+/// Finds the orders of a bulk status update.
+///
+/// The selector is either `all <state>` or a comma separated list of order IDs. Returns `None`
+/// after printing an error if the selector is invalid.
+fn select_orders(order_manager: &OrderManager, selector: &[&str]) -> Option<Vec<u64>> {
+    let ["all", state] = selector else {
+        return selector
+            .join("")
+            .split(',')
+            .map(|order_id| parse_arg(order_id, "order ID"))
+            .collect();
+    };
+    let in_state: fn(&OrderState) -> bool = match *state {
+        "open" => |state| matches!(state, OrderState::Open),
+        "awaiting-delivery" => |state| matches!(state, OrderState::AwaitingDelivery),
+        "closed" => |state| matches!(state, OrderState::Closed { .. }),
+        "delivered" => |state| matches!(state, OrderState::Delivered { .. }),
+        "cancelled" => |state| matches!(state, OrderState::Cancelled { .. }),
+        _ => {
+            println!("Unknown order state: {state}");
+            println!("The states are open, awaiting-delivery, closed, delivered and cancelled.");
+            return None;
+        }
+    };
+    Some(
+        order_manager
+            .orders()
+            .iter()
+            .filter(|order| in_state(order.state()))
+            .map(Order::order_id)
+            .collect(),
+    )
+}
+
+/// Moves several orders to a new state at once, like `order status all closed -> delivered` or
+/// `order status 3,4,7 -> cancelled`.
+///
+/// Orders that cannot make the transition are skipped and counted in the summary.
+fn order_status(app: &mut CoronaApplication, actor: &str, args: &[&str]) {
+    let Some(arrow) = args.iter().position(|&arg| arg == "->") else {
+        println!("Usage: order status <all STATE | ID,ID,...> -> <delivered | cancelled>");
+        return;
+    };
+    let target = match &args[arrow + 1..] {
+        [target @ ("delivered" | "cancelled")] => *target,
+        _ => {
+            println!("Orders can only be moved to delivered or cancelled.");
+            return;
+        }
+    };
+    let Some(order_ids) = select_orders(&app.order_manager, &args[..arrow]) else {
+        return;
+    };
+
+    let (mut updated, mut skipped, mut not_found) = (0, 0, 0);
+    for order_id in order_ids {
+        let changed = match app
+            .order_manager
+            .orders_mut()
+            .iter_mut()
+            .find(|order| order.order_id() == order_id)
+        {
+            None => {
+                not_found += 1;
+                continue;
+            }
+            Some(order) if target == "delivered" => order.deliver(),
+            Some(_) => app.cancel_order(order_id, "by the shop"),
+        };
+        if changed {
+            updated += 1;
+            audit(actor, &format!("order #{order_id} marked as {target}"));
+            if target == "cancelled" {
+                app.activity_log.log_activity(
+                    actor,
+                    ActivityKind::Cancellation,
+                    format!("order #{order_id}"),
+                );
+            }
+        } else {
+            skipped += 1;
+        }
+    }
+    println!("Updated {updated} order(s), skipped {skipped} that cannot be {target}.");
+    if not_found > 0 {
+        println!("{not_found} order(s) were not found.");
+    }
+}
+
+/// Lets the owner of an open order or an admin change its items before it is paid.
+///
+/// The edits are asked until a blank line. Each one updates the stock and prints the new totals.
+fn order_edit(
+    order_manager: &mut OrderManager,
+    catalog: &mut Catalog,
+    user: &User,
+    order_id: &str,
+) {
+    let Some(order_id) = parse_arg::<u64>(order_id, "order ID") else {
+        return;
+    };
+    let Some(order) = order_manager
+        .orders_mut()
+        .iter_mut()
+        .find(|order| order.order_id() == order_id)
+        .filter(|order| user.is_admin() || order.username() == user.username())
+    else {
+        println!("Order not found.");
+        return;
+    };
+    if !order.is_open() {
+        println!("Only open orders can be edited.");
+        return;
+    }
+    order.view();
+    loop {
+        let line = read_line(
+            "Edit (add <code> <quantity>, quantity <code> <quantity>, remove <code>, \
+             leave blank when done): ",
+        );
+        let (code, quantity) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => break,
+            ["add", code, quantity] => match quantity.parse::<f64>() {
+                Ok(quantity) => {
+                    let current = order
+                        .items()
+                        .iter()
+                        .find(|item| item.code() == *code)
+                        .map_or(0.0, OrderItem::quantity);
+                    (code.to_string(), current + quantity)
+                }
+                Err(_) => {
+                    println!("Invalid quantity: {quantity}");
+                    continue;
+                }
+            },
+            ["quantity", code, quantity] => match quantity.parse::<f64>() {
+                Ok(quantity) => (code.to_string(), quantity),
+                Err(_) => {
+                    println!("Invalid quantity: {quantity}");
+                    continue;
+                }
+            },
+            ["remove", code] => (code.to_string(), 0.0),
+            _ => {
+                println!("I don't understand what you are saying!!!");
+                continue;
+            }
+        };
+        match order.set_item_quantity(catalog, &code, quantity) {
+            Ok(()) => {
+                audit(
+                    user.username(),
+                    &format!("order #{order_id}: quantity of [{code}] set to {quantity}"),
+                );
+                println!("Order #{order_id} - {}", order.totals());
+            }
+            Err(err) => println!("Sorry, {err}."),
+        }
+    }
+}
+
+/// Records the cash received by the courier for an order paid on delivery and prints the change.
+#[allow(clippy::too_many_arguments)]
+fn order_collect(
+    order_manager: &mut OrderManager,
+    user_manager: &mut UserManager,
+    receipt_manager: &mut ReceiptManager,
+    transaction_log: &mut TransactionLog,
+    activity_log: &mut ActivityLog,
+    actor: &str,
+    order_id: &str,
+    amount: &str,
+) {
+    let (Some(order_id), Some(amount)) = (
+        parse_arg::<u64>(order_id, "order ID"),
+        parse_arg::<f64>(amount, "amount"),
+    ) else {
+        return;
+    };
+    let Some(order) = order_manager
+        .orders_mut()
+        .iter_mut()
+        .find(|order| order.order_id() == order_id)
+    else {
+        println!("Order not found.");
+        return;
+    };
+    match order.collect(amount) {
+        Ok(change) => {
+            println!(
+                "Cash collected, order delivered. Change: {change}",
+                change = format_price(change)
+            );
+            if let Some(user) = user_manager.find_mut(order.username()) {
+                record_payment(
+                    order,
+                    user,
+                    receipt_manager,
+                    transaction_log,
+                    activity_log,
+                    actor,
+                );
+            }
+        }
+        Err(err) => println!("Cannot collect: {err}."),
+    }
+}
+
+/// Prints the packing slip of an order for an admin.
+fn order_slip(order_manager: &OrderManager, order_id: &str) {
+    let Some(order_id) = parse_arg::<u64>(order_id, "order ID") else {
+        return;
+    };
+    match order_manager
+        .orders()
+        .iter()
+        .find(|order| order.order_id() == order_id)
+    {
+        Some(order) => print_packing_slip(order),
+        None => println!("Order not found."),
+    }
+}
+
+/// Writes the invoice of a paid order to `invoice_<id>.html`.
+///
+/// Admins can write the invoice of any order and users only of their own.
+fn order_invoice(
+    order_manager: &OrderManager,
+    user_manager: &UserManager,
+    store_name: &str,
+    username: &str,
+    order_id: &str,
+) {
+    let Some(order_id) = parse_arg::<u64>(order_id, "order ID") else {
+        return;
+    };
+    let is_admin = user_manager.find(username).is_some_and(User::is_admin);
+    let Some(order) = order_manager
+        .order(order_id)
+        .filter(|order| is_admin || order.username() == username)
+    else {
+        println!("Order not found.");
+        return;
+    };
+    if order.payment().is_none() {
+        println!("Only paid orders have an invoice.");
+        return;
+    }
+    let invoice = invoice_html(order, user_manager.find(order.username()), store_name);
+    let path = format!("invoice_{order_id}.html");
+    match std::fs::write(&path, invoice) {
+        Ok(()) => println!("Invoice written to {path}."),
+        Err(err) => println!("Failed to write {path}: {err}"),
+    }
+}
+
+/// Writes all orders to `orders.csv` for accounting.
+fn order_export(order_manager: &OrderManager) {
+    let path = "orders.csv";
+    match std::fs::write(path, order_manager.to_csv()) {
+        Ok(()) => println!(
+            "{} order(s) written to {path}.",
+            order_manager.orders().len()
+        ),
+        Err(err) => println!("Failed to write {path}: {err}"),
+    }
+}
+
+/// Asks user for the quantity and reason of returning an item of a delivered order.
+fn return_request(user: &User, order_manager: &mut OrderManager, order_id: &str, code: &str) {
+    let Some(order_id) = parse_arg(order_id, "order ID") else {
+        return;
+    };
+    if let Err(err) = order_manager.returnable_quantity(user.username(), order_id, code) {
+        println!("Cannot return item: {err}.");
+        return;
+    }
+    let quantity = read_value("Quantity: ");
+    let reason = read_line("Reason: ");
+
+    match order_manager.request_return(user.username(), order_id, code.to_owned(), quantity, reason)
+    {
+        Ok(request) => {
+            println!("Return requested.");
+            request.view();
+        }
+        Err(err) => println!("Cannot return item: {err}."),
+    }
+}
+
+/// Lists return requests, all of them for admins and only their own for other users.
+fn list_returns(order_manager: &OrderManager, user: &User) {
+    order_manager
+        .returns()
+        .iter()
+        .filter(|request| user.is_admin() || request.username() == user.username())
+        .for_each(View::view);
+}
+
+/// Moves a return request to a new status on behalf of an admin.
+fn return_update(order_manager: &mut OrderManager, return_id: &str, status: ReturnStatus) {
+    let Some(return_id) = parse_arg(return_id, "return ID") else {
+        return;
+    };
+    match order_manager.update_return(return_id, status) {
+        Ok(request) => request.view(),
+        Err(err) => println!("Cannot update return: {err}."),
+    }
+}
+
+/// Refunds a return request on behalf of an admin.
+///
+/// The money goes back to the payment method of the order and is recorded in the transaction log.
+/// The returned items can then be put back in stock.
+fn return_refund(app: &mut CoronaApplication, actor: &str, return_id: &str) {
+    let CoronaApplication {
+        order_manager,
+        catalog,
+        user_manager,
+        gift_card_manager,
+        receipt_manager,
+        transaction_log,
+        ..
+    } = app;
+    let Some(return_id) = parse_arg(return_id, "return ID") else {
+        return;
+    };
+    match order_manager.update_return(return_id, ReturnStatus::Refunded) {
+        Ok(request) => request.view(),
+        Err(err) => {
+            println!("Cannot update return: {err}.");
+            return;
+        }
+    }
+    let Some(request) = order_manager.return_request(return_id) else {
+        return;
+    };
+    let amount = order_manager.refund_amount(return_id).unwrap_or_default();
+    receipt_manager.issue_for_refund(request, amount).view();
+    let instructions = order_manager
+        .order(request.order_id())
+        .map(|order| order.refund_payment(amount))
+        .unwrap_or_default();
+    if instructions.is_empty() {
+        println!("The order was not paid, nothing to refund.");
+        return;
+    }
+    for instruction in &instructions {
+        println!("Refund: {instruction}.");
+        match instruction {
+            RefundInstruction::Wallet { amount } => {
+                if let Some(user) = user_manager.find_mut(request.username()) {
+                    user.credit_wallet(*amount);
+                }
+            }
+            RefundInstruction::GiftCard { code, amount } => {
+                if let Some(gift_card) = gift_card_manager.find_mut(code) {
+                    gift_card.credit(*amount);
+                }
+            }
+            RefundInstruction::Cash { .. } | RefundInstruction::CreditCard { .. } => {}
+        }
+        transaction_log.record_refund(request.order_id(), request.username(), instruction);
+    }
+    restock_return(catalog, order_manager, actor, request);
+}
+
+/// Asks whether the items of a refunded return go back in stock and restocks them if so.
+///
+/// Nothing is asked for products whose stock is not tracked.
+fn restock_return(
+    catalog: &mut Catalog,
+    order_manager: &OrderManager,
+    actor: &str,
+    request: &ReturnRequest,
+) {
+    let code = request.code();
+    if catalog.product(code).and_then(Product::stock).is_none() {
+        return;
+    }
+    let units = order_manager
+        .restock_quantity(request.return_id())
+        .unwrap_or_default();
+    if units <= 0 {
+        println!("The stock taken for [{code}] is still used by the rest of the order.");
+        return;
+    }
+    if !read_yes_no(&format!(
+        "Put {units} unit(s) of [{code}] back in stock? (y/n): "
+    )) {
+        return;
+    }
+    match catalog.restock(code, units, false) {
+        Ok(stock) => {
+            audit(
+                actor,
+                &format!(
+                    "product [{code}] restocked to {stock} by return #{}",
+                    request.return_id()
+                ),
+            );
+            println!("Stock of [{code}]: {stock}");
+        }
+        Err(err) => println!("Sorry, {err}."),
+    }
+}
+
+/// Prints the sales of paid orders, the refunds of returns and what is left.
+fn report_revenue(order_manager: &OrderManager) {
+    order_manager.revenue().view();
+}
+
+/// Prints the last transactions of the transaction log, 20 unless a count is given.
+fn ledger_show(transaction_log: &TransactionLog, count: Option<&str>) {
+    let count = match count {
+        Some(count) => match parse_arg(count, "number of entries") {
+            Some(count) => count,
+            None => return,
+        },
+        None => 20,
+    };
+    println!("Ledger:");
+    transaction_log.last(count).iter().for_each(View::view);
+}
+
+/// Prints the latest entries of the activity log, 20 unless given, of one user if given.
+fn activity_show(activity_log: &ActivityLog, username: Option<&str>, count: Option<&str>) {
+    let count = match count {
+        Some(count) => match parse_arg(count, "number of entries") {
+            Some(count) => count,
+            None => return,
+        },
+        None => 20,
+    };
+    match username {
+        Some(username) => println!("Activity of {username}:"),
+        None => println!("Activity:"),
+    }
+    activity_log
+        .latest(username, count)
+        .into_iter()
+        .for_each(View::view);
+}
+
+/// Changes how many entries the activity log keeps.
+fn max_activity_entries(activity_log: &mut ActivityLog, max: &str) {
+    let Some(max) = parse_arg(max, "number of entries") else {
+        return;
+    };
+    if activity_log.set_max_activity_entries(max) {
+        println!("The activity log keeps the latest {max} entries.");
+    } else {
+        println!("The activity log must keep at least 1 entry.");
+    }
+}
+
+/// Prints the sum of the transactions of every kind.
+fn ledger_balance(transaction_log: &TransactionLog) {
+    println!("Ledger balance:");
+    for (kind, amount) in transaction_log.balance_by_kind() {
+        println!("  {kind}: {}", format_price(amount));
+    }
+}
+
+/// Prints the customers who spent the most on paid orders.
+fn report_customers(order_manager: &OrderManager, count: Option<&str>) {
+    let count = match count {
+        Some(count) => match parse_arg(count, "number of customers") {
+            Some(count) => count,
+            None => return,
+        },
+        None => 10,
+    };
+    let mut report = CustomerReport::from_orders(order_manager.orders());
+    report.truncate(count);
+    report.view();
+}
+
+/// Changes the name of the store shown at startup and on receipts.
+fn store_name_set(store_name: &mut String, name: &[&str]) {
+    let name = name.join(" ");
+    if name.is_empty() {
+        println!("The name of the store can not be empty.");
+        return;
+    }
+    set_store_name(&name);
+    *store_name = name;
+    print_banner();
+}
+
+/// The roles that manage the stock and fulfil orders
+const STAFF: &[Role] = &[Role::Staff, Role::Admin];
+
+/// The roles that deliver orders, couriers only the orders assigned to them
+const DELIVERY: &[Role] = &[Role::Courier, Role::Staff, Role::Admin];
+
+/// The first words of the commands of the logged in menu, aliases must lead to one of them
+const COMMANDS: &[&str] = &[
+    "add",
+    "activity",
+    "address",
+    "addresses",
+    "alias",
+    "card",
+    "cards",
+    "cart",
+    "catalog",
+    "checkout",
+    "delete",
+    "deliveries",
+    "exit",
+    "favorite",
+    "favorites",
+    "giftcard",
+    "giftcards",
+    "inbox",
+    "ledger",
+    "logout",
+    "my",
+    "order",
+    "orders",
+    "password",
+    "pay",
+    "product",
+    "products",
+    "profile",
+    "promo",
+    "promos",
+    "q",
+    "quit",
+    "quote",
+    "receipt",
+    "receipts",
+    "report",
+    "return",
+    "returns",
+    "settings",
+    "transactions",
+    "unalias",
+    "user",
+    "users",
+    "wallet",
+];
+
+/// Lists the aliases of the user.
+fn alias_list(command_aliases: &CommandAliases, username: &str) {
+    let mut aliases = command_aliases.of(username).peekable();
+    if aliases.peek().is_none() {
+        println!("You have no aliases.");
+    }
+    for (name, command) in aliases {
+        println!("{name} = {command}");
+    }
+}
+
+/// Makes a word a shortcut for a command of the user.
+fn alias_define(
+    command_aliases: &mut CommandAliases,
+    username: &str,
+    name: &str,
+    command: &[&str],
+) {
+    let command = command.join(" ");
+    match command_aliases.define(username, name, &command, COMMANDS) {
+        Ok(()) => println!("\"{name}\" now runs \"{command}\"."),
+        Err(err) => println!("Cannot define the alias: {err}."),
+    }
+}
+
+/// Changes after how many seconds without a command users are logged out, 0 to never.
+fn idle_timeout_set(idle_timeout_secs: &mut u64, seconds: &str) {
+    let Some(seconds) = parse_arg(seconds, "number of seconds") else {
+        return;
+    };
+    *idle_timeout_secs = seconds;
+    if seconds == 0 {
+        println!("Idle users are never logged out.");
+    } else {
+        println!("Idle users are logged out after {seconds} seconds.");
+    }
+}
+
+/// Turns saving after every command that changed something on or off.
+fn autosave_set(autosave: &mut bool, state: &str) {
+    match state {
+        "on" => {
+            *autosave = true;
+            println!("Changes are saved after every command.");
+        }
+        "off" => {
+            *autosave = false;
+            println!("Changes are only saved with the save command and on exit.");
+        }
+        _ => println!("Say on or off."),
+    }
+}
+
+/// Saves the entire state to a new file, as JSON if its extension says so and as TOML otherwise.
+///
+/// The file holds everything, password hashes and card numbers included, so only admins may do
+/// this and existing files are never replaced.
+fn save_as(app: &CoronaApplication, actor: &str, path: &str) {
+    let format = Format::from_path(path).unwrap_or(Format::Toml);
+    match app.save_as(path, format) {
+        Ok(()) => {
+            audit(actor, &format!("state saved to {path}"));
+            println!("Saved to {path}.");
+        }
+        Err(err) => println!("Failed to save to {path}, {err}."),
+    }
+}
+
+/// Changes how many backups of the data file are kept.
+fn max_backups_set(max_backups: &mut usize, count: &str) {
+    let Some(count) = parse_arg(count, "number of backups") else {
+        return;
+    };
+    *max_backups = count;
+    if count == 0 {
+        println!("No backups are made when saving.");
+    } else {
+        println!("The last {count} different saves are kept as backups.");
+    }
+}
+
+/// Writes a size in bytes for the user, like `12.5 KB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Lists the backups of the data file and replaces the current state with the chosen one after
+/// confirmation.
+fn restore(app: &mut CoronaApplication) {
+    let backups = Backup::list(data_file());
+    if backups.is_empty() {
+        println!("There are no backups of {}.", data_file());
+        return;
+    }
+    println!("Backups of {}:", data_file());
+    for (idx, backup) in backups.iter().enumerate() {
+        println!(
+            "{:>3}. {} - {}",
+            idx + 1,
+            backup.created_at().format("%Y-%m-%d %H:%M:%S"),
+            format_size(backup.size())
+        );
+    }
+    let choice = read_line("Backup to restore (leave blank to cancel): ");
+    if choice.trim().is_empty() {
+        return;
+    }
+    let Some(backup) = choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| backups.get(number.checked_sub(1)?))
+    else {
+        println!("There is no such backup.");
+        return;
+    };
+    let created_at = backup.created_at().format("%Y-%m-%d %H:%M:%S");
+    if !read_yes_no(&format!(
+        "Replace all current data with the backup from {created_at}? (y/n): "
+    )) {
+        println!("Nothing was restored.");
+        return;
+    }
+    match app.restore(backup) {
+        Ok(()) => {
+            audit("system", &format!("backup from {created_at} restored"));
+            println!("The backup from {created_at} was restored.");
+        }
+        Err(err) => println!("Could not restore the backup, {err}."),
+    }
+}
+
+/// Shows how amounts of money are written.
+fn money_format_show(money_format: &MoneyFormat) {
+    println!("Decimal places: {}", money_format.decimal_places());
+    match money_format.thousands_separator() {
+        Some(' ') => println!("Thousands separator: space"),
+        Some(separator) => println!("Thousands separator: {separator}"),
+        None => println!("Thousands separator: none"),
+    }
+    println!(
+        "Rounding: {} (2.345 is shown as {})",
+        money_format.rounding(),
+        format_money(2.345)
+    );
+    println!(
+        "Currency: {} ({} the amount)",
+        currency_symbol(),
+        money_format.symbol_position()
+    );
+    println!("Example: {}", format_price(1234567.5));
+}
+
+/// Changes the bcrypt cost of new password hashes.
+///
+/// Existing hashes are made stronger when their users log in next.
+fn bcrypt_cost_set(bcrypt_cost: &mut u32, cost: &str) {
+    let Some(cost) = parse_arg(cost, "cost") else {
+        return;
+    };
+    if !set_bcrypt_cost(cost) {
+        println!(
+            "The cost must be between {} and {}.",
+            BCRYPT_COSTS.start(),
+            BCRYPT_COSTS.end()
+        );
+        return;
+    }
+    *bcrypt_cost = cost;
+    println!("New passwords are hashed with cost {cost}.");
+}
+
+/// Changes how amounts of money are rounded.
+fn money_format_rounding(money_format: &mut MoneyFormat, rounding: &str) {
+    let Some(rounding) = RoundingMode::ALL
+        .into_iter()
+        .find(|mode| mode.to_string() == rounding)
+    else {
+        println!("Unknown rounding mode: {rounding}");
+        println!("The rounding modes are half-up, half-even and truncate.");
+        return;
+    };
+    money_format.set_rounding(rounding);
+    money_format.apply();
+    money_format_show(money_format);
+}
+
+/// Changes the number of decimal places amounts of money are written with.
+fn money_format_decimals(money_format: &mut MoneyFormat, decimal_places: &str) {
+    let Some(decimal_places) = parse_arg(decimal_places, "number of decimal places") else {
+        return;
+    };
+    if !money_format.set_decimal_places(decimal_places) {
+        println!(
+            "At most {} decimal places can be used.",
+            MoneyFormat::MAX_DECIMAL_PLACES
+        );
+        return;
+    }
+    money_format.apply();
+    money_format_show(money_format);
+}
+
+/// Changes the thousands separator, `none` removes it and `space` uses a space.
+fn money_format_separator(money_format: &mut MoneyFormat, separator: &str) {
+    let separator = match separator {
+        "none" => None,
+        "space" => Some(' '),
+        _ => {
+            let mut chars = separator.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => {
+                    println!("The separator must be a single character, none or space.");
+                    return;
+                }
+            }
+        }
+    };
+    if !money_format.set_thousands_separator(separator) {
+        println!("Digits, '.' and '-' can not be used as separator.");
+        return;
+    }
+    money_format.apply();
+    money_format_show(money_format);
+}
+
+/// Changes on which side of amounts the currency symbol is written.
+fn money_format_symbol_position(money_format: &mut MoneyFormat, position: &str) {
+    let Some(position) = SymbolPosition::ALL
+        .into_iter()
+        .find(|candidate| candidate.to_string() == position)
+    else {
+        println!("The currency symbol can be put before or after the amount.");
+        return;
+    };
+    money_format.set_symbol_position(position);
+    money_format.apply();
+    money_format_show(money_format);
+}
+
+/// Changes the currency symbol amounts of money are written with.
+fn currency_symbol_set(money_format: &MoneyFormat, currency_symbol: &mut String, symbol: &str) {
+    if symbol
+        .chars()
+        .any(|c| c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        println!("Digits, '.' and '-' can not be used in the currency symbol.");
+        return;
+    }
+    set_currency_symbol(symbol);
+    *currency_symbol = symbol.to_owned();
+    money_format_show(money_format);
+}
+
+/// Makes a user who must change their password choose a new one right after logging in.
+///
+/// Returns false if the user gave up by leaving the password blank or typing "cancel", or after
+/// too many failed attempts. They must then be logged out.
+fn forced_password_change(user: &mut User) -> bool {
+    const MAX_ATTEMPTS: u32 = 3;
+
+    println!("You must choose a new password before continuing, leave it blank to log out.");
+    for _ in 0..MAX_ATTEMPTS {
+        let password = read_password("New password: ");
+        if password.trim().is_empty() || password.trim() == "cancel" {
+            return false;
+        }
+        if user.verify_password(&password) {
+            println!("The new password must be different from the current one.");
+            continue;
+        }
+        if read_password("Repeat new password: ") != password {
+            println!("The passwords do not match.");
+            continue;
+        }
+        match user.set_password(&password) {
+            Ok(()) => {
+                audit(user.username(), "password changed");
+                println!("Password changed.");
+                return true;
+            }
+            Err(err) => println!("Sorry, {err}."),
+        }
+    }
+    false
+}
+
+/// Asks user for their current password and a new one, and changes it.
+///
+/// The change is aborted after three wrong current passwords.
+fn change_password(user: &mut User) {
+    const MAX_ATTEMPTS: u32 = 3;
+
+    let mut attempts = 0;
+    while !user.verify_password(&read_password("Current password: ")) {
+        attempts += 1;
+        if attempts == MAX_ATTEMPTS {
+            audit(
+                user.username(),
+                "password change refused after wrong passwords",
+            );
+            println!("Too many wrong passwords, the password was not changed.");
+            return;
+        }
+        println!("Wrong password.");
+    }
+    let password = read_password("New password: ");
+    if read_password("Repeat new password: ") != password {
+        println!("The passwords do not match, the password was not changed.");
+        return;
+    }
+    match user.set_password(&password) {
+        Ok(()) => {
+            audit(user.username(), "password changed");
+            println!("Password changed.");
+        }
+        Err(err) => println!("Sorry, {err}, the password was not changed."),
+    }
+}
+
+/// Prints the profile of the user with what is left of their monthly budget.
+fn profile(user: &User, order_manager: &OrderManager) {
+    user.view();
+    let orders = order_manager
+        .orders()
+        .iter()
+        .filter(|order| order.username() == user.username())
+        .count();
+    println!("  orders: {orders}");
+    if let Some(budget) = user.monthly_budget() {
+        let spent = order_manager.spent_in_month(user.username(), chrono::Local::now());
+        println!(
+            "  monthly budget: {budget}, {left} left this month",
+            budget = format_price(budget),
+            left = format_price((budget - spent).max(0.0))
+        );
+    }
+}
+
+/// Changes the email of the user after asking for their password.
+fn profile_email(user_manager: &mut UserManager, username: &str, email: &str) {
+    let Some(user) = user_manager.find(username) else {
+        return;
+    };
+    if !user.verify_password(&read_password("Password: ")) {
+        println!("Wrong password, the email was not changed.");
+        return;
+    }
+    match user_manager.set_email(username, email) {
+        Ok(()) => {
+            audit(username, "email changed");
+            println!("Your email is now {}.", email.trim());
+        }
+        Err(err) => println!("Sorry, {err}."),
+    }
+}
+
+/// Sets how much the user wants to spend at most each month.
+fn budget_set(user: &mut User, amount: &str) {
+    let Some(amount) = parse_arg(amount, "budget") else {
+        return;
+    };
+    if user.set_monthly_budget(Some(amount)) {
+        println!("Your monthly budget is {}.", format_price(amount));
+    } else {
+        println!("The budget can not be negative.");
+    }
+}
+
+/// Prints the messages in the inbox of the user and marks them as read.
+fn inbox(user: &mut User) {
+    if user.inbox().is_empty() {
+        println!("Your inbox is empty.");
+        return;
+    }
+    for notification in user.inbox() {
+        let new = if notification.is_read() { "" } else { " (new)" };
+        println!(
+            "{}{new}: {}",
+            notification.at().format("%Y-%m-%d %H:%M"),
+            notification.message()
+        );
+    }
+    user.mark_inbox_read();
+}
+
+/// Changes how many hours open orders can wait to be paid, 0 to never cancel them.
+fn payment_deadline(order_manager: &mut OrderManager, hours: &str) {
+    let Some(hours) = parse_arg(hours, "number of hours") else {
+        return;
+    };
+    order_manager.set_payment_deadline_hours(hours);
+    if hours == 0 {
+        println!("Unpaid orders are never cancelled.");
+    } else {
+        println!("Unpaid orders are cancelled after {hours} hours.");
+    }
+}
+
+/// Changes the most of one product a cart can have, 0 for no maximum.
+fn max_line_quantity(catalog: &mut Catalog, max: &str) {
+    let Some(max) = parse_arg(max, "quantity") else {
+        return;
+    };
+    catalog.set_max_line_quantity(max);
+    if max == 0 {
+        println!("Carts can have any quantity of a product.");
+    } else {
+        println!("Carts can have at most {max} of a product.");
+    }
+}
+
+/// Changes how many unpaid orders a customer can have at once, 0 for no limit.
+fn max_open_orders(order_manager: &mut OrderManager, max: &str) {
+    let Some(max) = parse_arg(max, "number of orders") else {
+        return;
+    };
+    order_manager.set_max_open_orders(max);
+    if max == 0 {
+        println!("Customers can have any number of unpaid orders.");
+    } else {
+        println!("Customers can have at most {max} unpaid order(s).");
+    }
+}
+
+/// Prints a receipt by its number, admins can see all receipts and users only their own.
+fn receipt_show(receipt_manager: &ReceiptManager, user: &User, receipt_number: &str) {
+    let Some(receipt_number) = parse_arg(receipt_number, "receipt number") else {
+        return;
+    };
+    match receipt_manager.receipt(receipt_number) {
+        Some(receipt) if user.is_admin() || receipt.username() == user.username() => receipt.view(),
+        _ => println!("Receipt not found."),
+    }
+}
+
+/// Prints all receipts of an order, admins can see all orders and users only their own.
+fn receipts_for_order(receipt_manager: &ReceiptManager, user: &User, order_id: &str) {
+    let Some(order_id) = parse_arg::<u64>(order_id, "order ID") else {
+        return;
+    };
+    receipt_manager
+        .receipts()
+        .iter()
+        .filter(|receipt| receipt.order_id() == order_id)
+        .filter(|receipt| user.is_admin() || receipt.username() == user.username())
+        .for_each(View::view);
+}
+
+/// Asks admin to add money to the wallet of a user.
+fn wallet_credit(
+    user_manager: &mut UserManager,
+    transaction_log: &mut TransactionLog,
+    username: &str,
+    amount: &str,
+) {
+    let Some(amount) = parse_arg::<f64>(amount, "amount") else {
+        return;
+    };
+    if !is_valid_amount(amount) {
+        println!("The amount must be positive.");
+        return;
+    }
+    match user_manager.find_mut(username) {
+        Some(user) => {
+            user.credit_wallet(amount);
+            transaction_log.record(
+                TransactionKind::WalletTopUp,
+                None,
+                username,
+                "wallet".to_owned(),
+                amount,
+            );
+            println!(
+                "Wallet balance of {username}: {}",
+                format_price(user.wallet_balance())
+            );
+        }
+        None => println!("User not found."),
+    }
+}
+
+/// Lists all users with their role, status and number of orders.
+fn user_list(user_manager: &UserManager, order_manager: &OrderManager) {
+    println!("Users:");
+    for user in user_manager.users() {
+        let orders = order_manager
+            .orders()
+            .iter()
+            .filter(|order| order.username() == user.username())
+            .count();
+        print!(
+            "  {} <{}> - {}, {orders} order(s)",
+            user.username(),
+            user.email(),
+            user.role()
+        );
+        if !user.is_active() {
+            print!(", deactivated");
+        }
+        match user.last_login() {
+            Some(last_login) => print!(", last login {}", last_login.format("%Y-%m-%d")),
+            None => print!(", never logged in"),
+        }
+        println!();
+    }
+}
+
+/// Shows the spending summary of a user.
+fn user_stats(user_manager: &UserManager, order_manager: &OrderManager, username: &str) {
+    match user_manager.find(username) {
+        Some(user) => reports::user_summary(order_manager, user).view(),
+        None => println!("User not found."),
+    }
+}
+
+/// Shows the profile, cart and spending of a user.
+fn user_show(user_manager: &UserManager, order_manager: &OrderManager, username: &str) {
+    let Some(user) = user_manager.find(username) else {
+        println!("User not found.");
+        return;
+    };
+    user.view();
+    println!("  role: {}", user.role());
+    if user.must_change_password() {
+        println!("  must change password: yes");
+    }
+    println!("  active: {}", if user.is_active() { "yes" } else { "no" });
+    let locked = user.is_login_locked(chrono::Local::now(), user_manager.login_lock_minutes());
+    println!("  locked: {}", if locked { "yes" } else { "no" });
+    println!(
+        "  cart: {} item(s), {}",
+        user.cart().iter().len(),
+        format_price(user.cart().total_price())
+    );
+    let report = CustomerReport::from_orders(order_manager.orders());
+    let (total_spend, paid_orders) = report
+        .customers()
+        .iter()
+        .find(|customer| customer.username() == user.username())
+        .map_or((0.0, 0), |customer| {
+            (customer.total_spend(), customer.order_count())
+        });
+    println!(
+        "  total spend: {} in {paid_orders} paid order(s)",
+        format_price(total_spend)
+    );
+}
+
+/// Lets a user whose login is locked after too many wrong passwords log in again.
+fn user_unlock(user_manager: &mut UserManager, actor: &str, username: &str) {
+    if user_manager.unlock(username) {
+        audit(actor, &format!("user {username} unlocked"));
+        println!("{username} can log in again.");
+    } else {
+        println!("User not found.");
+    }
+}
+
+/// Changes how many minutes logins stay locked after too many wrong passwords, 0 until an admin
+/// unlocks them.
+fn login_lock_set(user_manager: &mut UserManager, minutes: &str) {
+    let Some(minutes) = parse_arg(minutes, "number of minutes") else {
+        return;
+    };
+    user_manager.set_login_lock_minutes(minutes);
+    if minutes == 0 {
+        println!("Locked logins stay locked until an admin unlocks them.");
+    } else {
+        println!("Locked logins are unlocked after {minutes} minutes.");
+    }
+}
+
+/// Allows or refuses the logins of a user.
+fn user_set_active(user_manager: &mut UserManager, actor: &str, username: &str, active: bool) {
+    match user_manager.set_active(username, active) {
+        Ok(()) if active => {
+            audit(actor, &format!("user {username} activated"));
+            println!("{username} can log in again.");
+        }
+        Ok(()) => {
+            audit(actor, &format!("user {username} deactivated"));
+            println!("{username} can no longer log in.");
+        }
+        Err(err) => println!("Sorry, {err}."),
+    }
+}
+
+/// Deletes a user after a typed confirmation.
+///
+/// Users deleting their own account must enter their password again. If the user still has open
+/// orders they are told those will be cancelled and must confirm once more.
+fn delete_user(app: &mut CoronaApplication, actor: &str, username: &str) {
+    let Some(user) = app.user_manager.find(username) else {
+        println!("User not found.");
+        return;
+    };
+    let username = user.username().to_owned();
+    if actor == username && !user.verify_password(&read_password("Password: ")) {
+        println!("Wrong password, the account was not deleted.");
+        return;
+    }
+    let open_orders = app
+        .order_manager
+        .orders()
+        .iter()
+        .filter(|o| o.username() == username && o.is_open())
+        .count();
+    if open_orders > 0 {
+        println!("Warning: {username} has {open_orders} open order(s), they will be cancelled.");
+        if !read_yes_no("Delete anyway? (y/n): ") {
+            println!("Nothing was deleted.");
+            return;
+        }
+    }
+    println!("Paid orders and receipts are kept, but the account cannot be restored.");
+    if read_line(&format!("Type \"{username}\" to delete the account: ")) != username {
+        println!("Nothing was deleted.");
+        return;
+    }
+    match app.delete_user(&username) {
+        Ok(()) => {
+            audit(actor, &format!("user {username} deleted"));
+            println!("The account of {username} was deleted.");
+        }
+        Err(err) => println!("Sorry, {err}."),
+    }
+}
+
+/// Makes a user choose a new password the next time they log in.
+fn user_require_password_change(user_manager: &mut UserManager, actor: &str, username: &str) {
+    if user_manager.require_password_change(username) {
+        audit(actor, &format!("password change required for {username}"));
+        println!("{username} must change their password at the next login.");
+    } else {
+        println!("User not found.");
+    }
+}
+
+/// Changes the role of a user, which decides the commands they can use.
+fn user_set_role(user_manager: &mut UserManager, actor: &str, username: &str, role: &str) {
+    let Ok(role) = role.parse::<Role>() else {
+        println!("Unknown role: {role}");
+        println!("The roles are customer, staff, courier and admin.");
+        return;
+    };
+    match user_manager.set_role(username, role) {
+        Ok(()) => {
+            audit(actor, &format!("user {username} made {role}"));
+            println!("{username} is now {role}.");
+        }
+        Err(err) => println!("Sorry, {err}."),
+    }
+}
+
+/// Asks admin to set the status of a card in the simulated bank.
+fn card_set(card_registry: &mut CardRegistry, card_number: &str, status: &str) {
+    if let Some(status) = parse_arg(status, "card status") {
+        card_registry.set_status(normalize_card_number(card_number), status);
+        println!("Card is now {status}.");
+    }
+}
+
+/// Menu for logged in users.
+///
+/// The user is looked up again for every command so admin commands can also change other users.
+fn logged_in_menu(app: &mut CoronaApplication, username: &str) {
+    let prompt = format!("({username}) >>> ");
+    if let Some(user) = app.user_manager.find(username) {
+        let items = user.cart().iter().count();
+        if items > 0 {
+            println!(
+                "You have {items} item(s) in your cart from last time (total {}).",
+                format_money(user.cart().total_price())
+            );
+        }
+    }
+    loop {
+        if let Err(err) = app.checkpoint() {
+            println!("Warning: the changes could not be saved automatically, {err}.");
+        }
+        let timeout = (app.idle_timeout_secs > 0)
+            .then(|| std::time::Duration::from_secs(app.idle_timeout_secs));
+        let Some(line) = read_line_timeout(&prompt, timeout) else {
+            println!();
+            println!(
+                "You were logged out after {} seconds without a command.",
+                app.idle_timeout_secs
+            );
+            audit(username, "logged out after being idle");
+            break;
+        };
+        let line = app.command_aliases.resolve(username, &line);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let CoronaApplication {
+            user_manager,
+            catalog,
+            order_manager,
+            card_registry,
+            gift_card_manager,
+            promo_code_manager,
+            receipt_manager,
+            transaction_log,
+            money_format,
+            store_name,
+            currency_symbol,
+            idle_timeout_secs,
+            max_backups,
+            autosave,
+            bcrypt_cost,
+            command_aliases,
+            activity_log,
+            payment_processor,
+            ..
+        } = &mut *app;
+        let Some(user) = user_manager.find_mut(username) else {
+            break;
+        };
+        if user.role() != Role::Customer && !words.is_empty() {
+            activity_log.log_activity(username, ActivityKind::Command, words.join(" "));
+        }
+        match words.as_slice() {
+            ["product", "add"] if user.is_admin() => product_add(catalog, user.username()),
+            ["product", "remove"] if user.is_admin() => product_remove(catalog, user.username()),
+            ["product", "list"] | ["product", "ls"] | ["catalog"] | ["products"] => catalog.view(),
+            ["product", "list" | "ls", "--all"] if user.is_admin() => product_list_all(catalog),
+            ["product", "list" | "ls", "under", max] => {
+                product_list_by_price(catalog, None, Some(max))
+            }
+            ["product", "list" | "ls", "over", min] => {
+                product_list_by_price(catalog, Some(min), None)
+            }
+            ["product", "list" | "ls", "between", min, max] => {
+                product_list_by_price(catalog, Some(min), Some(max))
+            }
+            ["product", "show", code] => product_show(catalog, code),
+            ["product", "compare", first, second] => product_compare(catalog, first, second),
+            ["product", "reorder", code, point] if user.has_role(STAFF) => {
+                product_reorder_point(catalog, user.username(), code, point)
+            }
+            ["product", "price", code, price] if user.is_admin() => {
+                product_price(catalog, user.username(), code, price)
+            }
+            ["product", "feature", code] if user.is_admin() => {
+                product_feature(catalog, user.username(), code, true)
+            }
+            ["product", "unfeature", code] if user.is_admin() => {
+                product_feature(catalog, user.username(), code, false)
+            }
+            ["product", "tax-exempt", code, exempt] if user.is_admin() => {
+                product_tax_exempt(catalog, user.username(), code, exempt)
+            }
+            ["product", "restock", code, quantity] if user.has_role(STAFF) => {
+                product_restock(catalog, user.username(), code, quantity, false)
+            }
+            ["product", "restock", code, quantity, "--set"]
+            | ["product", "restock", "--set", code, quantity]
+                if user.has_role(STAFF) =>
+            {
+                product_restock(catalog, user.username(), code, quantity, true)
+            }
+            ["cart", "add"] | ["add"] => cart_add(user, catalog, None, None),
+            ["cart", "add", item] | ["add", item] => cart_add(user, catalog, Some(item), None),
+            ["cart", "add", item, quantity] | ["add", item, quantity] => {
+                cart_add(user, catalog, Some(item), Some(quantity))
+            }
+            ["cart", "remove"] => cart_remove(user),
+            ["cart", "show", username] if user.is_admin() => cart_show_of(user_manager, username),
+            ["cart", "fix", username, "remove", code] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                cart_fix_remove(user_manager, &actor, username, code)
+            }
+            ["cart", "list"] | ["cart", "ls"] | ["cart"] => user.cart().view(),
+            ["quote"] => quote(user),
+            ["profile"] => profile(user, order_manager),
+            ["profile", "email", email] => profile_email(user_manager, username, email),
+            ["budget", "set", amount] => budget_set(user, amount),
+            ["budget", "clear"] => {
+                user.set_monthly_budget(None);
+                println!("Your monthly budget was removed.");
+            }
+            ["inbox"] => inbox(user),
+            ["password"] => change_password(user),
+            ["alias"] => alias_list(command_aliases, username),
+            ["alias", name, command @ ..] if !command.is_empty() => {
+                alias_define(command_aliases, username, name, command)
+            }
+            ["unalias", name] => {
+                if !command_aliases.remove(username, name) {
+                    println!("You have no alias named \"{name}\".");
+                }
+            }
+            ["delete", "account"] => {
+                let actor = user.username().to_owned();
+                delete_user(app, &actor, &actor)
+            }
+            ["favorite", "add", code] => favorite_add(user, catalog, code),
+            ["favorite", "remove", code] => user.remove_favorite(code),
+            ["favorite", "list"] | ["favorites"] => list_favorites(user, catalog),
+            ["order", "favorites"] => order_favorites(user, catalog),
+            ["order", "list", "from", from, "to", to] if user.has_role(STAFF) => {
+                order_list_between(order_manager, from, to)
+            }
+            ["order", "list"] | ["order", "ls"] | ["orders"] if user.has_role(STAFF) => {
+                order_manager.view()
+            }
+            ["order", "list"] | ["order", "ls"] | ["orders"] => {
+                list_orders_for_user(order_manager, user)
+            }
+            ["deliveries"] if user.has_role(DELIVERY) => deliveries(order_manager, user.username()),
+            ["order", "deliver" | "slip", order_id] | ["order", "collect", order_id, _]
+                if user.role() == Role::Courier
+                    && !is_assigned_to(order_manager, order_id, user.username()) =>
+            {
+                println!("Order not found.")
+            }
+            ["order", "assign", order_id, courier] if user.has_role(STAFF) => {
+                let actor = user.username().to_owned();
+                order_assign(order_manager, user_manager, &actor, order_id, courier)
+            }
+            ["order", "deliver", order_id] if user.has_role(DELIVERY) => {
+                order_deliver(order_manager, order_id)
+            }
+            ["order", "status", args @ ..] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                order_status(app, &actor, args)
+            }
+            ["order", "slip", order_id] if user.has_role(DELIVERY) => {
+                order_slip(order_manager, order_id)
+            }
+            ["order", "export"] if user.is_admin() => order_export(order_manager),
+            ["order", "invoice", order_id] => {
+                let username = user.username().to_owned();
+                order_invoice(order_manager, user_manager, store_name, &username, order_id)
+            }
+            ["order", "edit", order_id] => order_edit(order_manager, catalog, user, order_id),
+            ["order", "collect", order_id, amount] if user.has_role(DELIVERY) => {
+                let actor = user.username().to_owned();
+                order_collect(
+                    order_manager,
+                    user_manager,
+                    receipt_manager,
+                    transaction_log,
+                    activity_log,
+                    &actor,
+                    order_id,
+                    amount,
+                )
+            }
+            ["order"] | ["checkout"] => checkout(user, catalog, order_manager, activity_log),
+            ["pay"] => pay(
+                user,
+                order_manager,
+                card_registry,
+                gift_card_manager,
+                promo_code_manager,
+                receipt_manager,
+                transaction_log,
+                activity_log,
+                payment_processor.as_mut(),
+            ),
+            ["receipt", receipt_number] => receipt_show(receipt_manager, user, receipt_number),
+            ["receipts", order_id] => receipts_for_order(receipt_manager, user, order_id),
+            ["giftcard", "create", value] if user.is_admin() => {
+                giftcard_create(gift_card_manager, value)
+            }
+            ["giftcard", "list"] | ["giftcards"] if user.is_admin() => gift_card_manager.view(),
+            ["promo", "add"] if user.is_admin() => promo_add(promo_code_manager),
+            ["promo", "list"] | ["promos"] if user.is_admin() => promo_code_manager.view(),
+            ["card", "set", card_number, status] if user.is_admin() => {
+                card_set(card_registry, card_number, status)
+            }
+            ["card", "policy", "accept"] if user.is_admin() => {
+                card_registry.set_accept_unknown_cards(true)
+            }
+            ["card", "policy", "reject"] if user.is_admin() => {
+                card_registry.set_accept_unknown_cards(false)
+            }
+            ["card", "registry"] if user.is_admin() => card_registry.view(),
+            ["card", "add"] => card_add(user),
+            ["card", "list"] | ["cards"] => list_saved_cards(user),
+            ["card", "remove", index] => card_remove(user, index),
+            ["address", "add"] => address_add(user),
+            ["address", "list"] | ["addresses"] => list_addresses(user),
+            ["address", "remove", index] => address_remove(user, index),
+            ["wallet"] => println!("Wallet balance: {}", format_price(user.wallet_balance())),
+            ["user", "list"] | ["users"] if user.is_admin() => {
+                user_list(user_manager, order_manager)
+            }
+            ["user", "show", username] if user.is_admin() => {
+                user_show(user_manager, order_manager, username)
+            }
+            ["user", "stats", username] if user.is_admin() => {
+                user_stats(user_manager, order_manager, username)
+            }
+            ["my", "stats"] => reports::user_summary(order_manager, user).view(),
+            ["user", "activate" | "enable", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_set_active(user_manager, &actor, username, true)
+            }
+            ["user", "deactivate" | "disable", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_set_active(user_manager, &actor, username, false)
+            }
+            ["user", "delete", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                delete_user(app, &actor, username)
+            }
+            ["user", "reset", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_reset(user_manager, &actor, username)
+            }
+            ["user", "resetpw", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_reset_password(user_manager, &actor, username)
+            }
+            ["user", "unlock", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_unlock(user_manager, &actor, username)
+            }
+            ["user", "require-password-change", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_require_password_change(user_manager, &actor, username)
+            }
+            ["user", "role", username, role] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_set_role(user_manager, &actor, username, role)
+            }
+            ["user", "promote", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_set_role(user_manager, &actor, username, "admin")
+            }
+            ["user", "demote", username] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                user_set_role(user_manager, &actor, username, "customer")
+            }
+            ["wallet", "credit", username, amount] if user.is_admin() => {
+                wallet_credit(user_manager, transaction_log, username, amount)
+            }
+            ["return", "list"] | ["returns"] => list_returns(order_manager, user),
+            ["return", "approve", return_id] if user.is_admin() => {
+                return_update(order_manager, return_id, ReturnStatus::Approved)
+            }
+            ["return", "reject", return_id] if user.is_admin() => {
+                return_update(order_manager, return_id, ReturnStatus::Rejected)
+            }
+            ["return", "refund", return_id] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                return_refund(app, &actor, return_id)
+            }
+            ["transactions"] if user.is_admin() => transaction_log.view(),
+            ["save", "as", path] if user.is_admin() => {
+                let actor = user.username().to_owned();
+                save_as(app, &actor, path)
+            }
+            ["activity"] if user.is_admin() => activity_show(activity_log, None, None),
+            ["activity", count] if user.is_admin() && count.parse::<usize>().is_ok() => {
+                activity_show(activity_log, None, Some(count))
+            }
+            ["activity", username] if user.is_admin() => {
+                activity_show(activity_log, Some(username), None)
+            }
+            ["activity", username, count] if user.is_admin() => {
+                activity_show(activity_log, Some(username), Some(count))
+            }
+            ["ledger"] if user.is_admin() => ledger_show(transaction_log, None),
+            ["ledger", "balance"] if user.is_admin() => ledger_balance(transaction_log),
+            ["ledger", count] if user.is_admin() => ledger_show(transaction_log, Some(count)),
+            ["report", "stock"] if user.has_role(STAFF) => report_stock(catalog, None),
+            ["report", "reorder"] if user.has_role(STAFF) => report_reorder(catalog, true),
+            ["report", "stock", threshold] if user.has_role(STAFF) => {
+                report_stock(catalog, Some(threshold))
+            }
+            ["report", "customers"] if user.is_admin() => report_customers(order_manager, None),
+            ["report", "tax"] if user.is_admin() => report_tax(order_manager),
+            ["report", "revenue"] if user.is_admin() => report_revenue(order_manager),
+            ["report", "customers", count] if user.is_admin() => {
+                report_customers(order_manager, Some(count))
+            }
+            ["settings", "payment", "deadline", hours] if user.is_admin() => {
+                payment_deadline(order_manager, hours)
+            }
+            ["settings", "cart", "max", max] if user.is_admin() => max_line_quantity(catalog, max),
+            ["settings", "activity", "max", max] if user.is_admin() => {
+                max_activity_entries(activity_log, max)
+            }
+            ["settings", "open", "orders", max] if user.is_admin() => {
+                max_open_orders(order_manager, max)
+            }
+            ["settings", "login", "lock", minutes] if user.is_admin() => {
+                login_lock_set(user_manager, minutes)
+            }
+            ["settings", "autosave", state] if user.is_admin() => autosave_set(autosave, state),
+            ["settings", "backups", count] if user.is_admin() => {
+                max_backups_set(max_backups, count)
+            }
+            ["settings", "idle", "timeout", seconds] if user.is_admin() => {
+                idle_timeout_set(idle_timeout_secs, seconds)
+            }
+            ["settings", "password", "cost", cost] if user.is_admin() => {
+                bcrypt_cost_set(bcrypt_cost, cost)
+            }
+            ["settings", "name", name @ ..] if user.is_admin() => store_name_set(store_name, name),
+            ["settings", "money"] if user.is_admin() => money_format_show(money_format),
+            ["settings", "money", "decimals", decimal_places] if user.is_admin() => {
+                money_format_decimals(money_format, decimal_places)
+            }
+            ["settings", "money", "rounding", rounding] if user.is_admin() => {
+                money_format_rounding(money_format, rounding)
+            }
+            ["settings", "money", "separator", separator] if user.is_admin() => {
+                money_format_separator(money_format, separator)
+            }
+            ["settings", "money", "symbol", position] if user.is_admin() => {
+                money_format_symbol_position(money_format, position)
+            }
+            ["settings", "money", "currency", symbol] if user.is_admin() => {
+                currency_symbol_set(money_format, currency_symbol, symbol)
+            }
+            ["return", order_id, code] => return_request(user, order_manager, order_id, code),
+            ["q"] | ["quit"] | ["exit"] | ["logout"] => break,
+            [] => {}
+            _ => {
+                println!("I don't understand what you are saying!!!");
+            }
+        }
+    }
+}
+
+/// Menu for users not logged in.
+pub(crate) fn main(app: &mut CoronaApplication) {
+    print_banner();
+    for usernames in app.user_manager.colliding_usernames() {
+        println!(
+            "Warning: the usernames {} only differ in case, type them exactly to log in.",
+            usernames.join(", ")
+        );
+    }
+    loop {
+        if let Err(err) = app.checkpoint() {
+            println!("Warning: the changes could not be saved automatically, {err}.");
+        }
+        let line = read_line(">>> ");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["register"] => register(&mut app.user_manager),
+            ["login"] => login(app),
+            ["forgot", "password"] => forgot_password(&mut app.user_manager, app.mailer.as_ref()),
+            ["reset"] => reset_password_menu(&mut app.user_manager),
+            ["restore"] => restore(app),
+            ["save"] => {
+                if let Err(err) = app.save() {
+                    println!("Failed to save, {err}.");
+                }
+            }
+            ["q"] | ["quit"] | ["exit"] => break,
+            [] => {}
+            _ => {
+                println!("I don't understand what you are saying!!!")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A payment processor that records what it was asked to authorize and refuses the payment
+    /// methods it is told to
+    #[derive(Default)]
+    struct MockProcessor {
+        calls: Rc<RefCell<Vec<(String, f64)>>>,
+        refuse_cards: bool,
+    }
+
+    impl PaymentProcessor for MockProcessor {
+        fn authorize(
+            &mut self,
+            payment: &OrderPayment,
+            amount: f64,
+            _card_registry: &CardRegistry,
+        ) -> Result<AuthId, PaymentError> {
+            self.calls.borrow_mut().push((payment.to_string(), amount));
+            match payment {
+                OrderPayment::CreditCard { .. } if self.refuse_cards => {
+                    Err(PaymentError::CardDeclined(CardStatus::InsufficientFunds))
+                }
+                _ => Ok(AuthId(self.calls.borrow().len() as u64)),
+            }
+        }
+    }
+
+    /// A customer with `balance` in their wallet
+    fn user_with_wallet(balance: f64) -> User {
+        let mut user: User = serde_json::from_value(serde_json::json!({
+            "username": "alice",
+            "password_hash": "",
+            "email": "alice@example.com",
+            "cart": [],
+        }))
+        .unwrap();
+        user.credit_wallet(balance);
+        user
+    }
+
+    #[test]
+    fn wallet_payments_are_only_taken_when_authorized() {
+        let mut processor = SimulatedProcessor::default();
+        let card_registry = CardRegistry::default();
+        let mut user = user_with_wallet(20.0);
+
+        let payment = pay_with_wallet(&mut user, &mut processor, &card_registry, 25.0);
+        assert!(matches!(payment, Err(PaymentAbort::Declined)));
+        assert_eq!(user.wallet_balance(), 20.0);
+
+        // The processor refuses amounts that round to nothing
+        let payment = pay_with_wallet(&mut user, &mut processor, &card_registry, 0.001);
+        assert!(matches!(payment, Err(PaymentAbort::Declined)));
+        assert_eq!(user.wallet_balance(), 20.0);
+
+        let payment = pay_with_wallet(&mut user, &mut processor, &card_registry, 12.5);
+        assert!(matches!(payment, Ok(OrderPayment::Wallet)));
+        assert_eq!(user.wallet_balance(), 7.5);
+    }
+
+    #[test]
+    fn payments_go_through_the_injected_processor() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut app = CoronaApplication {
+            payment_processor: Box::new(MockProcessor {
+                calls: Rc::clone(&calls),
+                refuse_cards: true,
+            }),
+            ..Default::default()
+        };
+        let card = OrderPayment::CreditCard {
+            card_number: "4111111111111111".to_owned(),
+            expiry_month: 1,
+            expiry_year: 2030,
+        };
+
+        let processor = app.payment_processor.as_mut();
+        let refused = authorize(processor, &app.card_registry, &card, 30.0);
+        assert!(matches!(refused, Err(PaymentAbort::Declined)));
+        let authorized = authorize(processor, &app.card_registry, &OrderPayment::Cash, 30.0);
+        assert!(authorized.is_ok());
+
+        let mut user = user_with_wallet(50.0);
+        let paid = pay_with_wallet(&mut user, processor, &app.card_registry, 20.0);
+        assert!(matches!(paid, Ok(OrderPayment::Wallet)));
+        assert_eq!(
+            *calls.borrow(),
+            [
+                ("credit card **** **** **** 1111 exp 01/30".to_owned(), 30.0),
+                ("cash".to_owned(), 30.0),
+                ("wallet".to_owned(), 20.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn declined_payments_leave_the_promo_code_unused() {
+        let mut promo_code_manager = PromoCodeManager::default();
+        assert!(promo_code_manager.add("SPRING", 10, None, Some(1)));
+        let open_order = || -> Order {
+            serde_json::from_value(serde_json::json!({
+                "order_id": 1,
+                "username": "alice",
+                "items": [{"code": "A1", "name": "Apple", "price": 10.0, "quantity": 2.0}],
+                "delivery_address": "Street 1",
+                "state": {"order_state": "open"},
+            }))
+            .unwrap()
+        };
+        let mut order = open_order();
+
+        // The discount is shown before the user confirms, without counting the use
+        assert_eq!(
+            promo_code_manager.apply("spring", &mut order).ok(),
+            Some(2.0)
+        );
+        assert_eq!(order.total_price(), 18.0);
+        assert_eq!(promo_code_manager.promo_codes()[0].uses(), 0);
+
+        settle_promo(&mut promo_code_manager, &mut order, true, false);
+        assert!(order.promo().is_none());
+        assert_eq!(order.total_price(), 20.0);
+        assert_eq!(promo_code_manager.promo_codes()[0].uses(), 0);
+
+        // The code can still be used once, when the order is paid
+        assert!(promo_code_manager.apply("SPRING", &mut order).is_ok());
+        settle_promo(&mut promo_code_manager, &mut order, true, true);
+        assert_eq!(order.total_price(), 18.0);
+        assert_eq!(promo_code_manager.promo_codes()[0].uses(), 1);
+        let mut other = open_order();
+        assert!(matches!(
+            promo_code_manager.apply("SPRING", &mut other),
+            Err(PromoError::UsedUp)
+        ));
     }
 }
@@ -0,0 +1,207 @@
+use serde_json::{Map, Value};
+
+/// The current version of the layout of the saved state.
+///
+/// Version history:
+/// - 1: the initial layout (users, products, orders and the order sequence id)
+/// - 2: admins are marked by the `is_admin` field of users instead of the username "admin"
+/// - 3: users have a `role` instead of the `is_admin` field
+pub(crate) const SCHEMA_VERSION: u32 = 3;
+
+/// The version assumed for files that do not have a `schema_version` field
+const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+/// A step that upgrades a saved state of the version before `version` to `version`
+struct Migration {
+    version: u32,
+    /// What the step changes, for the audit log
+    description: &'static str,
+    apply: fn(&mut Value),
+}
+
+/// Every upgrade step, oldest first.
+///
+/// A change to the saved layout that older files cannot be read with adds a step here and bumps
+/// [`SCHEMA_VERSION`]. New fields with a serde default do not need one.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "the user named admin is marked as admin",
+        apply: mark_admin_user,
+    },
+    Migration {
+        version: 3,
+        description: "admins get the admin role instead of the is_admin flag",
+        apply: roles_from_is_admin,
+    },
+];
+
+/// Why a saved state could not be upgraded
+pub(crate) enum MigrationError {
+    /// The state is not a table of fields
+    NotATable,
+    /// The `schema_version` field is not a version number
+    InvalidVersion,
+    /// The state was written by a newer version of the application
+    TooNew(u32),
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotATable => f.write_str("the state is not a table"),
+            Self::InvalidVersion => f.write_str("schema_version is not a version number"),
+            Self::TooNew(version) => write!(
+                f,
+                "it has schema version {version} but this version of the application only \
+                 understands up to {SCHEMA_VERSION}"
+            ),
+        }
+    }
+}
+
+/// Upgrade a saved state, read as a plain value before it is turned into the application, to
+/// [`SCHEMA_VERSION`].
+///
+/// Returns the version and description of every step that was applied, oldest first. States
+/// from a newer version are refused rather than loaded with their newer data dropped.
+pub(crate) fn migrate(state: &mut Value) -> Result<Vec<(u32, &'static str)>, MigrationError> {
+    let table = state.as_object_mut().ok_or(MigrationError::NotATable)?;
+    let version = match table.get("schema_version") {
+        None => LEGACY_SCHEMA_VERSION,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or(MigrationError::InvalidVersion)?,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(MigrationError::TooNew(version));
+    }
+    let applied = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+        .map(|migration| {
+            (migration.apply)(state);
+            (migration.version, migration.description)
+        })
+        .collect();
+    state["schema_version"] = SCHEMA_VERSION.into();
+    Ok(applied)
+}
+
+/// The users of a saved state as tables of fields
+fn users_mut(state: &mut Value) -> impl Iterator<Item = &mut Map<String, Value>> {
+    state
+        .get_mut("users")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+
+/// Admins used to be recognized by the username "admin"
+fn mark_admin_user(state: &mut Value) {
+    for user in users_mut(state) {
+        if user.get("username").and_then(Value::as_str) == Some("admin") {
+            user.insert("is_admin".to_owned(), true.into());
+        }
+    }
+}
+
+/// Admins used to be marked by `is_admin`, everyone else was a customer
+fn roles_from_is_admin(state: &mut Value) {
+    for user in users_mut(state) {
+        if user
+            .remove("is_admin")
+            .and_then(|is_admin| is_admin.as_bool())
+            == Some(true)
+        {
+            user.insert("role".to_owned(), "admin".into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The role of each user in a migrated state, by username
+    fn roles(state: &Value) -> Vec<(&str, Option<&str>)> {
+        state["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| (user["username"].as_str().unwrap(), user["role"].as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn v1_admin_is_found_by_username() {
+        let mut state = json!({
+            "users": [{"username": "admin"}, {"username": "alice"}],
+        });
+        let applied = migrate(&mut state).ok().unwrap();
+        assert_eq!(
+            applied
+                .iter()
+                .map(|(version, _)| *version)
+                .collect::<Vec<_>>(),
+            [2, 3]
+        );
+        assert_eq!(roles(&state), [("admin", Some("admin")), ("alice", None)]);
+        assert!(state["users"][0].get("is_admin").is_none());
+        assert_eq!(state["schema_version"], SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn v2_is_admin_becomes_a_role() {
+        let mut state = json!({
+            "schema_version": 2,
+            "users": [
+                {"username": "admin", "is_admin": false},
+                {"username": "boss", "is_admin": true},
+            ],
+        });
+        let applied = migrate(&mut state).ok().unwrap();
+        assert_eq!(
+            applied
+                .iter()
+                .map(|(version, _)| *version)
+                .collect::<Vec<_>>(),
+            [3]
+        );
+        assert_eq!(roles(&state), [("admin", None), ("boss", Some("admin"))]);
+        assert!(state["users"][1].get("is_admin").is_none());
+        assert_eq!(state["schema_version"], SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let mut state = json!({
+            "schema_version": SCHEMA_VERSION,
+            "users": [{"username": "admin"}],
+        });
+        let before = state.clone();
+        assert!(migrate(&mut state).ok().unwrap().is_empty());
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn newer_and_invalid_versions_are_refused() {
+        let mut newer = json!({"schema_version": SCHEMA_VERSION + 1});
+        assert!(matches!(
+            migrate(&mut newer),
+            Err(MigrationError::TooNew(version)) if version == SCHEMA_VERSION + 1
+        ));
+        let mut invalid = json!({"schema_version": "three"});
+        assert!(matches!(
+            migrate(&mut invalid),
+            Err(MigrationError::InvalidVersion)
+        ));
+        assert!(matches!(
+            migrate(&mut json!([])),
+            Err(MigrationError::NotATable)
+        ));
+    }
+}
@@ -1,5 +1,356 @@
+use crate::migrations::{self, MigrationError};
 use serde::{Deserialize, Serialize};
 
+/// Where the audit log is written unless the `CORONA_AUDIT_LOG` environment variable is set,
+/// tests write it to the build directory instead of the working tree
+pub(crate) const AUDIT_LOG_PATH: &str = if cfg!(test) {
+    "target/test-audit.log"
+} else {
+    "audit.log"
+};
+
+/// Append a significant action to the audit log.
+///
+/// Each line has the time, the username of who did the action and what happened. Lines are only
+/// ever appended. Failing to write the log does not stop the application.
+pub(crate) fn audit(actor: &str, event: &str) {
+    use std::io::Write;
+
+    let path = std::env::var("CORONA_AUDIT_LOG").unwrap_or_else(|_| AUDIT_LOG_PATH.to_owned());
+    let line = format!(
+        "{} [{actor}] {event}\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+    );
+    if let Ok(mut file) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+    {
+        file.write_all(line.as_bytes()).ok();
+    }
+}
+
+/// Where the [`OutboxMailer`] writes emails unless the `CORONA_OUTBOX` environment variable is
+/// set, tests write it to the build directory instead of the working tree
+pub(crate) const OUTBOX_PATH: &str = if cfg!(test) {
+    "target/test-outbox.txt"
+} else {
+    "outbox.txt"
+};
+
+/// Something that sends emails to users.
+///
+/// The application only sends emails through this trait, so the stub can be replaced by a real
+/// mail server.
+pub(crate) trait Mailer {
+    /// Send an email, or say why it could not be sent
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+/// The mailer used without a mail server.
+///
+/// Emails are appended to the outbox file, see [`OUTBOX_PATH`], never shown in the terminal where
+/// whoever asked for them could read them.
+#[derive(Default)]
+pub(crate) struct OutboxMailer;
+
+impl Mailer for OutboxMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        use std::io::Write;
+
+        let path = std::env::var("CORONA_OUTBOX").unwrap_or_else(|_| OUTBOX_PATH.to_owned());
+        let mail = format!(
+            "{} to {to}: {subject}\n{body}\n\n",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+        );
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(mail.as_bytes()))
+            .map_err(|err| format!("{path}: {err}"))
+    }
+}
+
+/// Convert an amount of money to whole cents, rounding with the configured [`RoundingMode`]
+pub(crate) fn to_cents(amount: f64) -> i64 {
+    let rounding = MONEY_FORMAT.read().map(|f| f.rounding).unwrap_or_default();
+    rounding.round(amount * 100.0)
+}
+
+/// Round an amount of money to 2 decimal places with the configured [`RoundingMode`].
+///
+/// Every total shown to the user or charged to them goes through this function so the displayed
+/// and the charged amounts always match.
+pub(crate) fn round_money(amount: f64) -> f64 {
+    to_cents(amount) as f64 / 100.0
+}
+
+/// Check if an amount of money typed by the user can be a price or a payment, it must be a
+/// finite number above zero
+pub(crate) fn is_valid_amount(amount: f64) -> bool {
+    amount.is_finite() && amount > 0.0
+}
+
+/// How fractions of the smallest unit of money are rounded away
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RoundingMode {
+    /// Halves are rounded away from zero, 2.345 becomes 2.35
+    #[default]
+    HalfUp,
+    /// Halves are rounded to the even neighbour, 2.345 becomes 2.34 and 2.355 becomes 2.36
+    HalfEven,
+    /// The fraction is dropped, 2.349 becomes 2.34
+    Truncate,
+}
+
+impl RoundingMode {
+    pub(crate) const ALL: [RoundingMode; 3] = [Self::HalfUp, Self::HalfEven, Self::Truncate];
+
+    /// Round a scaled amount, like an amount in cents, to a whole number.
+    ///
+    /// Amounts within a millionth of a half or of a whole number count as that number, so values
+    /// like `2.345 * 100` (stored as `234.49999...`) round the way a person would expect.
+    pub(crate) fn round(self, scaled: f64) -> i64 {
+        const EPSILON: f64 = 1e-6;
+
+        let nudged = scaled + scaled.signum() * EPSILON;
+        match self {
+            Self::HalfUp => nudged.round() as i64,
+            Self::Truncate => nudged.trunc() as i64,
+            Self::HalfEven => {
+                let floor = scaled.floor();
+                if (scaled - floor - 0.5).abs() < EPSILON {
+                    let floor = floor as i64;
+                    if floor % 2 == 0 {
+                        floor
+                    } else {
+                        floor + 1
+                    }
+                } else {
+                    scaled.round() as i64
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for RoundingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::HalfUp => f.write_str("half-up"),
+            Self::HalfEven => f.write_str("half-even"),
+            Self::Truncate => f.write_str("truncate"),
+        }
+    }
+}
+
+/// On which side of an amount the currency symbol is written
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SymbolPosition {
+    /// Like `$5.00` or `EGP 5.00`
+    Before,
+    /// Like `5.00 EGP`
+    #[default]
+    After,
+}
+
+impl SymbolPosition {
+    pub(crate) const ALL: [SymbolPosition; 2] = [Self::Before, Self::After];
+}
+
+impl std::fmt::Display for SymbolPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Before => f.write_str("before"),
+            Self::After => f.write_str("after"),
+        }
+    }
+}
+
+/// How amounts of money are written out to the user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct MoneyFormat {
+    /// How many digits are shown after the decimal point
+    decimal_places: u8,
+    /// The character put between groups of three digits, if any
+    #[serde(default)]
+    thousands_separator: Option<char>,
+    /// How amounts are rounded, both when shown and when totals are computed
+    #[serde(default)]
+    rounding: RoundingMode,
+    /// On which side of amounts the currency symbol is written by [`format_price`]
+    #[serde(default)]
+    symbol_position: SymbolPosition,
+}
+
+/// The money format used by [`format_money`], set from the saved application on load
+static MONEY_FORMAT: std::sync::RwLock<MoneyFormat> = std::sync::RwLock::new(MoneyFormat::DEFAULT);
+
+impl MoneyFormat {
+    /// The most decimal places an amount can be shown with
+    pub(crate) const MAX_DECIMAL_PLACES: u8 = 4;
+
+    const DEFAULT: MoneyFormat = MoneyFormat {
+        decimal_places: 2,
+        thousands_separator: Some(','),
+        rounding: RoundingMode::HalfUp,
+        symbol_position: SymbolPosition::After,
+    };
+
+    pub(crate) fn decimal_places(&self) -> u8 {
+        self.decimal_places
+    }
+
+    pub(crate) fn thousands_separator(&self) -> Option<char> {
+        self.thousands_separator
+    }
+
+    pub(crate) fn rounding(&self) -> RoundingMode {
+        self.rounding
+    }
+
+    pub(crate) fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+
+    pub(crate) fn symbol_position(&self) -> SymbolPosition {
+        self.symbol_position
+    }
+
+    pub(crate) fn set_symbol_position(&mut self, symbol_position: SymbolPosition) {
+        self.symbol_position = symbol_position;
+    }
+
+    /// Change the number of decimal places. Fails if it is more than [`Self::MAX_DECIMAL_PLACES`].
+    pub(crate) fn set_decimal_places(&mut self, decimal_places: u8) -> bool {
+        if decimal_places > Self::MAX_DECIMAL_PLACES {
+            return false;
+        }
+        self.decimal_places = decimal_places;
+        true
+    }
+
+    /// Change the thousands separator. Digits and the decimal point can not be used.
+    pub(crate) fn set_thousands_separator(&mut self, separator: Option<char>) -> bool {
+        if separator.is_some_and(|c| c.is_ascii_digit() || c == '.' || c == '-') {
+            return false;
+        }
+        self.thousands_separator = separator;
+        true
+    }
+
+    /// Write an amount of money in this format, without the currency.
+    ///
+    /// Amounts are rounded to the decimal places with the rounding mode, the same way as
+    /// [`to_cents`], and the whole part is grouped with the thousands separator, like
+    /// `1,234.50`.
+    fn format(&self, amount: f64) -> String {
+        let scale = 10_i64.pow(self.decimal_places as u32);
+        let units = self.rounding.round(amount * scale as f64);
+
+        let mut text = String::new();
+        if units < 0 {
+            text.push('-');
+        }
+        let whole = (units.abs() / scale).to_string();
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i).is_multiple_of(3) {
+                if let Some(separator) = self.thousands_separator {
+                    text.push(separator);
+                }
+            }
+            text.push(digit);
+        }
+        if self.decimal_places > 0 {
+            let fraction = units.abs() % scale;
+            let width = self.decimal_places as usize;
+            text.push_str(&format!(".{fraction:0width$}"));
+        }
+        text
+    }
+
+    /// Write an amount of money in this format with the currency symbol on the configured side.
+    ///
+    /// Symbols without letters are written right before the amount, like `$1,234.50`, others
+    /// with a space, like `EGP 1,234.50`.
+    fn format_price(&self, amount: f64, symbol: &str) -> String {
+        let amount = self.format(amount);
+        if symbol.is_empty() {
+            return amount;
+        }
+        match self.symbol_position {
+            SymbolPosition::After => format!("{amount} {symbol}"),
+            SymbolPosition::Before => {
+                let (sign, amount) = match amount.strip_prefix('-') {
+                    Some(amount) => ("-", amount),
+                    None => ("", amount.as_str()),
+                };
+                if symbol.chars().any(char::is_alphabetic) {
+                    format!("{sign}{symbol} {amount}")
+                } else {
+                    format!("{sign}{symbol}{amount}")
+                }
+            }
+        }
+    }
+
+    /// Make this the format used by [`format_money`]
+    pub(crate) fn apply(self) {
+        if let Ok(mut current) = MONEY_FORMAT.write() {
+            *current = self;
+        }
+    }
+}
+
+impl Default for MoneyFormat {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Write an amount of money for the user in the configured [`MoneyFormat`], without the
+/// currency.
+pub(crate) fn format_money(amount: f64) -> String {
+    MONEY_FORMAT
+        .read()
+        .map(|f| *f)
+        .unwrap_or_default()
+        .format(amount)
+}
+
+/// Write an amount of money for the user with the currency symbol.
+///
+/// The amount is written like [`format_money`] does and the symbol is put on the configured side,
+/// like `1,234.50 EGP` or `$1,234.50`.
+pub(crate) fn format_price(amount: f64) -> String {
+    MONEY_FORMAT
+        .read()
+        .map(|f| *f)
+        .unwrap_or_default()
+        .format_price(amount, &currency_symbol())
+}
+
+/// The currency symbol used by [`format_price`], set from the saved application on load
+static CURRENCY_SYMBOL: std::sync::RwLock<String> = std::sync::RwLock::new(String::new());
+
+/// The currency symbol amounts of money are written with, like `EGP` or `$`
+pub(crate) fn currency_symbol() -> String {
+    CURRENCY_SYMBOL
+        .read()
+        .map(|symbol| symbol.clone())
+        .unwrap_or_default()
+}
+
+/// Make this the symbol returned by [`currency_symbol`]
+pub(crate) fn set_currency_symbol(symbol: &str) {
+    if let Ok(mut current) = CURRENCY_SYMBOL.write() {
+        *current = symbol.to_owned();
+    }
+}
+
 /// A product in the catalog
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct Product {
@@ -10,6 +361,58 @@ pub(crate) struct Product {
     /// The price prt unit
     #[serde(rename = "price")]
     unit_price: f64,
+    /// A link to a picture of the product
+    #[serde(default)]
+    image_url: Option<String>,
+    /// The weight of one unit of the product, used to compute the delivery weight of orders
+    #[serde(default)]
+    weight_grams: Option<u32>,
+    /// What one unit of the product is, like "kg", "each" or "litre"
+    #[serde(default = "Product::default_unit")]
+    unit: String,
+    /// How many units are left, `None` if the stock of the product is not tracked
+    #[serde(default)]
+    stock: Option<u32>,
+    /// The barcode printed on the product, for shops that scan products
+    #[serde(default)]
+    barcode: Option<String>,
+    /// Whether the product is exempt from tax, like staple foods
+    #[serde(default)]
+    tax_exempt: bool,
+    /// Whether the product is still sold, removed products are kept as discontinued for reports
+    #[serde(default = "Product::default_active")]
+    active: bool,
+    /// The stock at which the product should be ordered again from the supplier
+    #[serde(default)]
+    reorder_point: Option<u32>,
+    /// Whether the product is highlighted at the top of the catalog
+    #[serde(default)]
+    featured: bool,
+    /// Every change of the unit price, oldest first
+    #[serde(default)]
+    price_history: Vec<PriceChange>,
+}
+
+/// A change of the unit price of a product
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PriceChange {
+    timestamp: chrono::DateTime<chrono::Local>,
+    old: f64,
+    new: f64,
+}
+
+impl PriceChange {
+    pub(crate) fn timestamp(&self) -> chrono::DateTime<chrono::Local> {
+        self.timestamp
+    }
+
+    pub(crate) fn old_price(&self) -> f64 {
+        self.old
+    }
+
+    pub(crate) fn new_price(&self) -> f64 {
+        self.new
+    }
 }
 
 impl Product {
@@ -19,9 +422,28 @@ impl Product {
             code,
             name,
             unit_price,
+            image_url: None,
+            weight_grams: None,
+            unit: Self::default_unit(),
+            stock: None,
+            barcode: None,
+            tax_exempt: false,
+            active: true,
+            reorder_point: None,
+            featured: false,
+            price_history: Vec::new(),
         }
     }
 
+    fn default_active() -> bool {
+        true
+    }
+
+    /// The unit of products that do not have one
+    fn default_unit() -> String {
+        "each".to_owned()
+    }
+
     pub(crate) fn code(&self) -> &str {
         self.code.as_ref()
     }
@@ -33,6 +455,79 @@ impl Product {
     pub(crate) fn unit_price(&self) -> f64 {
         self.unit_price
     }
+
+    pub(crate) fn image_url(&self) -> Option<&str> {
+        self.image_url.as_deref()
+    }
+
+    pub(crate) fn set_image_url(&mut self, image_url: Option<String>) {
+        self.image_url = image_url;
+    }
+
+    pub(crate) fn weight_grams(&self) -> Option<u32> {
+        self.weight_grams
+    }
+
+    pub(crate) fn set_weight_grams(&mut self, weight_grams: Option<u32>) {
+        self.weight_grams = weight_grams;
+    }
+
+    pub(crate) fn unit(&self) -> &str {
+        self.unit.as_ref()
+    }
+
+    pub(crate) fn set_unit(&mut self, unit: String) {
+        self.unit = unit;
+    }
+
+    pub(crate) fn stock(&self) -> Option<u32> {
+        self.stock
+    }
+
+    pub(crate) fn set_stock(&mut self, stock: Option<u32>) {
+        self.stock = stock;
+    }
+
+    pub(crate) fn barcode(&self) -> Option<&str> {
+        self.barcode.as_deref()
+    }
+
+    pub(crate) fn set_barcode(&mut self, barcode: Option<String>) {
+        self.barcode = barcode;
+    }
+
+    pub(crate) fn is_tax_exempt(&self) -> bool {
+        self.tax_exempt
+    }
+
+    pub(crate) fn reorder_point(&self) -> Option<u32> {
+        self.reorder_point
+    }
+
+    pub(crate) fn is_featured(&self) -> bool {
+        self.featured
+    }
+
+    pub(crate) fn price_history(&self) -> &[PriceChange] {
+        &self.price_history
+    }
+
+    /// Check if the stock of the product is tracked and nothing is left
+    pub(crate) fn is_out_of_stock(&self) -> bool {
+        self.stock == Some(0)
+    }
+}
+
+/// Check that a URL looks like an http(s) link to a host, like "https://example.com/apple.png"
+pub(crate) fn is_valid_url(url: &str) -> bool {
+    let Some(rest) = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    let host = rest.split('/').next().unwrap_or_default();
+    !host.is_empty() && !url.chars().any(char::is_whitespace)
 }
 
 /// An item in an order
@@ -62,13 +557,28 @@ impl OrderItem {
         self.quantity
     }
 
+    /// Whether the product was exempt from tax when it was ordered
+    pub(crate) fn is_tax_exempt(&self) -> bool {
+        self.product.tax_exempt
+    }
+
+    /// The price of one unit when the product was ordered
+    pub(crate) fn unit_price(&self) -> f64 {
+        self.product.unit_price()
+    }
+
     pub(crate) fn total_price(&self) -> f64 {
-        self.quantity * self.product.unit_price()
+        round_money(self.quantity * self.product.unit_price())
+    }
+
+    /// The weight of all units of the item, 0 if the product has no weight
+    pub(crate) fn total_weight_grams(&self) -> f64 {
+        self.quantity * f64::from(self.product.weight_grams.unwrap_or_default())
     }
 }
 
 /// The status of the credit card
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub(crate) enum CardStatus {
     /// The card is valid and is capable of accepting payments
@@ -78,290 +588,6596 @@ pub(crate) enum CardStatus {
     /// This is not a valid card.
     Invalid,
     /// The card is valid but does not have enough money.
-    InsufficintFunds,
-}
-
-/// The method of payment for the order
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "payment_method", content = "payment")]
-pub(crate) enum OrderPayment {
-    Cash,
-    CreditCard { card_number: String },
+    #[serde(alias = "insufficint_funds")]
+    InsufficientFunds,
 }
 
-impl std::fmt::Display for OrderPayment {
+impl std::fmt::Display for CardStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::Cash => {
-                f.write_str("cash")?;
-            }
-            Self::CreditCard { card_number, .. } => {
-                f.write_str("credit card ")?;
-                f.write_str(&card_number)?;
-            }
-        };
-        Ok(())
+            Self::Valid => f.write_str("valid"),
+            Self::Expired => f.write_str("expired"),
+            Self::Invalid => f.write_str("invalid"),
+            Self::InsufficientFunds => f.write_str("insufficient_funds"),
+        }
     }
 }
 
-/// The state of the order
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "order_state", content = "state")]
-pub(crate) enum OrderState {
-    Open,
-    Closed { payment: OrderPayment },
+impl std::str::FromStr for CardStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "valid" => Ok(Self::Valid),
+            "expired" => Ok(Self::Expired),
+            "invalid" => Ok(Self::Invalid),
+            "insufficient_funds" => Ok(Self::InsufficientFunds),
+            _ => Err(()),
+        }
+    }
 }
 
-impl std::fmt::Display for OrderState {
+/// Why a credit card number is not valid
+pub(crate) enum CardError {
+    /// The number has characters other than digits, spaces and dashes
+    NonDigits,
+    /// The number does not have 13 to 19 digits
+    InvalidLength,
+    /// The Luhn checksum of the number is wrong
+    ChecksumFailed,
+}
+
+impl std::fmt::Display for CardError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::Open => f.write_str("open"),
-            Self::Closed { .. } => f.write_str("closed"),
+            Self::NonDigits => f.write_str("contains non-digits"),
+            Self::InvalidLength => f.write_str("must have 13 to 19 digits"),
+            Self::ChecksumFailed => f.write_str("checksum failed"),
         }
     }
 }
 
-/// An order for a specific user with all items in the order and delivery address
-#[derive(Serialize, Deserialize)]
-pub(crate) struct Order {
-    order_id: u64,
-    username: String,
-    items: Vec<OrderItem>,
-    delivery_address: String,
-    state: OrderState,
+/// Remove the spaces and dashes used to group the digits of a card number
+pub(crate) fn normalize_card_number(card_number: &str) -> String {
+    card_number
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect()
 }
 
-impl Order {
-    pub(crate) fn order_id(&self) -> u64 {
-        self.order_id
+/// Check that a card number has 13 to 19 digits and a valid Luhn checksum.
+///
+/// Spaces and dashes between the digits are ignored, so "4111 1111 1111 1111" is valid.
+pub(crate) fn validate_card_number(card_number: &str) -> Result<(), CardError> {
+    let card_number = normalize_card_number(card_number);
+    if !card_number.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(CardError::NonDigits);
+    }
+    if !(13..=19).contains(&card_number.len()) {
+        return Err(CardError::InvalidLength);
     }
 
-    pub(crate) fn username(&self) -> &str {
-        self.username.as_ref()
+    let checksum: u32 = card_number
+        .bytes()
+        .rev()
+        .map(|b| u32::from(b - b'0'))
+        .enumerate()
+        .map(|(i, digit)| match (i % 2, digit * 2) {
+            (0, _) => digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    if !checksum.is_multiple_of(10) {
+        return Err(CardError::ChecksumFailed);
     }
 
-    pub(crate) fn items(&self) -> &[OrderItem] {
-        self.items.as_ref()
+    Ok(())
+}
+
+/// Parse a card expiry date written as "MM/YY" or "MM/YYYY".
+///
+/// Return the month and the full year.
+pub(crate) fn parse_card_expiry(expiry: &str) -> Option<(u8, u16)> {
+    let (month, year) = expiry.trim().split_once('/')?;
+    let month: u8 = month
+        .parse()
+        .ok()
+        .filter(|month| (1..=12).contains(month))?;
+    let year: u16 = match year.len() {
+        2 => 2000 + year.parse::<u16>().ok()?,
+        4 => year.parse().ok()?,
+        _ => return None,
+    };
+    Some((month, year))
+}
+
+/// Check if a card is expired at the given date.
+///
+/// A card can be used until the last day of its expiry month.
+pub(crate) fn is_card_expired(
+    expiry_month: u8,
+    expiry_year: u16,
+    today: chrono::NaiveDate,
+) -> bool {
+    use chrono::Datelike;
+    (i32::from(expiry_year), u32::from(expiry_month)) < (today.year(), today.month())
+}
+
+/// Hide all but the last 4 digits of a card number, like "**** **** **** 1234"
+///
+/// Numbers shorter than 4 characters are shown after the stars as they are.
+pub(crate) fn mask_card_number(card_number: &str) -> String {
+    let last_digits: String = card_number
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("**** **** **** {last_digits}")
+}
+
+/// A credit card saved by the user to pay faster
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedCard {
+    card_number: String,
+    expiry_month: u8,
+    expiry_year: u16,
+    /// A name the user gave to the card, like "work"
+    #[serde(default)]
+    label: Option<String>,
+}
+
+impl SavedCard {
+    /// Create a new saved card
+    pub(crate) fn new(
+        card_number: String,
+        expiry_month: u8,
+        expiry_year: u16,
+        label: Option<String>,
+    ) -> Self {
+        Self {
+            card_number,
+            expiry_month,
+            expiry_year,
+            label,
+        }
     }
 
-    pub(crate) fn delivery_address(&self) -> &str {
-        self.delivery_address.as_ref()
+    pub(crate) fn card_number(&self) -> &str {
+        self.card_number.as_ref()
     }
 
-    pub(crate) fn state(&self) -> &OrderState {
-        &self.state
+    pub(crate) fn expiry_month(&self) -> u8 {
+        self.expiry_month
     }
 
-    /// Compute the total price for each item in the order with respect to their quantity.
-    pub(crate) fn total_price(&self) -> f64 {
-        self.items
-            .iter()
-            .map(|item| item.product.unit_price * item.quantity)
-            .sum()
+    pub(crate) fn expiry_year(&self) -> u16 {
+        self.expiry_year
     }
+}
 
-    /// Close the order with the specific payment method
-    pub(crate) fn close(&mut self, payment: OrderPayment) -> bool {
-        if let OrderState::Open = self.state {
-            self.state = OrderState::Closed { payment };
-            true
-        } else {
-            false
+impl std::fmt::Display for SavedCard {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&mask_card_number(&self.card_number))?;
+        write!(
+            f,
+            " exp {:02}/{:02}",
+            self.expiry_month,
+            self.expiry_year % 100
+        )?;
+        if let Some(label) = &self.label {
+            write!(f, " ({label})")?;
         }
+        Ok(())
     }
 }
 
-/// A cart for the user with the list of items in it
-#[derive(Serialize, Deserialize, Default)]
-pub(crate) struct Cart(Vec<OrderItem>);
+/// An address saved by the user to deliver orders to
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Address {
+    /// A name the user gave to the address, like "home"
+    label: String,
+    street: String,
+    city: String,
+}
 
-impl Cart {
-    pub(crate) fn iter(&self) -> std::slice::Iter<'_, OrderItem> {
-        self.0.iter()
+impl Address {
+    /// Create a new address. Fails if any of the fields is empty.
+    pub(crate) fn new(label: &str, street: &str, city: &str) -> Option<Self> {
+        let (label, street, city) = (label.trim(), street.trim(), city.trim());
+        if label.is_empty() || street.is_empty() || city.is_empty() {
+            return None;
+        }
+        Some(Self {
+            label: label.to_owned(),
+            street: street.to_owned(),
+            city: city.to_owned(),
+        })
     }
 
-    /// Add an item in the cart
-    /// 
-    /// If the item already exists, the quantities are added to the already existing item.
-    pub(crate) fn add_item(&mut self, product: &Product, quantity: f64) {
-        if let Some(cart_item) = self
-            .0
-            .iter_mut()
-            .find(|cart_item| cart_item.product.code == product.code)
-        {
-            cart_item.quantity += quantity;
-        } else {
-            self.0.push(OrderItem {
-                product: product.clone(),
-                quantity,
-            })
-        }
+    /// The address to deliver to, like "12 Tahrir St, Cairo"
+    pub(crate) fn delivery_address(&self) -> String {
+        format!("{}, {}", self.street, self.city)
     }
+}
 
-    /// Remove an item from the cart.
-    pub(crate) fn remove_item(&mut self, code: &str) {
-        self.0.retain(|item| item.product.code != code);
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}, {}", self.label, self.street, self.city)
     }
 }
 
-/// The user that will use the application
+/// The method of payment for the order
 #[derive(Serialize, Deserialize)]
-pub(crate) struct User {
-    username: String,
-    password_hash: String,
-    email: String,
+#[serde(rename_all = "snake_case", tag = "payment_method", content = "payment")]
+pub(crate) enum OrderPayment {
+    Cash,
+    CreditCard {
+        card_number: String,
+        /// The expiry month of the card, 0 for orders paid before the expiry was collected
+        #[serde(default)]
+        expiry_month: u8,
+        #[serde(default)]
+        expiry_year: u16,
+    },
+    Wallet,
+    /// The whole order was paid by a gift card
+    GiftCard,
+    /// The order was paid with several payment methods, each paying part of it
+    Split {
+        legs: Vec<PaymentLeg>,
+    },
+}
 
-    cart: Cart,
+impl OrderPayment {
+    /// The card number of a card payment with all but the last 4 digits hidden.
+    ///
+    /// Every view that shows the card of a payment must use this instead of the full number.
+    pub(crate) fn masked(&self) -> Option<String> {
+        match self {
+            Self::CreditCard { card_number, .. } => Some(mask_card_number(card_number)),
+            _ => None,
+        }
+    }
 }
 
-impl User {
-    pub(crate) fn username(&self) -> &str {
-        self.username.as_ref()
+impl std::fmt::Display for OrderPayment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Cash => {
+                f.write_str("cash")?;
+            }
+            Self::CreditCard {
+                expiry_month,
+                expiry_year,
+                ..
+            } => {
+                f.write_str("credit card ")?;
+                f.write_str(&self.masked().unwrap_or_default())?;
+                if *expiry_month != 0 {
+                    write!(f, " exp {expiry_month:02}/{:02}", expiry_year % 100)?;
+                }
+            }
+            Self::Wallet => {
+                f.write_str("wallet")?;
+            }
+            Self::GiftCard => {
+                f.write_str("gift card")?;
+            }
+            Self::Split { legs } => {
+                for (i, leg) in legs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" + ")?;
+                    }
+                    write!(f, "{} {}", leg.payment, format_price(leg.amount))?;
+                }
+            }
+        };
+        Ok(())
     }
+}
 
-    pub(crate) fn cart(&self) -> &Cart {
-        &self.cart
+/// The part of a split payment paid with one payment method
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PaymentLeg {
+    payment: OrderPayment,
+    amount: f64,
+}
+
+impl PaymentLeg {
+    pub(crate) fn new(payment: OrderPayment, amount: f64) -> Self {
+        Self { payment, amount }
     }
 
-    pub(crate) fn cart_mut(&mut self) -> &mut Cart {
-        &mut self.cart
+    pub(crate) fn payment(&self) -> &OrderPayment {
+        &self.payment
     }
 
-    /// Check if the user is an admin.
-    /// 
-    /// The user is an admin if his username is "admin"
-    pub(crate) fn is_admin(&self) -> bool {
-        self.username == "admin"
+    pub(crate) fn amount(&self) -> f64 {
+        self.amount
+    }
+
+    pub(crate) fn into_payment(self) -> OrderPayment {
+        self.payment
     }
 }
 
-/// The user manager contains all users
-/// 
-/// This class is responsible for adding new users and checking that no user have the same username.
-#[derive(Serialize, Deserialize, Default)]
-pub(crate) struct UserManager {
-    users: Vec<User>,
+/// The part of an order that was paid by a gift card
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GiftCardPayment {
+    code: String,
+    amount: f64,
+}
 
-    #[serde(skip)]
-    usernames_taken: std::collections::HashSet<String>,
+impl GiftCardPayment {
+    pub(crate) fn code(&self) -> &str {
+        self.code.as_ref()
+    }
+
+    pub(crate) fn amount(&self) -> f64 {
+        self.amount
+    }
 }
 
-impl UserManager {
-    /// Create a new user with the given username, password and email.
-    /// 
-    /// Return whether the user was created (`true`) or not (`false`).
-    pub fn add_user(&mut self, username: String, password: String, email: String) -> bool {
-        if !self.usernames_taken.insert(username.clone()) {
-            return false;
+/// The state of the order
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "order_state", content = "state")]
+pub(crate) enum OrderState {
+    Open,
+    /// The order will be paid in cash when it is delivered
+    AwaitingDelivery,
+    Closed {
+        payment: OrderPayment,
+    },
+    Delivered {
+        payment: OrderPayment,
+    },
+    /// The order was cancelled before it was paid
+    Cancelled {
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for OrderState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Open => f.write_str("open"),
+            Self::AwaitingDelivery => f.write_str("to be paid on delivery"),
+            Self::Closed { .. } => f.write_str("closed"),
+            Self::Delivered { .. } => f.write_str("delivered"),
+            Self::Cancelled { reason } => write!(f, "cancelled ({reason})"),
         }
+    }
+}
 
-        let password_hash = bcrypt::hash(password, 4).unwrap();
+/// The part of the day in which an order is delivered
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeliverySlot {
+    Morning,
+    Afternoon,
+    Evening,
+}
 
-        self.users.push(User {
-            username,
-            password_hash,
-            email,
+impl std::fmt::Display for DeliverySlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Morning => f.write_str("morning"),
+            Self::Afternoon => f.write_str("afternoon"),
+            Self::Evening => f.write_str("evening"),
+        }
+    }
+}
 
-            cart: Default::default(),
-        });
+impl std::str::FromStr for DeliverySlot {
+    type Err = ();
 
-        true
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "morning" => Ok(Self::Morning),
+            "afternoon" => Ok(Self::Afternoon),
+            "evening" => Ok(Self::Evening),
+            _ => Err(()),
+        }
     }
+}
 
-    /// Find a user by their username and password
-    pub(crate) fn user_login_mut(
-        &mut self,
-        username: String,
-        password: String,
-    ) -> Option<&mut User> {
-        self.users.iter_mut().find(|u| {
-            u.username == username && bcrypt::verify(&password, &u.password_hash).unwrap()
-        })
+/// The date and part of the day the customer wants the order to be delivered
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(crate) struct DeliveryWindow {
+    date: chrono::NaiveDate,
+    slot: DeliverySlot,
+}
+
+impl DeliveryWindow {
+    /// Create a new delivery window.
+    ///
+    /// Return `None` if the date is before `today`.
+    pub(crate) fn new(
+        date: chrono::NaiveDate,
+        slot: DeliverySlot,
+        today: chrono::NaiveDate,
+    ) -> Option<Self> {
+        (date >= today).then_some(Self { date, slot })
+    }
+
+    pub(crate) fn date(&self) -> chrono::NaiveDate {
+        self.date
+    }
+
+    pub(crate) fn slot(&self) -> DeliverySlot {
+        self.slot
     }
 }
 
-/// The list of all available items
-#[derive(Serialize, Deserialize, Default)]
-pub(crate) struct Catalog {
-    products: Vec<Product>,
+/// How many loyalty points are worth 1 EGP off an order when redeemed
+pub(crate) const POINTS_PER_EGP: u64 = 100;
+
+/// What the customer chooses about the delivery of an order at checkout
+pub(crate) struct CheckoutDetails {
+    pub delivery_address: String,
+    pub notes: Option<String>,
+    pub delivery_window: Option<DeliveryWindow>,
+    pub gift_wrap: bool,
+    pub gift_message: Option<String>,
+    /// The loyalty points used for a discount on the order
+    pub points_redeemed: u64,
 }
 
-impl Catalog {
-    pub(crate) fn add_product(&mut self, product: Product) {
-        self.products.push(product);
-    }
+/// Something that happened to an order, like being paid or cancelled
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OrderEvent {
+    timestamp: chrono::DateTime<chrono::Local>,
+    description: String,
+}
 
-    pub(crate) fn remove_product(&mut self, code: &str) {
-        self.products.retain(|product| product.code != code);
+impl OrderEvent {
+    pub(crate) fn timestamp(&self) -> chrono::DateTime<chrono::Local> {
+        self.timestamp
     }
 
-    pub(crate) fn products(&self) -> &[Product] {
-        self.products.as_ref()
+    pub(crate) fn description(&self) -> &str {
+        &self.description
     }
 }
 
-/// Order manager is responsible for adding and managing all orders.
-/// 
-/// This class also gives a unique ID to every order
-#[derive(Serialize, Deserialize, Default)]
-pub(crate) struct OrderManager {
-    orders: Vec<Order>,
-    sequence_id: u64,
+/// An order for a specific user with all items in the order and delivery address
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Order {
+    order_id: u64,
+    username: String,
+    items: Vec<OrderItem>,
+    delivery_address: String,
+    state: OrderState,
+
+    /// Special instructions of the customer for the delivery
+    #[serde(default)]
+    notes: Option<String>,
+
+    /// When the customer wants the order to be delivered
+    #[serde(default)]
+    delivery_window: Option<DeliveryWindow>,
+
+    /// Whether the order is a gift that should be wrapped
+    #[serde(default)]
+    gift_wrap: bool,
+    /// A message to the receiver of the gift
+    #[serde(default)]
+    gift_message: Option<String>,
+
+    /// The loyalty points used for a discount on the order
+    #[serde(default)]
+    points_redeemed: u64,
+
+    /// The part of the total that was paid by a gift card, the rest is paid by the payment in
+    /// the state of the order
+    #[serde(default)]
+    gift_card: Option<GiftCardPayment>,
+    /// The tip for the courier, added by the customer when paying
+    #[serde(default)]
+    tip: f64,
+    /// The promo code applied when paying and the discount it gave
+    #[serde(default)]
+    promo: Option<AppliedPromo>,
+    /// When the order was placed, unknown for orders placed before it was recorded
+    #[serde(default)]
+    created_at: Option<chrono::DateTime<chrono::Local>>,
+    /// When the order was paid, unknown for orders that are not paid or were paid before it was
+    /// recorded
+    #[serde(default)]
+    paid_at: Option<chrono::DateTime<chrono::Local>>,
+    /// What happened to the order since it was placed, oldest first
+    #[serde(default)]
+    history: Vec<OrderEvent>,
+    /// The courier who delivers the order, if one was assigned
+    #[serde(default)]
+    courier: Option<String>,
 }
 
-impl OrderManager {
-    /// Takes all items from the cart of the user and creates a new order
-    pub(crate) fn checkout(&mut self, user: &mut User, delivery_address: String) -> &Order {
-        let order_id = self.sequence_id;
-        self.sequence_id += 1;
+impl Order {
+    /// The price of wrapping an order as a gift
+    pub(crate) const GIFT_WRAP_FEE: f64 = 15.0;
 
-        self.orders.push(Order {
-            order_id,
-            username: user.username.clone(),
-            items: std::mem::take(&mut user.cart.0),
-            delivery_address,
-            state: OrderState::Open,
+    pub(crate) fn history(&self) -> &[OrderEvent] {
+        &self.history
+    }
+
+    /// Add an event that happened now to the history of the order
+    fn record(&mut self, description: String) {
+        self.history.push(OrderEvent {
+            timestamp: chrono::Local::now(),
+            description,
         });
+    }
 
-        self.orders.last().unwrap()
+    pub(crate) fn order_id(&self) -> u64 {
+        self.order_id
     }
 
-    pub(crate) fn orders(&self) -> &[Order] {
-        &self.orders
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
     }
 
-    pub(crate) fn orders_mut(&mut self) -> &mut [Order] {
-        &mut self.orders
+    pub(crate) fn items(&self) -> &[OrderItem] {
+        self.items.as_ref()
     }
-}
 
-/// The main Corona Application manager class
-#[derive(Serialize, Deserialize, Default)]
-pub(crate) struct CoronaApplication {
-    #[serde(flatten)]
-    pub user_manager: UserManager,
-    #[serde(flatten)]
-    pub catalog: Catalog,
-    #[serde(flatten)]
-    pub order_manager: OrderManager,
-}
+    pub(crate) fn delivery_address(&self) -> &str {
+        self.delivery_address.as_ref()
+    }
 
-impl CoronaApplication {
-    /// The name of the file that will store the state
-    const PATH: &str = "corona.toml";
+    pub(crate) fn state(&self) -> &OrderState {
+        &self.state
+    }
 
-    /// Save the entire state of the application to a file
-    pub fn save(&self) -> Option<()> {
-        toml::to_string(self)
-            .ok()
-            .and_then(|s| std::fs::write(Self::PATH, s).ok())
+    pub(crate) fn created_at(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        self.created_at
     }
 
-    /// Load the entire state of the application from a file or creates a new application
-    pub fn load() -> CoronaApplication {
-        std::fs::read_to_string(Self::PATH)
-            .ok()
-            .and_then(|s| toml::from_str(&s).ok())
-            .unwrap_or_default()
+    pub(crate) fn courier(&self) -> Option<&str> {
+        self.courier.as_deref()
+    }
+
+    /// Give the delivery of the order to a courier, only possible for orders that still have to
+    /// be delivered
+    pub(crate) fn assign_courier(&mut self, courier: &str) -> bool {
+        if !matches!(
+            self.state,
+            OrderState::AwaitingDelivery | OrderState::Closed { .. }
+        ) {
+            return false;
+        }
+        self.courier = Some(courier.to_owned());
+        self.record(format!("assigned to courier {courier}"));
+        true
+    }
+
+    /// Check if the order was paid, either when it was closed or in cash on delivery
+    pub(crate) fn is_paid(&self) -> bool {
+        matches!(
+            self.state,
+            OrderState::Closed { .. } | OrderState::Delivered { .. }
+        )
+    }
+
+    /// Check if the order is still waiting to be paid
+    pub(crate) fn is_open(&self) -> bool {
+        matches!(self.state, OrderState::Open)
+    }
+
+    /// The payment of the order if it was paid
+    pub(crate) fn payment(&self) -> Option<&OrderPayment> {
+        match &self.state {
+            OrderState::Open | OrderState::AwaitingDelivery | OrderState::Cancelled { .. } => None,
+            OrderState::Closed { payment } | OrderState::Delivered { payment } => Some(payment),
+        }
+    }
+
+    pub(crate) fn notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
+    pub(crate) fn delivery_window(&self) -> Option<DeliveryWindow> {
+        self.delivery_window
+    }
+
+    pub(crate) fn gift_wrap(&self) -> bool {
+        self.gift_wrap
+    }
+
+    pub(crate) fn gift_message(&self) -> Option<&str> {
+        self.gift_message.as_deref()
+    }
+
+    pub(crate) fn gift_card(&self) -> Option<&GiftCardPayment> {
+        self.gift_card.as_ref()
+    }
+
+    pub(crate) fn tip(&self) -> f64 {
+        self.tip
+    }
+
+    /// Set the tip of the order, only possible while it is not paid
+    pub(crate) fn set_tip(&mut self, tip: f64) -> bool {
+        if self.is_open() && tip >= 0.0 {
+            self.tip = round_money(tip);
+            if self.tip > 0.0 {
+                self.record(format!("tip of {} added", format_price(self.tip)));
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn points_redeemed(&self) -> u64 {
+        self.points_redeemed
+    }
+
+    /// The discount given by the redeemed loyalty points
+    pub(crate) fn points_discount(&self) -> f64 {
+        points_discount(self.points_redeemed)
+    }
+
+    pub(crate) fn promo(&self) -> Option<&AppliedPromo> {
+        self.promo.as_ref()
+    }
+
+    /// Check if the order already has a discount, promo codes can not be combined with one
+    pub(crate) fn is_discounted(&self) -> bool {
+        self.points_redeemed > 0 || self.promo.is_some()
+    }
+
+    /// Take a percentage off the items of an open order that has no other discount
+    fn apply_promo(&mut self, code: &str, percent_off: u8) -> Result<f64, PromoError> {
+        if !self.is_open() {
+            return Err(PromoError::OrderNotOpen);
+        }
+        if self.is_discounted() {
+            return Err(PromoError::AlreadyDiscounted);
+        }
+        self.promo = Some(AppliedPromo {
+            code: code.to_owned(),
+            percent_off,
+            discount: 0.0,
+        });
+        self.update_promo_discount();
+        self.record(format!("promo code {code} applied"));
+        Ok(self.promo.as_ref().map_or(0.0, AppliedPromo::discount))
+    }
+
+    /// Take the promo code off an open order again, like when the payment is cancelled
+    pub(crate) fn remove_promo(&mut self) {
+        if !self.is_open() {
+            return;
+        }
+        if let Some(promo) = self.promo.take() {
+            self.record(format!("promo code {} removed", promo.code));
+        }
+    }
+
+    /// Compute the discount of the applied promo code again from the current items
+    fn update_promo_discount(&mut self) {
+        let items: f64 = self.items.iter().map(OrderItem::total_price).sum();
+        if let Some(promo) = &mut self.promo {
+            if promo.percent_off > 0 {
+                promo.discount = round_money(items * promo.percent_off as f64 / 100.0);
+            }
+        }
+    }
+
+    /// Change how much of a product is in an open order, 0 removes the product.
+    ///
+    /// Products that are not in the order yet are added from the catalog. The difference in
+    /// quantity is taken out of or put back in the stock of the product, and the promo code
+    /// discount follows the new items.
+    pub(crate) fn set_item_quantity(
+        &mut self,
+        catalog: &mut Catalog,
+        code: &str,
+        quantity: f64,
+    ) -> Result<(), OrderEditError> {
+        if !self.is_open() {
+            return Err(OrderEditError::NotOpen);
+        }
+        if !quantity.is_finite() || quantity < 0.0 {
+            return Err(OrderEditError::InvalidQuantity);
+        }
+        let index = self.items.iter().position(|item| item.code() == code);
+        let old_quantity = index.map_or(0.0, |i| self.items[i].quantity);
+        if quantity == 0.0 && index.is_some() && self.items.len() == 1 {
+            return Err(OrderEditError::EmptyOrder);
+        }
+        let product = match index {
+            Some(_) => None,
+            None if quantity == 0.0 => return Err(OrderEditError::NotInOrder),
+            None => Some(
+                catalog
+                    .product(code)
+                    .cloned()
+                    .ok_or(OrderEditError::UnknownProduct)?,
+            ),
+        };
+        catalog
+            .change_stock(code, old_quantity, quantity)
+            .map_err(|available| OrderEditError::NotEnoughStock { available })?;
+
+        match (index, product) {
+            (Some(i), _) if quantity == 0.0 => {
+                self.items.remove(i);
+            }
+            (Some(i), _) => self.items[i].quantity = quantity,
+            (None, Some(product)) => self.items.push(OrderItem { product, quantity }),
+            (None, None) => {}
+        }
+        self.update_promo_discount();
+        self.record(if quantity == 0.0 {
+            format!("{code} removed")
+        } else if old_quantity == 0.0 {
+            format!("{quantity}x {code} added")
+        } else {
+            format!("quantity of {code} changed from {old_quantity} to {quantity}")
+        });
+        Ok(())
+    }
+
+    /// The loyalty points earned by paying the order, 1 point for every full EGP paid.
+    ///
+    /// The tip goes to the courier and does not earn points.
+    pub(crate) fn points_earned(&self) -> u64 {
+        (self.total_price() - self.tip).floor() as u64
+    }
+
+    /// The weight of all items of the order, used to price the delivery
+    pub(crate) fn total_weight_grams(&self) -> f64 {
+        self.items.iter().map(OrderItem::total_weight_grams).sum()
+    }
+
+    /// Check if the order is a gift, either wrapped or with a message
+    pub(crate) fn is_gift(&self) -> bool {
+        self.gift_wrap || self.gift_message.is_some()
+    }
+
+    /// Compute the total price for each item in the order with respect to their quantity,
+    /// plus the gift wrap fee if the order is gift wrapped, minus the loyalty points or promo code
+    /// discount, plus the tip.
+    pub(crate) fn total_price(&self) -> f64 {
+        self.totals().total
+    }
+
+    /// The price of the taxable items and of the tax exempt items, before discounts and fees
+    pub(crate) fn tax_subtotals(&self) -> (f64, f64) {
+        let (exempt, taxable): (Vec<&OrderItem>, Vec<&OrderItem>) =
+            self.items.iter().partition(|item| item.is_tax_exempt());
+        let subtotal = |items: Vec<&OrderItem>| {
+            round_money(items.into_iter().map(OrderItem::total_price).sum())
+        };
+        (subtotal(taxable), subtotal(exempt))
+    }
+
+    /// The parts that make up the total price of the order
+    pub(crate) fn totals(&self) -> OrderTotals {
+        OrderTotals::compute(
+            &self.items,
+            self.gift_wrap,
+            self.points_redeemed,
+            self.promo.as_ref().map_or(0.0, AppliedPromo::discount),
+            self.tip,
+        )
+    }
+
+    /// Close the order with the specific payment method
+    pub(crate) fn close(&mut self, payment: OrderPayment) -> bool {
+        if let OrderState::Open = self.state {
+            self.record(format!("paid by {payment}"));
+            self.state = OrderState::Closed { payment };
+            self.paid_at = Some(chrono::Local::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Close the order paying part of it with a gift card and the rest with the payment method.
+    ///
+    /// The balance of the gift card is only decremented if the order was closed.
+    pub(crate) fn close_with_gift_card(
+        &mut self,
+        payment: OrderPayment,
+        gift_card: &mut GiftCard,
+        amount: f64,
+    ) -> bool {
+        if !self.close(payment) {
+            return false;
+        }
+        let amount = gift_card.redeem(amount);
+        self.record(format!(
+            "{} paid with gift card {}",
+            format_price(amount),
+            gift_card.code
+        ));
+        self.gift_card = Some(GiftCardPayment {
+            code: gift_card.code.clone(),
+            amount,
+        });
+        true
+    }
+
+    /// Cancel an open order. Orders that are paid or to be paid on delivery can not be cancelled.
+    pub(crate) fn cancel(&mut self, reason: &str) -> bool {
+        if let OrderState::Open = self.state {
+            self.record(format!("cancelled ({reason})"));
+            self.state = OrderState::Cancelled {
+                reason: reason.to_owned(),
+            };
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Mark an open order to be paid in cash when it is delivered
+    pub(crate) fn await_delivery(&mut self) -> bool {
+        if let OrderState::Open = self.state {
+            self.record("to be paid in cash on delivery".to_owned());
+            self.state = OrderState::AwaitingDelivery;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record the cash received for an order paid on delivery, which delivers it.
+    ///
+    /// Return the change to give back.
+    pub(crate) fn collect(&mut self, amount: f64) -> Result<f64, CollectError> {
+        if !matches!(self.state, OrderState::AwaitingDelivery) {
+            return Err(CollectError::NotPayOnDelivery);
+        }
+        let total = self.total_price();
+        if to_cents(amount) < to_cents(total) {
+            return Err(CollectError::NotEnough {
+                missing: round_money(total - amount),
+            });
+        }
+        self.state = OrderState::Delivered {
+            payment: OrderPayment::Cash,
+        };
+        self.record("paid in cash and delivered".to_owned());
+        self.paid_at = Some(chrono::Local::now());
+        Ok(round_money(amount - total))
+    }
+
+    /// Mark a closed order as delivered to the customer
+    pub(crate) fn deliver(&mut self) -> bool {
+        match std::mem::replace(&mut self.state, OrderState::Open) {
+            OrderState::Closed { payment } => {
+                self.state = OrderState::Delivered { payment };
+                self.record("delivered".to_owned());
+                true
+            }
+            state => {
+                self.state = state;
+                false
+            }
+        }
+    }
+
+    /// The quantity of the product with the given code in the order
+    pub(crate) fn quantity_of(&self, code: &str) -> Option<f64> {
+        self.items
+            .iter()
+            .find(|item| item.product.code == code)
+            .map(|item| item.quantity)
+    }
+
+    /// The unit price the product with the given code was bought at in the order
+    pub(crate) fn unit_price_of(&self, code: &str) -> Option<f64> {
+        self.items
+            .iter()
+            .find(|item| item.product.code == code)
+            .map(|item| item.product.unit_price)
+    }
+
+    /// The money paid for the order with each payment method, as the refunds that would give all
+    /// of it back. Empty if the order was not paid.
+    fn paid_shares(&self) -> Vec<RefundInstruction> {
+        let Some(payment) = self.payment() else {
+            return Vec::new();
+        };
+        let mut shares = Vec::new();
+        let mut paid = 0.0;
+        if let Some(gift_card) = &self.gift_card {
+            shares.push(RefundInstruction::GiftCard {
+                code: gift_card.code.clone(),
+                amount: gift_card.amount,
+            });
+            paid = gift_card.amount;
+        }
+        let legs = match payment {
+            OrderPayment::Split { legs } => legs
+                .iter()
+                .map(|leg| (leg.payment(), leg.amount()))
+                .collect(),
+            payment => vec![(payment, round_money(self.total_price() - paid))],
+        };
+        for (payment, amount) in legs {
+            shares.push(match payment {
+                OrderPayment::Cash => RefundInstruction::Cash { amount },
+                payment @ OrderPayment::CreditCard { .. } => RefundInstruction::CreditCard {
+                    masked_card: payment.masked().unwrap_or_default(),
+                    amount,
+                },
+                OrderPayment::Wallet => RefundInstruction::Wallet { amount },
+                // The gift card share was added above
+                OrderPayment::GiftCard | OrderPayment::Split { .. } => continue,
+            });
+        }
+        shares
+    }
+
+    /// Where to send back `amount` of the money paid for the order.
+    ///
+    /// The money goes back to the payment methods that paid for the order, each of them getting
+    /// the same share of the refund as it paid of the order, so a split payment or a gift card
+    /// that paid part of the order is refunded leg by leg. Rounding is given to the last method.
+    /// Empty if the order was not paid.
+    pub(crate) fn refund_payment(&self, amount: f64) -> Vec<RefundInstruction> {
+        let shares = self.paid_shares();
+        let paid: f64 = shares.iter().map(RefundInstruction::amount).sum();
+        if to_cents(paid) <= 0 {
+            return Vec::new();
+        }
+        let count = shares.len();
+        let mut left = round_money(amount);
+        shares
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, share)| {
+                let share_amount = if i + 1 == count {
+                    left
+                } else {
+                    round_money(amount * share.amount() / paid)
+                };
+                left = round_money(left - share_amount);
+                (to_cents(share_amount) > 0).then(|| share.with_amount(share_amount))
+            })
+            .collect()
+    }
+}
+
+/// Why an item of an order could not be changed
+pub(crate) enum OrderEditError {
+    /// Only open orders can be edited, paid and cancelled orders can not
+    NotOpen,
+    InvalidQuantity,
+    UnknownProduct,
+    NotInOrder,
+    /// The last item of an order can not be removed
+    EmptyOrder,
+    NotEnoughStock {
+        available: f64,
+    },
+}
+
+impl std::fmt::Display for OrderEditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotOpen => f.write_str("only open orders can be edited"),
+            Self::InvalidQuantity => f.write_str("the quantity can not be negative"),
+            Self::UnknownProduct => f.write_str("there is no product with this code"),
+            Self::NotInOrder => f.write_str("the product is not in the order"),
+            Self::EmptyOrder => f.write_str("the last item of an order can not be removed"),
+            Self::NotEnoughStock { available } => {
+                write!(f, "only {available} more available")
+            }
+        }
+    }
+}
+
+/// Why the cash of an order paid on delivery could not be collected
+pub(crate) enum CollectError {
+    /// The order is not waiting to be paid on delivery, it may be paid already
+    NotPayOnDelivery,
+    /// The cash received does not cover the order
+    NotEnough { missing: f64 },
+}
+
+impl std::fmt::Display for CollectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotPayOnDelivery => {
+                f.write_str("the order is not waiting to be paid on delivery")
+            }
+            Self::NotEnough { missing } => {
+                write!(f, "{missing} missing", missing = format_price(*missing))
+            }
+        }
+    }
+}
+
+/// The discount given by redeeming loyalty points
+fn points_discount(points: u64) -> f64 {
+    round_money(points as f64 / POINTS_PER_EGP as f64)
+}
+
+/// The parts that make up the total price of an order
+pub(crate) struct OrderTotals {
+    pub(crate) items: f64,
+    pub(crate) gift_wrap: f64,
+    pub(crate) points_discount: f64,
+    pub(crate) promo_discount: f64,
+    pub(crate) tip: f64,
+    /// The price to pay, never negative
+    pub(crate) total: f64,
+}
+
+impl OrderTotals {
+    /// Add up the price of an order, used both for orders and for quotes of a cart so they
+    /// always match
+    fn compute(
+        items: &[OrderItem],
+        gift_wrap: bool,
+        points_redeemed: u64,
+        promo_discount: f64,
+        tip: f64,
+    ) -> OrderTotals {
+        let items = round_money(items.iter().map(OrderItem::total_price).sum());
+        let gift_wrap = if gift_wrap { Order::GIFT_WRAP_FEE } else { 0.0 };
+        let points_discount = points_discount(points_redeemed);
+        OrderTotals {
+            items,
+            gift_wrap,
+            points_discount,
+            promo_discount,
+            tip,
+            total: round_money(
+                (items + gift_wrap - points_discount - promo_discount).max(0.0) + tip,
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for OrderTotals {
+    /// Print like "total due: 44.00 EGP (items 30.00 + gift wrap 15.00 - points 1.00)"
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "total due: {} (items {}",
+            format_price(self.total),
+            format_money(self.items)
+        )?;
+        if self.gift_wrap > 0.0 {
+            write!(f, " + gift wrap {}", format_money(self.gift_wrap))?;
+        }
+        if self.points_discount > 0.0 {
+            write!(f, " - points {}", format_money(self.points_discount))?;
+        }
+        if self.promo_discount > 0.0 {
+            write!(f, " - promo {}", format_money(self.promo_discount))?;
+        }
+        if self.tip > 0.0 {
+            write!(f, " + tip {}", format_money(self.tip))?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// How the money of a refund goes back to the customer
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum RefundInstruction {
+    /// Given back in cash on the next delivery
+    Cash { amount: f64 },
+    /// Refunded to the card that paid, only its masked number is kept
+    CreditCard { masked_card: String, amount: f64 },
+    /// Credited back to the wallet of the user
+    Wallet { amount: f64 },
+    /// Put back on the balance of the gift card that paid
+    GiftCard { code: String, amount: f64 },
+}
+
+impl RefundInstruction {
+    pub(crate) fn amount(&self) -> f64 {
+        match self {
+            Self::Cash { amount }
+            | Self::CreditCard { amount, .. }
+            | Self::Wallet { amount }
+            | Self::GiftCard { amount, .. } => *amount,
+        }
+    }
+
+    /// The same refund of another amount
+    fn with_amount(mut self, new_amount: f64) -> Self {
+        match &mut self {
+            Self::Cash { amount }
+            | Self::CreditCard { amount, .. }
+            | Self::Wallet { amount }
+            | Self::GiftCard { amount, .. } => *amount = new_amount,
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for RefundInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Cash { amount } => {
+                write!(
+                    f,
+                    "refund {amount} in cash on next delivery",
+                    amount = format_price(*amount)
+                )
+            }
+            Self::CreditCard {
+                masked_card,
+                amount,
+            } => write!(
+                f,
+                "refund {amount} to credit card {masked_card}",
+                amount = format_price(*amount)
+            ),
+            Self::Wallet { amount } => write!(
+                f,
+                "refund {amount} to the wallet",
+                amount = format_price(*amount)
+            ),
+            Self::GiftCard { code, amount } => {
+                write!(
+                    f,
+                    "refund {amount} to gift card {code}",
+                    amount = format_price(*amount)
+                )
+            }
+        }
+    }
+}
+
+/// The status of a return request
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReturnStatus {
+    /// The customer asked to return the item and is waiting for an admin
+    Requested,
+    /// An admin accepted the return but the money is not refunded yet
+    Approved,
+    /// An admin refused the return
+    Rejected,
+    /// The money of the returned item was refunded to the customer
+    Refunded,
+}
+
+impl std::fmt::Display for ReturnStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Requested => f.write_str("requested"),
+            Self::Approved => f.write_str("approved"),
+            Self::Rejected => f.write_str("rejected"),
+            Self::Refunded => f.write_str("refunded"),
+        }
+    }
+}
+
+/// A request of a customer to return an item of a delivered order
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ReturnRequest {
+    return_id: u64,
+    order_id: u64,
+    username: String,
+    code: String,
+    quantity: f64,
+    reason: String,
+    status: ReturnStatus,
+}
+
+impl ReturnRequest {
+    pub(crate) fn return_id(&self) -> u64 {
+        self.return_id
+    }
+
+    pub(crate) fn order_id(&self) -> u64 {
+        self.order_id
+    }
+
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn code(&self) -> &str {
+        self.code.as_ref()
+    }
+
+    pub(crate) fn quantity(&self) -> f64 {
+        self.quantity
+    }
+
+    pub(crate) fn reason(&self) -> &str {
+        self.reason.as_ref()
+    }
+
+    pub(crate) fn status(&self) -> ReturnStatus {
+        self.status
+    }
+}
+
+/// Why a return request could not be created or updated
+pub(crate) enum ReturnError {
+    /// There is no order with this ID for the user
+    OrderNotFound,
+    /// The order was not delivered yet
+    NotDelivered,
+    /// The product is not part of the order
+    ItemNotInOrder,
+    /// The quantity is not positive or more than what can still be returned
+    InvalidQuantity { returnable: f64 },
+    /// There is no return request with this ID
+    ReturnNotFound,
+    /// The return request cannot move to the wanted status from its current status
+    InvalidTransition {
+        from: ReturnStatus,
+        to: ReturnStatus,
+    },
+}
+
+impl std::fmt::Display for ReturnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::OrderNotFound => f.write_str("order not found"),
+            Self::NotDelivered => f.write_str("order was not delivered yet"),
+            Self::ItemNotInOrder => f.write_str("item is not in the order"),
+            Self::InvalidQuantity { returnable } => {
+                write!(f, "invalid quantity, at most {returnable} can be returned")
+            }
+            Self::ReturnNotFound => f.write_str("return request not found"),
+            Self::InvalidTransition { from, to } => {
+                write!(f, "a {from} return request cannot be {to}")
+            }
+        }
+    }
+}
+
+/// Why an item could not be added to the cart
+pub(crate) enum CartError {
+    /// The quantity is zero, negative or not a number
+    InvalidQuantity,
+    /// The line of the product would have more than the maximum quantity
+    LineTooLarge { max: u32, in_cart: f64 },
+}
+
+impl std::fmt::Display for CartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidQuantity => f.write_str("the quantity must be more than 0"),
+            Self::LineTooLarge { max, in_cart } if *in_cart > 0.0 => write!(
+                f,
+                "at most {max} of a product can be in the cart and you already have {in_cart}"
+            ),
+            Self::LineTooLarge { max, .. } => {
+                write!(f, "at most {max} of a product can be in the cart")
+            }
+        }
+    }
+}
+
+/// A cart for the user with the list of items in it
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Cart(Vec<OrderItem>);
+
+impl Cart {
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, OrderItem> {
+        self.0.iter()
+    }
+
+    /// Compute the total price of all items in the cart.
+    pub(crate) fn total_price(&self) -> f64 {
+        round_money(self.iter().map(OrderItem::total_price).sum())
+    }
+
+    /// Add an item in the cart
+    /// 
+    /// If the item already exists, the quantities are added to the already existing item.
+    ///
+    /// Fails if the quantity is not positive, or if the line would have more than
+    /// `max_line_quantity` of the product, 0 for no maximum.
+    pub(crate) fn add_item(
+        &mut self,
+        product: &Product,
+        quantity: f64,
+        max_line_quantity: u32,
+    ) -> Result<(), CartError> {
+        if !(quantity.is_finite() && quantity > 0.0) {
+            return Err(CartError::InvalidQuantity);
+        }
+        let in_cart = self.quantity_of(&product.code);
+        if max_line_quantity > 0 && in_cart + quantity > f64::from(max_line_quantity) {
+            return Err(CartError::LineTooLarge {
+                max: max_line_quantity,
+                in_cart,
+            });
+        }
+        if let Some(cart_item) = self
+            .0
+            .iter_mut()
+            .find(|cart_item| cart_item.product.code == product.code)
+        {
+            cart_item.quantity += quantity;
+        } else {
+            self.0.push(OrderItem {
+                product: product.clone(),
+                quantity,
+            })
+        }
+        Ok(())
+    }
+
+    /// How much of a product is in the cart, 0 if it is not in the cart
+    pub(crate) fn quantity_of(&self, code: &str) -> f64 {
+        self.iter()
+            .filter(|item| item.code() == code)
+            .map(OrderItem::quantity)
+            .sum()
+    }
+
+    /// The totals an order of the cart would have, without creating the order
+    pub(crate) fn quote(&self, gift_wrap: bool, points_redeemed: u64) -> OrderTotals {
+        OrderTotals::compute(&self.0, gift_wrap, points_redeemed, 0.0, 0.0)
+    }
+
+    /// Remove an item from the cart.
+    pub(crate) fn remove_item(&mut self, code: &str) {
+        self.0.retain(|item| item.product.code != code);
+    }
+}
+
+/// A message from the shop to a user
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Notification {
+    at: chrono::DateTime<chrono::Local>,
+    message: String,
+    #[serde(default)]
+    read: bool,
+}
+
+impl Notification {
+    pub(crate) fn at(&self) -> chrono::DateTime<chrono::Local> {
+        self.at
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        self.message.as_ref()
+    }
+
+    pub(crate) fn is_read(&self) -> bool {
+        self.read
+    }
+}
+
+/// The user that will use the application
+#[derive(Serialize, Deserialize)]
+pub(crate) struct User {
+    username: String,
+    password_hash: String,
+    email: String,
+    /// The phone number used to coordinate deliveries
+    #[serde(default)]
+    phone: String,
+
+    cart: Cart,
+
+    /// The codes of the products the user marked as favorite
+    #[serde(default)]
+    favorites: Vec<String>,
+
+    /// The credit cards the user saved to pay faster
+    #[serde(default)]
+    saved_cards: Vec<SavedCard>,
+
+    /// The addresses the user can pick from at checkout
+    #[serde(default)]
+    addresses: Vec<Address>,
+
+    /// The loyalty points earned by paying orders
+    #[serde(default)]
+    points: u64,
+
+    /// The money the user can pay orders with, topped up by admins
+    #[serde(default)]
+    wallet_balance: f64,
+
+    /// How many times in a row a wrong password was given for the user
+    #[serde(default)]
+    failed_logins: u32,
+    #[serde(default)]
+    last_failed_login: Option<chrono::DateTime<chrono::Local>>,
+
+    /// The hash of the one-time code to reset the password, if the user asked for one
+    #[serde(default)]
+    password_reset_hash: Option<String>,
+    /// Until when the reset code can be used, codes without it are expired
+    #[serde(default)]
+    password_reset_expires_at: Option<chrono::DateTime<chrono::Local>>,
+
+    /// Messages from the shop to the user
+    #[serde(default)]
+    inbox: Vec<Notification>,
+
+    /// What the user may do in the shop
+    #[serde(default)]
+    role: Role,
+
+    /// Whether the user can log in, admins deactivate accounts instead of deleting them
+    #[serde(default = "User::default_active")]
+    active: bool,
+
+    /// How much the user wants to spend at most each calendar month, paying more is warned about
+    #[serde(default)]
+    monthly_budget: Option<f64>,
+
+    /// When the user registered, unknown for users who registered before it was recorded
+    #[serde(default)]
+    registered_at: Option<chrono::DateTime<chrono::Local>>,
+
+    /// When the user last logged in, none if they never did since it was recorded
+    #[serde(default)]
+    last_login: Option<chrono::DateTime<chrono::Local>>,
+
+    /// Whether the user must choose a new password before they can use the shop
+    #[serde(default)]
+    must_change_password: bool,
+}
+
+impl User {
+    /// The shortest password a user can have
+    pub(crate) const MIN_PASSWORD_LENGTH: usize = 8;
+    /// The shortest username a user can register
+    pub(crate) const MIN_USERNAME_LENGTH: usize = 3;
+    /// The longest username a user can register
+    pub(crate) const MAX_USERNAME_LENGTH: usize = 32;
+
+    fn default_active() -> bool {
+        true
+    }
+
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn email(&self) -> &str {
+        self.email.as_ref()
+    }
+
+    /// Change the email of the user, only through [`UserManager::set_email`] which keeps the
+    /// emails unique
+    fn set_email(&mut self, email: String) {
+        self.email = email;
+    }
+
+    pub(crate) fn registered_at(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        self.registered_at
+    }
+
+    pub(crate) fn last_login(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        self.last_login
+    }
+
+    pub(crate) fn phone(&self) -> &str {
+        self.phone.as_ref()
+    }
+
+    pub(crate) fn cart(&self) -> &Cart {
+        &self.cart
+    }
+
+    pub(crate) fn cart_mut(&mut self) -> &mut Cart {
+        &mut self.cart
+    }
+
+    pub(crate) fn favorites(&self) -> &[String] {
+        self.favorites.as_ref()
+    }
+
+    /// Mark a product as favorite.
+    ///
+    /// Return whether the product was added (`true`) or was already a favorite (`false`).
+    pub(crate) fn add_favorite(&mut self, code: &str) -> bool {
+        if self.favorites.iter().any(|favorite| favorite == code) {
+            return false;
+        }
+        self.favorites.push(code.to_owned());
+        true
+    }
+
+    /// Remove a product from the favorites.
+    pub(crate) fn remove_favorite(&mut self, code: &str) {
+        self.favorites.retain(|favorite| favorite != code);
+    }
+
+    pub(crate) fn wallet_balance(&self) -> f64 {
+        self.wallet_balance
+    }
+
+    pub(crate) fn monthly_budget(&self) -> Option<f64> {
+        self.monthly_budget
+    }
+
+    /// Set the monthly budget of the user, `None` to remove it. A negative budget is refused.
+    pub(crate) fn set_monthly_budget(&mut self, budget: Option<f64>) -> bool {
+        if budget.is_some_and(|budget| !budget.is_finite() || budget < 0.0) {
+            return false;
+        }
+        self.monthly_budget = budget.map(round_money);
+        true
+    }
+
+    /// Add money to the wallet of the user
+    pub(crate) fn credit_wallet(&mut self, amount: f64) {
+        self.wallet_balance = round_money(self.wallet_balance + amount);
+    }
+
+    /// Take money from the wallet of the user.
+    ///
+    /// Return the missing amount if the balance is not enough, in which case nothing is taken.
+    pub(crate) fn debit_wallet(&mut self, amount: f64) -> Result<(), f64> {
+        if to_cents(self.wallet_balance) < to_cents(amount) {
+            return Err(round_money(amount - self.wallet_balance));
+        }
+        self.wallet_balance = round_money(self.wallet_balance - amount);
+        Ok(())
+    }
+
+    pub(crate) fn points(&self) -> u64 {
+        self.points
+    }
+
+    pub(crate) fn add_points(&mut self, points: u64) {
+        self.points += points;
+    }
+
+    /// Check if the password is the password of the user.
+    ///
+    /// Save files from before passwords were hashed hold the plain password instead of a bcrypt
+    /// hash, those are compared as they are until [`User::upgrade_password_hash`] replaces them. A
+    /// saved bcrypt hash that cannot be checked matches no password and is reported in the audit
+    /// log.
+    pub(crate) fn verify_password(&self, password: &str) -> bool {
+        if self.has_plaintext_password() {
+            return self.password_hash == password;
+        }
+        match bcrypt::verify(password, &self.password_hash) {
+            Ok(matches) => matches,
+            Err(err) => {
+                audit(
+                    "system",
+                    &format!(
+                        "warning: the password hash of user {} is invalid: {err}",
+                        self.username
+                    ),
+                );
+                false
+            }
+        }
+    }
+
+    /// Whether the saved password is a legacy plain password rather than a bcrypt hash.
+    ///
+    /// Every bcrypt hash starts with `$2`, so a value with that prefix that cannot be parsed is a
+    /// corrupted hash, which matches no password, rather than a plain password.
+    fn has_plaintext_password(&self) -> bool {
+        !self.password_hash.is_empty() && !self.password_hash.starts_with("$2")
+    }
+
+    /// Hash the password of the user again if it is still saved in plain text or its hash is
+    /// weaker than the current [`bcrypt_cost`], so old hashes get stronger as users log in.
+    ///
+    /// Must only be called with the right password.
+    fn upgrade_password_hash(&mut self, password: &str) {
+        if self.has_plaintext_password() {
+            if let Ok(password_hash) = hash_secret(password) {
+                self.password_hash = password_hash;
+                audit(&self.username, "plain text password replaced by a hash");
+            }
+            return;
+        }
+        let cost = self
+            .password_hash
+            .parse::<bcrypt::HashParts>()
+            .map(|parts| parts.get_cost());
+        if cost.is_ok_and(|cost| cost < bcrypt_cost()) {
+            if let Ok(password_hash) = hash_secret(password) {
+                self.password_hash = password_hash;
+                audit(&self.username, "password hash upgraded");
+            }
+        }
+    }
+
+    /// Change the password of the user.
+    ///
+    /// Fails if the password does not pass [`validate_password`]. A pending password reset code
+    /// can no longer be used afterwards, and a required password change is done.
+    pub(crate) fn set_password(&mut self, password: &str) -> Result<(), PasswordError> {
+        validate_password(&self.username, password)?;
+        self.password_hash = hash_secret(password).map_err(|_| PasswordError::HashFailed)?;
+        self.password_reset_hash = None;
+        self.password_reset_expires_at = None;
+        self.must_change_password = false;
+        Ok(())
+    }
+
+    pub(crate) fn must_change_password(&self) -> bool {
+        self.must_change_password
+    }
+
+    pub(crate) fn inbox(&self) -> &[Notification] {
+        &self.inbox
+    }
+
+    /// Put a message in the inbox of the user
+    pub(crate) fn notify(&mut self, message: String) {
+        self.inbox.push(Notification {
+            at: chrono::Local::now(),
+            message,
+            read: false,
+        });
+    }
+
+    /// The number of messages in the inbox the user has not read yet
+    pub(crate) fn unread_notifications(&self) -> usize {
+        self.inbox.iter().filter(|n| !n.read).count()
+    }
+
+    /// Mark all messages in the inbox as read
+    pub(crate) fn mark_inbox_read(&mut self) {
+        self.inbox.iter_mut().for_each(|n| n.read = true);
+    }
+
+    /// The most loyalty points the user can redeem on an order of their current cart.
+    ///
+    /// Users cannot redeem more points than they have or more than the cart is worth.
+    pub(crate) fn max_redeemable_points(&self) -> u64 {
+        let cart_points = (self.cart.total_price() * POINTS_PER_EGP as f64).floor() as u64;
+        self.points.min(cart_points)
+    }
+
+    pub(crate) fn saved_cards(&self) -> &[SavedCard] {
+        self.saved_cards.as_ref()
+    }
+
+    pub(crate) fn add_saved_card(&mut self, card: SavedCard) {
+        self.saved_cards.push(card);
+    }
+
+    /// Remove the saved card at the given index.
+    ///
+    /// Return whether there was a card at this index (`true`) or not (`false`).
+    pub(crate) fn remove_saved_card(&mut self, index: usize) -> bool {
+        if index < self.saved_cards.len() {
+            self.saved_cards.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn addresses(&self) -> &[Address] {
+        self.addresses.as_ref()
+    }
+
+    pub(crate) fn add_address(&mut self, address: Address) {
+        self.addresses.push(address);
+    }
+
+    /// Remove the address at the given index.
+    ///
+    /// Return whether there was an address at this index (`true`) or not (`false`).
+    pub(crate) fn remove_address(&mut self, index: usize) -> bool {
+        if index < self.addresses.len() {
+            self.addresses.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add every favorite product that is still in the catalog to the cart with quantity 1.
+    ///
+    /// Return the codes of the favorites that were skipped because they are no longer listed, out
+    /// of stock or already at the maximum quantity of a cart line.
+    pub(crate) fn add_favorites_to_cart(&mut self, catalog: &Catalog) -> Vec<String> {
+        let mut skipped = Vec::new();
+        for code in &self.favorites {
+            let added = match catalog.product(code) {
+                Some(product) if catalog.available(code, &self.cart) >= 1.0 => self
+                    .cart
+                    .add_item(product, 1.0, catalog.max_line_quantity())
+                    .is_ok(),
+                _ => false,
+            };
+            if !added {
+                skipped.push(code.clone());
+            }
+        }
+        skipped
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Check if the user is an admin.
+    pub(crate) fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Check if the user has one of the roles
+    pub(crate) fn has_role(&self, roles: &[Role]) -> bool {
+        roles.contains(&self.role)
+    }
+
+    /// Check if the user can log in
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Check if too many wrong passwords were given for the user within the last `lock_minutes`,
+    /// or at any time if `lock_minutes` is 0
+    pub(crate) fn is_login_locked(
+        &self,
+        now: chrono::DateTime<chrono::Local>,
+        lock_minutes: u32,
+    ) -> bool {
+        let lock = chrono::Duration::minutes(lock_minutes.into());
+        self.failed_logins >= UserManager::MAX_FAILED_LOGINS
+            && (lock_minutes == 0
+                || self
+                    .last_failed_login
+                    .is_some_and(|last_failed_login| now - last_failed_login < lock))
+    }
+}
+
+/// What a user may do in the shop
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    /// Shops for themselves
+    #[default]
+    Customer,
+    /// Manages the stock and fulfils orders, but not users or prices
+    Staff,
+    /// Delivers the orders assigned to them
+    Courier,
+    /// Manages everything in the shop
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Customer => f.write_str("customer"),
+            Self::Staff => f.write_str("staff"),
+            Self::Courier => f.write_str("courier"),
+            Self::Admin => f.write_str("admin"),
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "customer" => Ok(Self::Customer),
+            "staff" => Ok(Self::Staff),
+            "courier" => Ok(Self::Courier),
+            "admin" => Ok(Self::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Why the admin rights of a user could not be changed
+pub(crate) enum RoleError {
+    UserNotFound,
+    /// The change would leave the shop without any admin who can log in
+    LastAdmin,
+}
+
+impl std::fmt::Display for RoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UserNotFound => f.write_str("user not found"),
+            Self::LastAdmin => f.write_str("the shop must keep at least one active admin"),
+        }
+    }
+}
+
+/// Check that a phone number is made of 7 to 15 digits with an optional leading `+`
+pub(crate) fn is_valid_phone(phone: &str) -> bool {
+    let digits = phone.strip_prefix('+').unwrap_or(phone);
+    (7..=15).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Check that an email looks like `name@example.com`.
+///
+/// It must have exactly one `@` with something before it, a domain with a dot after it, and no
+/// whitespace.
+pub(crate) fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !email.chars().any(char::is_whitespace)
+}
+
+/// Quote a CSV field if it has a comma, a quote or a line break, doubling the quotes in it
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// The bcrypt cost of new password hashes when no other cost is set
+pub(crate) const DEFAULT_BCRYPT_COST: u32 = 10;
+
+/// The bcrypt cost used by [`hash_secret`], set from the saved application on load
+static BCRYPT_COST: std::sync::atomic::AtomicU32 =
+    std::sync::atomic::AtomicU32::new(DEFAULT_BCRYPT_COST);
+
+/// The bcrypt cost of new password hashes
+pub(crate) fn bcrypt_cost() -> u32 {
+    BCRYPT_COST.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// The bcrypt costs that can be set. Bcrypt supports up to 31, but every login and registration
+/// hashes in the interactive loop and each step doubles the time, so higher costs would make the
+/// application hang for minutes or hours.
+pub(crate) const BCRYPT_COSTS: std::ops::RangeInclusive<u32> = 4..=16;
+
+/// Make this the cost of new password hashes. Fails if the cost is not in [`BCRYPT_COSTS`].
+pub(crate) fn set_bcrypt_cost(cost: u32) -> bool {
+    if !BCRYPT_COSTS.contains(&cost) {
+        return false;
+    }
+    BCRYPT_COST.store(cost, std::sync::atomic::Ordering::Relaxed);
+    true
+}
+
+/// Hash a password or a reset code with the current [`bcrypt_cost`]
+fn hash_secret(secret: &str) -> bcrypt::BcryptResult<String> {
+    bcrypt::hash(secret, bcrypt_cost())
+}
+
+/// Passwords that are too easy to guess, compared without case
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "password1",
+    "password123",
+    "passw0rd",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "87654321",
+    "11111111",
+    "00000000",
+    "abc12345",
+    "abcd1234",
+    "qwerty123",
+    "qwertyuiop",
+    "iloveyou1",
+    "letmein1",
+    "welcome1",
+    "admin123",
+];
+
+/// Why a password was refused
+pub(crate) enum PasswordError {
+    TooShort,
+    NoLetter,
+    NoDigit,
+    SameAsUsername,
+    TooCommon,
+    /// The password is fine but hashing it failed
+    HashFailed,
+}
+
+impl std::fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TooShort => write!(
+                f,
+                "the password must have at least {} characters",
+                User::MIN_PASSWORD_LENGTH
+            ),
+            Self::NoLetter => f.write_str("the password must have at least one letter"),
+            Self::NoDigit => f.write_str("the password must have at least one digit"),
+            Self::SameAsUsername => f.write_str("the password can not be the username"),
+            Self::TooCommon => f.write_str("the password is too common"),
+            Self::HashFailed => f.write_str("the password could not be stored"),
+        }
+    }
+}
+
+/// Check that a password is hard enough to guess.
+///
+/// It must have at least [`User::MIN_PASSWORD_LENGTH`] characters with at least one letter and
+/// one digit, and it can neither be the username nor one of a few very common passwords.
+pub(crate) fn validate_password(username: &str, password: &str) -> Result<(), PasswordError> {
+    if password.chars().count() < User::MIN_PASSWORD_LENGTH {
+        return Err(PasswordError::TooShort);
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Err(PasswordError::SameAsUsername);
+    }
+    if COMMON_PASSWORDS
+        .iter()
+        .any(|common| password.eq_ignore_ascii_case(common))
+    {
+        return Err(PasswordError::TooCommon);
+    }
+    if !password.chars().any(char::is_alphabetic) {
+        return Err(PasswordError::NoLetter);
+    }
+    if !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err(PasswordError::NoDigit);
+    }
+    Ok(())
+}
+
+/// Usernames that can not be registered because they would be mistaken for the shop itself
+const RESERVED_USERNAMES: &[&str] = &["admin", "system"];
+
+/// Why a username was refused
+pub(crate) enum UsernameError {
+    TooShort,
+    TooLong,
+    /// The username does not start with a letter
+    NoLeadingLetter,
+    /// The username has a character other than a letter, a digit, `_` or `.`
+    InvalidCharacter(char),
+    Reserved,
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TooShort => write!(
+                f,
+                "the username must have at least {} characters",
+                User::MIN_USERNAME_LENGTH
+            ),
+            Self::TooLong => write!(
+                f,
+                "the username can have at most {} characters",
+                User::MAX_USERNAME_LENGTH
+            ),
+            Self::NoLeadingLetter => f.write_str("the username must start with a letter"),
+            Self::InvalidCharacter(c) => write!(
+                f,
+                "the username can not contain {c:?}, only letters, digits, _ and ."
+            ),
+            Self::Reserved => f.write_str("the username is reserved"),
+        }
+    }
+}
+
+/// Check that a username can be registered.
+///
+/// It must have [`User::MIN_USERNAME_LENGTH`] to [`User::MAX_USERNAME_LENGTH`] characters, start
+/// with a letter and only have letters, digits, `_` and `.`, so it can not break the prompt.
+/// A few names of the shop itself are reserved.
+pub(crate) fn validate_username(username: &str) -> Result<(), UsernameError> {
+    let length = username.chars().count();
+    if length < User::MIN_USERNAME_LENGTH {
+        return Err(UsernameError::TooShort);
+    }
+    if length > User::MAX_USERNAME_LENGTH {
+        return Err(UsernameError::TooLong);
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err(UsernameError::NoLeadingLetter);
+    }
+    if let Some(c) = username
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+    {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+    if RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| username.eq_ignore_ascii_case(reserved))
+    {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(())
+}
+
+/// Why a user could not be registered
+pub(crate) enum RegisterError {
+    InvalidUsername(UsernameError),
+    UsernameTaken,
+    WeakPassword(PasswordError),
+    /// Hashing the password failed
+    HashFailed,
+    InvalidEmail,
+    EmailTaken,
+    InvalidPhone,
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidUsername(err) => err.fmt(f),
+            Self::UsernameTaken => f.write_str("the username is already taken"),
+            Self::WeakPassword(err) => err.fmt(f),
+            Self::HashFailed => f.write_str("the password could not be stored"),
+            Self::InvalidEmail => f.write_str("the email is invalid"),
+            Self::EmailTaken => f.write_str("the email is already registered"),
+            Self::InvalidPhone => f.write_str(
+                "the phone number is invalid, it must have 7 to 15 digits and may start with +",
+            ),
+        }
+    }
+}
+
+/// The user manager contains all users
+/// 
+/// This class is responsible for adding new users and checking that no user have the same username
+/// or email.
+#[derive(Serialize)]
+pub(crate) struct UserManager {
+    users: Vec<User>,
+    /// How many minutes the login of a user stays locked after the last wrong password, 0 to keep
+    /// it locked until an admin unlocks it
+    #[serde(default = "UserManager::default_login_lock_minutes")]
+    login_lock_minutes: u32,
+
+    /// The usernames of all users.
+    ///
+    /// This and `emails_taken` are not saved, deserializing the user manager rebuilds them from
+    /// the users so usernames and emails stay unique after a restart.
+    #[serde(skip)]
+    usernames_taken: std::collections::HashSet<String>,
+    /// The lowercase emails of all users
+    #[serde(skip)]
+    emails_taken: std::collections::HashSet<String>,
+}
+
+impl Default for UserManager {
+    fn default() -> Self {
+        Self {
+            users: Default::default(),
+            login_lock_minutes: Self::default_login_lock_minutes(),
+            usernames_taken: Default::default(),
+            emails_taken: Default::default(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UserManager {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// The saved part of the user manager
+        #[derive(Deserialize)]
+        struct SavedUsers {
+            users: Vec<User>,
+            #[serde(default = "UserManager::default_login_lock_minutes")]
+            login_lock_minutes: u32,
+        }
+
+        let SavedUsers {
+            users,
+            login_lock_minutes,
+        } = SavedUsers::deserialize(deserializer)?;
+        let mut user_manager = UserManager {
+            users,
+            login_lock_minutes,
+            ..Default::default()
+        };
+        user_manager.rebuild_taken();
+        Ok(user_manager)
+    }
+}
+
+impl UserManager {
+    fn default_login_lock_minutes() -> u32 {
+        15
+    }
+
+    pub(crate) fn login_lock_minutes(&self) -> u32 {
+        self.login_lock_minutes
+    }
+
+    pub(crate) fn set_login_lock_minutes(&mut self, minutes: u32) {
+        self.login_lock_minutes = minutes;
+    }
+
+    /// Let a user whose login is locked after too many wrong passwords log in again.
+    ///
+    /// Return whether the user exists.
+    pub(crate) fn unlock(&mut self, username: &str) -> bool {
+        let Some(user) = self.find_mut(username) else {
+            return false;
+        };
+        user.failed_logins = 0;
+        user.last_failed_login = None;
+        true
+    }
+
+    /// How many hours a password reset code can be used
+    pub(crate) const RESET_CODE_HOURS: i64 = 24;
+
+    /// Give the user a new one-time code to reset their password.
+    ///
+    /// Only the hash of the code is kept and a new code replaces the previous one. The code
+    /// expires after [`UserManager::RESET_CODE_HOURS`].
+    fn new_reset_code(user: &mut User) -> Option<String> {
+        use rand::{distributions::Alphanumeric, Rng};
+
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        user.password_reset_hash = Some(hash_secret(&code).ok()?);
+        user.password_reset_expires_at =
+            Some(chrono::Local::now() + chrono::Duration::hours(Self::RESET_CODE_HOURS));
+        Some(code)
+    }
+
+    /// Start a password reset for the user with the given username and email.
+    ///
+    /// Return the one-time reset code to send to the user, or `None` if no user matches. A code is
+    /// hashed either way, so how long this takes does not tell whether the user exists.
+    pub(crate) fn request_password_reset(&mut self, username: &str, email: &str) -> Option<String> {
+        let Some(user) = self
+            .find_mut(username)
+            .filter(|u| u.email.eq_ignore_ascii_case(email))
+        else {
+            hash_secret(username).ok();
+            return None;
+        };
+        Self::new_reset_code(user)
+    }
+
+    /// Start a password reset for a user on behalf of an admin, who passes the code on to them.
+    ///
+    /// Until the password is reset, the user must also change it if they log in with the old
+    /// one. Return the one-time reset code, or `None` if there is no such user.
+    pub(crate) fn issue_reset_code(&mut self, username: &str) -> Option<String> {
+        let user = self.find_mut(username)?;
+        user.must_change_password = true;
+        Self::new_reset_code(user)
+    }
+
+    /// Make a user choose a new password the next time they log in.
+    ///
+    /// Return whether the user exists.
+    pub(crate) fn require_password_change(&mut self, username: &str) -> bool {
+        let Some(user) = self.find_mut(username) else {
+            return false;
+        };
+        user.must_change_password = true;
+        true
+    }
+
+    /// Set a new password for the user if the reset code is right and has not expired.
+    ///
+    /// The password must pass [`validate_password`], which is checked first so a weak password
+    /// tells nothing about the user. The code can only be used once. A bcrypt hash is checked
+    /// even for unknown users and users without a code, so how long this takes does not tell
+    /// whether the user exists. A successful reset also unlocks the login of the user.
+    pub(crate) fn reset_password(
+        &mut self,
+        username: &str,
+        code: &str,
+        password: &str,
+    ) -> Result<(), ResetError> {
+        validate_password(username, password).map_err(ResetError::Password)?;
+        let Some(user) = self
+            .find_mut(username)
+            .filter(|user| user.password_reset_hash.is_some())
+        else {
+            hash_secret(code).ok();
+            return Err(ResetError::InvalidCode);
+        };
+        let valid = user
+            .password_reset_hash
+            .as_ref()
+            .is_some_and(|reset_hash| bcrypt::verify(code.trim(), reset_hash).unwrap_or(false));
+        let expired = user
+            .password_reset_expires_at
+            .is_none_or(|expires_at| expires_at < chrono::Local::now());
+        if !valid || expired {
+            return Err(ResetError::InvalidCode);
+        }
+        user.set_password(password).map_err(ResetError::Password)?;
+        user.failed_logins = 0;
+        user.last_failed_login = None;
+        Ok(())
+    }
+
+    /// How many wrong passwords in a row lock the login of a user
+    pub(crate) const MAX_FAILED_LOGINS: u32 = 5;
+
+    /// Create a new user with the given username, password, email and phone number.
+    ///
+    /// The first user of a new shop is made an admin so the shop can be set up, every later user
+    /// is a customer until an admin promotes them.
+    ///
+    /// The username is trimmed and compared with the other usernames without case, but it is kept
+    /// as it was typed.
+    ///
+    /// Fails if the username or the email is already used by another user, if the password is not
+    /// strong enough, or if the username, the email or the phone number is invalid.
+    pub fn add_user(
+        &mut self,
+        username: String,
+        password: String,
+        email: String,
+        phone: String,
+    ) -> Result<(), RegisterError> {
+        let username = username.trim().to_owned();
+        validate_username(&username).map_err(RegisterError::InvalidUsername)?;
+        if self
+            .usernames_taken
+            .contains(&Self::normalize_username(&username))
+        {
+            return Err(RegisterError::UsernameTaken);
+        }
+        validate_password(&username, &password).map_err(RegisterError::WeakPassword)?;
+        if !is_valid_email(&email) {
+            return Err(RegisterError::InvalidEmail);
+        }
+        if self.emails_taken.contains(&email.to_lowercase()) {
+            return Err(RegisterError::EmailTaken);
+        }
+        if !is_valid_phone(&phone) {
+            return Err(RegisterError::InvalidPhone);
+        }
+        let password_hash = hash_secret(&password).map_err(|_| RegisterError::HashFailed)?;
+        self.usernames_taken
+            .insert(Self::normalize_username(&username));
+        self.emails_taken.insert(email.to_lowercase());
+
+        let role = if self.users.is_empty() {
+            Role::Admin
+        } else {
+            Role::Customer
+        };
+
+        self.users.push(User {
+            username,
+            password_hash,
+            email,
+            phone,
+
+            cart: Default::default(),
+            favorites: Default::default(),
+            saved_cards: Default::default(),
+            addresses: Default::default(),
+            points: 0,
+            wallet_balance: 0.0,
+            failed_logins: 0,
+            last_failed_login: None,
+            password_reset_hash: None,
+            password_reset_expires_at: None,
+            inbox: Default::default(),
+            role,
+            active: true,
+            monthly_budget: None,
+            registered_at: Some(chrono::Local::now()),
+            last_login: None,
+            must_change_password: false,
+        });
+
+        Ok(())
+    }
+
+    /// Fill the sets of taken usernames and emails from the users, they are not saved
+    fn rebuild_taken(&mut self) {
+        self.usernames_taken = self
+            .users
+            .iter()
+            .map(|u| Self::normalize_username(&u.username))
+            .collect();
+        self.emails_taken = self.users.iter().map(|u| u.email.to_lowercase()).collect();
+    }
+
+    /// Change the role of a user.
+    ///
+    /// The last admin cannot be given another role, so the shop can always be managed.
+    pub(crate) fn set_role(&mut self, username: &str, role: Role) -> Result<(), RoleError> {
+        let admins = self.active_admins();
+        let user = self.find_mut(username).ok_or(RoleError::UserNotFound)?;
+        if user.is_admin() && user.active && role != Role::Admin && admins == 1 {
+            return Err(RoleError::LastAdmin);
+        }
+        user.role = role;
+        Ok(())
+    }
+
+    /// Allow or refuse the logins of a user.
+    ///
+    /// The last active admin cannot be deactivated, so the shop can always be managed.
+    pub(crate) fn set_active(&mut self, username: &str, active: bool) -> Result<(), RoleError> {
+        let admins = self.active_admins();
+        let user = self.find_mut(username).ok_or(RoleError::UserNotFound)?;
+        if user.is_admin() && user.active && !active && admins == 1 {
+            return Err(RoleError::LastAdmin);
+        }
+        user.active = active;
+        Ok(())
+    }
+
+    /// Remove a user and free their username and email for new users.
+    ///
+    /// The last active admin cannot be removed, so the shop can always be managed.
+    pub(crate) fn remove_user(&mut self, username: &str) -> Result<User, RoleError> {
+        let admins = self.active_admins();
+        let index = self.position(username).ok_or(RoleError::UserNotFound)?;
+        let user = &self.users[index];
+        if user.is_admin() && user.active && admins == 1 {
+            return Err(RoleError::LastAdmin);
+        }
+        let user = self.users.remove(index);
+        self.usernames_taken
+            .remove(&Self::normalize_username(&user.username));
+        self.emails_taken.remove(&user.email.to_lowercase());
+        Ok(user)
+    }
+
+    /// Change the email of a user.
+    ///
+    /// Fails with [`RegisterError::InvalidEmail`] or [`RegisterError::EmailTaken`] like
+    /// registering would, giving the same email again is not an error. Unknown users are ignored.
+    pub(crate) fn set_email(&mut self, username: &str, email: &str) -> Result<(), RegisterError> {
+        let email = email.trim();
+        if !is_valid_email(email) {
+            return Err(RegisterError::InvalidEmail);
+        }
+        let Some(index) = self.position(username) else {
+            return Ok(());
+        };
+        let old_email = self.users[index].email.to_lowercase();
+        let new_email = email.to_lowercase();
+        if new_email != old_email && self.emails_taken.contains(&new_email) {
+            return Err(RegisterError::EmailTaken);
+        }
+        self.emails_taken.remove(&old_email);
+        self.emails_taken.insert(new_email);
+        self.users[index].set_email(email.to_owned());
+        Ok(())
+    }
+
+    /// The number of admins who can still log in
+    fn active_admins(&self) -> usize {
+        self.users
+            .iter()
+            .filter(|u| u.is_admin() && u.active)
+            .count()
+    }
+
+    pub(crate) fn users(&self) -> &[User] {
+        &self.users
+    }
+
+    /// The form of a username that is compared: without surrounding whitespace and in lowercase
+    fn normalize_username(username: &str) -> String {
+        username.trim().to_lowercase()
+    }
+
+    /// The index of the user with the given username, ignoring case and surrounding whitespace.
+    ///
+    /// An exact match is preferred, so users of older shops whose usernames only differ in case
+    /// can still be told apart.
+    fn position(&self, username: &str) -> Option<usize> {
+        self.users
+            .iter()
+            .position(|u| u.username == username)
+            .or_else(|| {
+                let username = Self::normalize_username(username);
+                self.users
+                    .iter()
+                    .position(|u| Self::normalize_username(&u.username) == username)
+            })
+    }
+
+    /// Find a user by their username, ignoring case and surrounding whitespace
+    pub(crate) fn find(&self, username: &str) -> Option<&User> {
+        self.position(username).map(|i| &self.users[i])
+    }
+
+    /// Find a user by their username, ignoring case and surrounding whitespace
+    pub(crate) fn find_mut(&mut self, username: &str) -> Option<&mut User> {
+        self.position(username).map(|i| &mut self.users[i])
+    }
+
+    /// The groups of usernames that only differ in case or surrounding whitespace.
+    ///
+    /// Shops created before usernames were compared this way can have them. They are reported
+    /// instead of merged, since each one is a separate account.
+    pub(crate) fn colliding_usernames(&self) -> Vec<Vec<&str>> {
+        let mut groups: std::collections::BTreeMap<String, Vec<&str>> = Default::default();
+        for user in &self.users {
+            groups
+                .entry(Self::normalize_username(&user.username))
+                .or_default()
+                .push(&user.username);
+        }
+        groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .collect()
+    }
+
+    /// Find a user by their username, ignoring case and surrounding whitespace, and password.
+    ///
+    /// After [`UserManager::MAX_FAILED_LOGINS`] wrong passwords in a row, logins of the user are
+    /// refused until the lock minutes passed since the last failure or an admin unlocks the user,
+    /// even with the right password. Admins are not excluded, their account is the one most worth
+    /// guessing. A successful login or the end of the lock resets the counter.
+    pub(crate) fn user_login_mut(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<&mut User, LoginError> {
+        let now = chrono::Local::now();
+        let lock_minutes = self.login_lock_minutes;
+        let user = self.find_mut(&username).ok_or(LoginError::Unauthorized)?;
+
+        if user.is_login_locked(now, lock_minutes) {
+            return Err(LoginError::TooManyAttempts);
+        }
+        if user.failed_logins >= UserManager::MAX_FAILED_LOGINS {
+            // The lock expired, the next wrong passwords are counted from zero again
+            user.failed_logins = 0;
+        }
+
+        if user.verify_password(&password) {
+            user.failed_logins = 0;
+            user.last_failed_login = None;
+            user.upgrade_password_hash(&password);
+            if !user.active {
+                return Err(LoginError::Deactivated);
+            }
+            user.last_login = Some(now);
+            Ok(user)
+        } else {
+            user.failed_logins += 1;
+            user.last_failed_login = Some(now);
+            Err(LoginError::Unauthorized)
+        }
+    }
+}
+
+/// Why a password could not be reset with a reset code
+pub(crate) enum ResetError {
+    /// The code is wrong or expired, or the user does not exist or has no code
+    InvalidCode,
+    /// The new password does not follow the password policy
+    Password(PasswordError),
+}
+
+impl std::fmt::Display for ResetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidCode => f.write_str("invalid or expired reset code"),
+            Self::Password(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Why a user could not log in
+pub(crate) enum LoginError {
+    /// The username or the password is wrong
+    Unauthorized,
+    /// Too many wrong passwords were given recently
+    TooManyAttempts,
+    /// The password is right but an admin deactivated the account
+    Deactivated,
+}
+
+/// Why the stock of a product could not be changed
+pub(crate) enum StockError {
+    UnknownProduct,
+    /// The stock of the product is not tracked, it can only be set
+    NotTracked,
+    /// The new stock would be negative or too big
+    OutOfRange,
+}
+
+impl std::fmt::Display for StockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnknownProduct => f.write_str("there is no product with this code"),
+            Self::NotTracked => {
+                f.write_str("the stock of this product is not tracked, set it with --set")
+            }
+            Self::OutOfRange => f.write_str("the stock can not be negative"),
+        }
+    }
+}
+
+/// The list of all available items
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Catalog {
+    products: Vec<Product>,
+
+    /// The most of one product a cart can have, against typing extra zeros, 0 for no maximum
+    #[serde(default = "Catalog::default_max_line_quantity")]
+    max_line_quantity: u32,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self {
+            products: Default::default(),
+            max_line_quantity: Self::default_max_line_quantity(),
+        }
+    }
+}
+
+impl Catalog {
+    fn default_max_line_quantity() -> u32 {
+        100
+    }
+
+    pub(crate) fn max_line_quantity(&self) -> u32 {
+        self.max_line_quantity
+    }
+
+    pub(crate) fn set_max_line_quantity(&mut self, max_line_quantity: u32) {
+        self.max_line_quantity = max_line_quantity;
+    }
+
+    /// Add a product to the catalog, replacing a discontinued product with the same code
+    pub(crate) fn add_product(&mut self, product: Product) {
+        self.products
+            .retain(|old| old.active || old.code != product.code);
+        self.products.push(product);
+    }
+
+    /// Stop selling a product. It is kept as discontinued so reports can still name it.
+    ///
+    /// Return false if there is no such product on sale.
+    pub(crate) fn remove_product(&mut self, code: &str) -> bool {
+        match self
+            .products
+            .iter_mut()
+            .find(|product| product.active && product.code == code)
+        {
+            Some(product) => {
+                product.active = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The products on sale
+    pub(crate) fn products(&self) -> Vec<&Product> {
+        self.products
+            .iter()
+            .filter(|product| product.active)
+            .collect()
+    }
+
+    /// The products on sale that cost at least `min` and at most `max` per unit, either bound
+    /// can be left out
+    pub(crate) fn products_by_price(&self, min: Option<f64>, max: Option<f64>) -> Vec<&Product> {
+        self.products()
+            .into_iter()
+            .filter(|product| min.is_none_or(|min| product.unit_price >= min))
+            .filter(|product| max.is_none_or(|max| product.unit_price <= max))
+            .collect()
+    }
+
+    /// The products that are no longer sold
+    pub(crate) fn discontinued_products(&self) -> Vec<&Product> {
+        self.products
+            .iter()
+            .filter(|product| !product.active)
+            .collect()
+    }
+
+    /// Find a product on sale by its code
+    pub(crate) fn product(&self, code: &str) -> Option<&Product> {
+        self.products
+            .iter()
+            .find(|product| product.active && product.code == code)
+    }
+
+    /// Up to `n` random products that are in stock and whose codes are not in `excluding`
+    pub(crate) fn suggestions(&self, excluding: &[&str], n: usize) -> Vec<&Product> {
+        use rand::seq::IteratorRandom;
+
+        self.products
+            .iter()
+            .filter(|product| product.active && !product.is_out_of_stock())
+            .filter(|product| !excluding.contains(&product.code()))
+            .choose_multiple(&mut rand::thread_rng(), n)
+    }
+
+    /// Find a product on sale by its barcode
+    pub(crate) fn product_by_barcode(&self, barcode: &str) -> Option<&Product> {
+        self.products
+            .iter()
+            .find(|product| product.active && product.barcode.as_deref() == Some(barcode))
+    }
+
+    /// Find a product by its code or its barcode
+    pub(crate) fn find(&self, code_or_barcode: &str) -> Option<&Product> {
+        self.product(code_or_barcode)
+            .or_else(|| self.product_by_barcode(code_or_barcode))
+    }
+
+    /// Change the stock of a product by `quantity`, or set it to `quantity` if `set` is true.
+    ///
+    /// Setting the stock also starts tracking it for products that were not tracked. Return the
+    /// new stock.
+    pub(crate) fn restock(
+        &mut self,
+        code: &str,
+        quantity: i64,
+        set: bool,
+    ) -> Result<u32, StockError> {
+        let product = self
+            .products
+            .iter_mut()
+            .find(|product| product.code == code)
+            .ok_or(StockError::UnknownProduct)?;
+        let stock = match (product.stock, set) {
+            (_, true) => quantity,
+            (Some(stock), false) => i64::from(stock) + quantity,
+            (None, false) => return Err(StockError::NotTracked),
+        };
+        let stock = u32::try_from(stock).map_err(|_| StockError::OutOfRange)?;
+        product.stock = Some(stock);
+        Ok(stock)
+    }
+
+    /// Mark a product as exempt from tax or not. Return false if there is no such product.
+    ///
+    /// Products already in orders keep the exemption they had when they were ordered.
+    pub(crate) fn set_tax_exempt(&mut self, code: &str, tax_exempt: bool) -> bool {
+        match self
+            .products
+            .iter_mut()
+            .find(|product| product.code == code)
+        {
+            Some(product) => {
+                product.tax_exempt = tax_exempt;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change the unit price of a product and record the change in its price history. Orders
+    /// already placed keep the price they were placed at.
+    ///
+    /// Return the old price, or `None` if there is no such product.
+    pub(crate) fn set_price(&mut self, code: &str, unit_price: f64) -> Option<f64> {
+        let product = self
+            .products
+            .iter_mut()
+            .find(|product| product.active && product.code == code)?;
+        let old = product.unit_price;
+        product.unit_price = unit_price;
+        product.price_history.push(PriceChange {
+            timestamp: chrono::Local::now(),
+            old,
+            new: unit_price,
+        });
+        Some(old)
+    }
+
+    /// Highlight a product at the top of the catalog or stop doing so. Return false if there is no
+    /// such product.
+    pub(crate) fn set_featured(&mut self, code: &str, featured: bool) -> bool {
+        match self
+            .products
+            .iter_mut()
+            .find(|product| product.active && product.code == code)
+        {
+            Some(product) => {
+                product.featured = featured;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set or clear the stock at which a product should be ordered again. Return false if there
+    /// is no such product.
+    pub(crate) fn set_reorder_point(&mut self, code: &str, reorder_point: Option<u32>) -> bool {
+        match self
+            .products
+            .iter_mut()
+            .find(|product| product.active && product.code == code)
+        {
+            Some(product) => {
+                product.reorder_point = reorder_point;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The products on sale whose tracked stock is at or below their reorder point, with how far
+    /// below it they are, furthest below first
+    pub(crate) fn to_reorder(&self) -> Vec<(&Product, u32)> {
+        let mut products: Vec<(&Product, u32)> = self
+            .products()
+            .into_iter()
+            .filter_map(|product| {
+                let (stock, reorder_point) = (product.stock?, product.reorder_point?);
+                (stock <= reorder_point).then_some((product, reorder_point - stock))
+            })
+            .collect();
+        products.sort_by(|(a, a_below), (b, b_below)| {
+            b_below.cmp(a_below).then_with(|| a.code.cmp(&b.code))
+        });
+        products
+    }
+
+    /// The products whose stock is tracked and at most `threshold`, lowest stock first
+    pub(crate) fn low_stock(&self, threshold: u32) -> Vec<&Product> {
+        let mut products: Vec<&Product> = self
+            .products
+            .iter()
+            .filter(|product| {
+                product.active && product.stock.is_some_and(|stock| stock <= threshold)
+            })
+            .collect();
+        products.sort_by_key(|product| product.stock);
+        products
+    }
+
+    /// How much more of a product can be added to a cart.
+    ///
+    /// This is the stock minus what is already in the cart, or infinity if the stock of the
+    /// product is not tracked.
+    pub(crate) fn available(&self, code: &str, cart: &Cart) -> f64 {
+        match self.product(code).and_then(Product::stock) {
+            Some(stock) => (f64::from(stock) - cart.quantity_of(code)).max(0.0),
+            None => f64::INFINITY,
+        }
+    }
+
+    /// Take the items of a cart out of the stock of their products.
+    ///
+    /// Nothing is taken if a product does not have enough stock left, in which case the code of
+    /// the first such product is returned. Partial units are taken as a whole unit.
+    pub(crate) fn take_stock(&mut self, cart: &Cart) -> Result<(), String> {
+        if let Some(item) = cart
+            .iter()
+            .find(|item| self.available(item.code(), &Cart::default()) < item.quantity())
+        {
+            return Err(item.code().to_owned());
+        }
+        for item in cart.iter() {
+            if let Some(product) = self.products.iter_mut().find(|p| p.code == item.code()) {
+                if let Some(stock) = &mut product.stock {
+                    *stock = stock.saturating_sub(item.quantity().ceil() as u32);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Take or put back the stock of a product when its quantity in an order changes.
+    ///
+    /// Partial units count as a whole unit, like in [`Catalog::take_stock`]. Fails with the stock
+    /// left if there is not enough of it, nothing is changed then.
+    pub(crate) fn change_stock(&mut self, code: &str, from: f64, to: f64) -> Result<(), f64> {
+        let Some(stock) = self
+            .products
+            .iter_mut()
+            .find(|p| p.code == code)
+            .and_then(|p| p.stock.as_mut())
+        else {
+            return Ok(());
+        };
+        let taken = to.ceil() as i64 - from.ceil() as i64;
+        if taken > i64::from(*stock) {
+            return Err(f64::from(*stock));
+        }
+        *stock = (i64::from(*stock) - taken) as u32;
+        Ok(())
+    }
+
+    /// Put the items of a cancelled order back in the stock of their products.
+    ///
+    /// This gives back exactly what [`Catalog::take_stock`] took.
+    pub(crate) fn release_stock(&mut self, items: &[OrderItem]) {
+        for item in items {
+            if let Some(product) = self.products.iter_mut().find(|p| p.code == item.code()) {
+                if let Some(stock) = &mut product.stock {
+                    *stock = stock.saturating_add(item.quantity().ceil() as u32);
+                }
+            }
+        }
+    }
+}
+
+/// A prepaid card that pays all or part of orders until its balance is used up
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GiftCard {
+    code: String,
+    initial_value: f64,
+    balance: f64,
+}
+
+impl GiftCard {
+    pub(crate) fn code(&self) -> &str {
+        self.code.as_ref()
+    }
+
+    pub(crate) fn initial_value(&self) -> f64 {
+        self.initial_value
+    }
+
+    pub(crate) fn balance(&self) -> f64 {
+        self.balance
+    }
+
+    /// Check if the whole balance was used, in which case the card cannot pay anymore
+    pub(crate) fn is_used_up(&self) -> bool {
+        to_cents(self.balance) <= 0
+    }
+
+    /// Take up to `amount` from the balance of the card.
+    ///
+    /// Return the amount that was actually taken.
+    pub(crate) fn redeem(&mut self, amount: f64) -> f64 {
+        let amount = round_money(amount.min(self.balance).max(0.0));
+        self.balance = round_money(self.balance - amount);
+        amount
+    }
+
+    /// Put refunded money back on the balance of the card
+    pub(crate) fn credit(&mut self, amount: f64) {
+        self.balance = round_money(self.balance + amount);
+    }
+}
+
+/// All gift cards sold by the shop
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct GiftCardManager {
+    #[serde(default)]
+    gift_cards: Vec<GiftCard>,
+}
+
+impl GiftCardManager {
+    /// Create a gift card of the given value with a new random code like "ABCD-EFGH-JKLM"
+    pub(crate) fn create(&mut self, value: f64) -> &GiftCard {
+        use rand::Rng;
+        const CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code = (0..3)
+                .map(|_| {
+                    (0..4)
+                        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("-");
+            if self.find_mut(&code).is_none() {
+                break code;
+            }
+        };
+
+        self.gift_cards.push(GiftCard {
+            code,
+            initial_value: round_money(value),
+            balance: round_money(value),
+        });
+        self.gift_cards.last().unwrap()
+    }
+
+    pub(crate) fn gift_cards(&self) -> &[GiftCard] {
+        &self.gift_cards
+    }
+
+    /// Find a gift card by its code, ignoring the case
+    pub(crate) fn find_mut(&mut self, code: &str) -> Option<&mut GiftCard> {
+        self.gift_cards
+            .iter_mut()
+            .find(|gift_card| gift_card.code.eq_ignore_ascii_case(code))
+    }
+}
+
+/// A promo code applied to an order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct AppliedPromo {
+    code: String,
+    /// The percentage taken off the items, used to update the discount when the order is edited
+    #[serde(default)]
+    percent_off: u8,
+    /// The money taken off the order
+    discount: f64,
+}
+
+impl AppliedPromo {
+    pub(crate) fn code(&self) -> &str {
+        self.code.as_ref()
+    }
+
+    pub(crate) fn discount(&self) -> f64 {
+        self.discount
+    }
+}
+
+/// Why a promo code could not be applied to an order
+pub(crate) enum PromoError {
+    UnknownCode,
+    Expired,
+    UsedUp,
+    OrderNotOpen,
+    /// The order already has a discount and discounts are not stacked
+    AlreadyDiscounted,
+}
+
+impl std::fmt::Display for PromoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnknownCode => f.write_str("unknown promo code"),
+            Self::Expired => f.write_str("this promo code has expired"),
+            Self::UsedUp => f.write_str("this promo code was used too many times"),
+            Self::OrderNotOpen => f.write_str("the order is already paid"),
+            Self::AlreadyDiscounted => {
+                f.write_str("the order already has a discount, discounts can not be combined")
+            }
+        }
+    }
+}
+
+/// A code sent by marketing that takes a percentage off an open order when paying
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PromoCode {
+    code: String,
+    percent_off: u8,
+    /// The last day the code can be used
+    #[serde(default)]
+    expires_on: Option<chrono::NaiveDate>,
+    /// How many orders can use the code, unlimited if not set
+    #[serde(default)]
+    max_uses: Option<u32>,
+    #[serde(default)]
+    uses: u32,
+}
+
+impl PromoCode {
+    pub(crate) fn code(&self) -> &str {
+        self.code.as_ref()
+    }
+
+    pub(crate) fn percent_off(&self) -> u8 {
+        self.percent_off
+    }
+
+    pub(crate) fn expires_on(&self) -> Option<chrono::NaiveDate> {
+        self.expires_on
+    }
+
+    pub(crate) fn max_uses(&self) -> Option<u32> {
+        self.max_uses
+    }
+
+    pub(crate) fn uses(&self) -> u32 {
+        self.uses
+    }
+
+    /// Check if the code can still be used on the given day
+    fn check(&self, today: chrono::NaiveDate) -> Result<(), PromoError> {
+        if self.expires_on.is_some_and(|expires_on| today > expires_on) {
+            return Err(PromoError::Expired);
+        }
+        if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) {
+            return Err(PromoError::UsedUp);
+        }
+        Ok(())
+    }
+}
+
+/// All promo codes handed out by marketing
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct PromoCodeManager {
+    #[serde(default)]
+    promo_codes: Vec<PromoCode>,
+}
+
+impl PromoCodeManager {
+    /// Add a new promo code. Fails if the code is already used or the percentage is not between
+    /// 1 and 100.
+    pub(crate) fn add(
+        &mut self,
+        code: &str,
+        percent_off: u8,
+        expires_on: Option<chrono::NaiveDate>,
+        max_uses: Option<u32>,
+    ) -> bool {
+        if !(1..=100).contains(&percent_off) || self.find(code).is_some() {
+            return false;
+        }
+        self.promo_codes.push(PromoCode {
+            code: code.to_uppercase(),
+            percent_off,
+            expires_on,
+            max_uses,
+            uses: 0,
+        });
+        true
+    }
+
+    pub(crate) fn promo_codes(&self) -> &[PromoCode] {
+        &self.promo_codes
+    }
+
+    /// Find a promo code, ignoring the case
+    fn find(&self, code: &str) -> Option<&PromoCode> {
+        self.promo_codes
+            .iter()
+            .find(|promo_code| promo_code.code.eq_ignore_ascii_case(code))
+    }
+
+    /// Apply a promo code to an open order without counting the use of the code, see `redeem`.
+    ///
+    /// Return the discount given to the order.
+    pub(crate) fn apply(&self, code: &str, order: &mut Order) -> Result<f64, PromoError> {
+        let promo_code = self.find(code).ok_or(PromoError::UnknownCode)?;
+        promo_code.check(chrono::Local::now().date_naive())?;
+        order.apply_promo(&promo_code.code, promo_code.percent_off)
+    }
+
+    /// Count the use of the promo code applied to an order once the order is paid
+    pub(crate) fn redeem(&mut self, order: &Order) {
+        let Some(promo) = order.promo() else {
+            return;
+        };
+        if let Some(promo_code) = self
+            .promo_codes
+            .iter_mut()
+            .find(|promo_code| promo_code.code.eq_ignore_ascii_case(&promo.code))
+        {
+            promo_code.uses += 1;
+        }
+    }
+}
+
+/// The registry of credit cards known to our simulated bank
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct CardRegistry {
+    #[serde(default)]
+    cards: std::collections::HashMap<String, CardStatus>,
+
+    /// Whether cards that are not in the registry are accepted as valid
+    #[serde(default)]
+    accept_unknown_cards: bool,
+}
+
+impl CardRegistry {
+    /// Set the status of a card, adding it to the registry if needed
+    pub(crate) fn set_status(&mut self, card_number: String, status: CardStatus) {
+        self.cards.insert(card_number, status);
+    }
+
+    /// The status of the card as known by the bank.
+    ///
+    /// Unknown cards are valid or invalid depending on the unknown cards policy.
+    pub(crate) fn status(&self, card_number: &str) -> CardStatus {
+        match self.cards.get(card_number) {
+            Some(status) => *status,
+            None if self.accept_unknown_cards => CardStatus::Valid,
+            None => CardStatus::Invalid,
+        }
+    }
+
+    pub(crate) fn cards(&self) -> &std::collections::HashMap<String, CardStatus> {
+        &self.cards
+    }
+
+    pub(crate) fn accept_unknown_cards(&self) -> bool {
+        self.accept_unknown_cards
+    }
+
+    pub(crate) fn set_accept_unknown_cards(&mut self, accept_unknown_cards: bool) {
+        self.accept_unknown_cards = accept_unknown_cards;
+    }
+}
+
+/// The reference a payment processor gives to an authorized payment
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AuthId(pub(crate) u64);
+
+impl std::fmt::Display for AuthId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "AUTH-{:06}", self.0)
+    }
+}
+
+/// Why a payment processor refused a payment
+pub(crate) enum PaymentError {
+    /// The amount is not a positive number
+    InvalidAmount,
+    /// The card number is not valid
+    InvalidCard(CardError),
+    /// The card is expired
+    CardExpired,
+    /// The bank refused the card
+    CardDeclined(CardStatus),
+}
+
+impl std::fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidAmount => f.write_str("the amount must be positive"),
+            Self::InvalidCard(err) => write!(f, "card number invalid: {err}"),
+            Self::CardExpired => f.write_str("this card is expired"),
+            Self::CardDeclined(CardStatus::InsufficientFunds) => {
+                f.write_str("not enough money in card")
+            }
+            Self::CardDeclined(CardStatus::Expired) => f.write_str("this card is expired"),
+            Self::CardDeclined(_) => f.write_str("this card is not valid"),
+        }
+    }
+}
+
+/// Something that decides whether money can be taken with a payment method.
+///
+/// The application only talks to the processor through this trait, so the simulated checks can be
+/// replaced by a real payment gateway.
+pub(crate) trait PaymentProcessor {
+    /// Authorize taking `amount` with the payment method.
+    ///
+    /// The card registry holds what the bank is known to say about cards.
+    fn authorize(
+        &mut self,
+        payment: &OrderPayment,
+        amount: f64,
+        card_registry: &CardRegistry,
+    ) -> Result<AuthId, PaymentError>;
+}
+
+/// The payment processor used without a real payment gateway.
+///
+/// Cards must have a valid number, not be expired and be valid in the card registry. Other
+/// payment methods only need a positive amount.
+#[derive(Default)]
+pub(crate) struct SimulatedProcessor {
+    sequence_id: u64,
+}
+
+impl PaymentProcessor for SimulatedProcessor {
+    fn authorize(
+        &mut self,
+        payment: &OrderPayment,
+        amount: f64,
+        card_registry: &CardRegistry,
+    ) -> Result<AuthId, PaymentError> {
+        if !amount.is_finite() || to_cents(amount) <= 0 {
+            return Err(PaymentError::InvalidAmount);
+        }
+        match payment {
+            OrderPayment::CreditCard {
+                card_number,
+                expiry_month,
+                expiry_year,
+            } => {
+                validate_card_number(card_number).map_err(PaymentError::InvalidCard)?;
+                let today = chrono::Local::now().date_naive();
+                if *expiry_month != 0 && is_card_expired(*expiry_month, *expiry_year, today) {
+                    return Err(PaymentError::CardExpired);
+                }
+                match card_registry.status(card_number) {
+                    CardStatus::Valid => {}
+                    status => return Err(PaymentError::CardDeclined(status)),
+                }
+            }
+            OrderPayment::Split { legs } => {
+                for leg in legs {
+                    self.authorize(leg.payment(), leg.amount(), card_registry)?;
+                }
+            }
+            OrderPayment::Cash | OrderPayment::Wallet | OrderPayment::GiftCard => {}
+        }
+        self.sequence_id += 1;
+        Ok(AuthId(self.sequence_id))
+    }
+}
+
+/// Order manager is responsible for adding and managing all orders.
+/// 
+/// This class also gives a unique ID to every order
+#[derive(Serialize, Deserialize)]
+pub(crate) struct OrderManager {
+    orders: Vec<Order>,
+    sequence_id: u64,
+
+    #[serde(default)]
+    returns: Vec<ReturnRequest>,
+    #[serde(default)]
+    return_sequence_id: u64,
+
+    /// How many hours an open order can wait to be paid before it is cancelled, 0 to never cancel
+    #[serde(default = "OrderManager::default_payment_deadline_hours")]
+    payment_deadline_hours: u32,
+
+    /// How many unpaid orders a customer can have at once, 0 for no limit, admins have none
+    #[serde(default = "OrderManager::default_max_open_orders")]
+    max_open_orders: u32,
+}
+
+impl Default for OrderManager {
+    fn default() -> Self {
+        Self {
+            orders: Default::default(),
+            sequence_id: 0,
+            returns: Default::default(),
+            return_sequence_id: 0,
+            payment_deadline_hours: Self::default_payment_deadline_hours(),
+            max_open_orders: Self::default_max_open_orders(),
+        }
+    }
+}
+
+impl OrderManager {
+    fn default_payment_deadline_hours() -> u32 {
+        48
+    }
+
+    pub(crate) fn payment_deadline_hours(&self) -> u32 {
+        self.payment_deadline_hours
+    }
+
+    pub(crate) fn set_payment_deadline_hours(&mut self, hours: u32) {
+        self.payment_deadline_hours = hours;
+    }
+
+    fn default_max_open_orders() -> u32 {
+        3
+    }
+
+    pub(crate) fn max_open_orders(&self) -> u32 {
+        self.max_open_orders
+    }
+
+    pub(crate) fn set_max_open_orders(&mut self, max_open_orders: u32) {
+        self.max_open_orders = max_open_orders;
+    }
+
+    /// The orders created between two days, both included. Orders from before creation times
+    /// were saved are never included.
+    pub(crate) fn orders_between(
+        &self,
+        from: chrono::NaiveDate,
+        to: chrono::NaiveDate,
+    ) -> Vec<&Order> {
+        self.orders
+            .iter()
+            .filter(|order| {
+                order
+                    .created_at
+                    .is_some_and(|created_at| (from..=to).contains(&created_at.date_naive()))
+            })
+            .collect()
+    }
+
+    /// How many orders of the user are still waiting to be paid
+    pub(crate) fn open_orders_of(&self, username: &str) -> usize {
+        self.orders
+            .iter()
+            .filter(|order| order.username == username && matches!(order.state, OrderState::Open))
+            .count()
+    }
+
+    /// Check if the user has as many unpaid orders as they may have, admins never do
+    pub(crate) fn has_too_many_open_orders(&self, user: &User) -> bool {
+        let max = self.max_open_orders as usize;
+        !user.is_admin() && max > 0 && self.open_orders_of(user.username()) >= max
+    }
+
+    /// Cancel the open orders placed more than `deadline` before `now`.
+    ///
+    /// Orders without a known creation time are left open. Return the IDs of the cancelled
+    /// orders.
+    pub(crate) fn expire_unpaid(
+        &mut self,
+        now: chrono::DateTime<chrono::Local>,
+        deadline: chrono::Duration,
+    ) -> Vec<u64> {
+        self.orders
+            .iter_mut()
+            .filter(|order| {
+                order
+                    .created_at
+                    .is_some_and(|created_at| now - created_at > deadline)
+            })
+            .filter_map(|order| order.cancel("payment timeout").then_some(order.order_id))
+            .collect()
+    }
+
+    /// The total of the orders the user paid in the calendar month of `now`.
+    ///
+    /// Orders paid before the payment time was recorded count in the month they were placed.
+    pub(crate) fn spent_in_month(
+        &self,
+        username: &str,
+        now: chrono::DateTime<chrono::Local>,
+    ) -> f64 {
+        use chrono::Datelike;
+
+        let total: f64 = self
+            .orders
+            .iter()
+            .filter(|order| order.username == username && order.is_paid())
+            .filter(|order| {
+                order.paid_at.or(order.created_at).is_some_and(|paid_at| {
+                    paid_at.year() == now.year() && paid_at.month() == now.month()
+                })
+            })
+            .map(Order::total_price)
+            .sum();
+        round_money(total)
+    }
+
+    /// Takes all items from the cart of the user and creates a new order
+    ///
+    /// The redeemed loyalty points are capped to what the user can redeem and taken from them.
+    pub(crate) fn checkout(&mut self, user: &mut User, details: CheckoutDetails) -> &Order {
+        let order_id = self.sequence_id;
+        self.sequence_id += 1;
+
+        let points_redeemed = details.points_redeemed.min(user.max_redeemable_points());
+        user.points -= points_redeemed;
+
+        self.orders.push(Order {
+            order_id,
+            username: user.username.clone(),
+            items: std::mem::take(&mut user.cart.0),
+            delivery_address: details.delivery_address,
+            state: OrderState::Open,
+            notes: details.notes,
+            delivery_window: details.delivery_window,
+            gift_wrap: details.gift_wrap,
+            gift_message: details.gift_message,
+            points_redeemed,
+            gift_card: None,
+            tip: 0.0,
+            promo: None,
+            created_at: Some(chrono::Local::now()),
+            paid_at: None,
+            history: Default::default(),
+            courier: None,
+        });
+
+        let order = self.orders.last_mut().unwrap();
+        order.record("placed".to_owned());
+        order
+    }
+
+    pub(crate) fn orders(&self) -> &[Order] {
+        &self.orders
+    }
+
+    pub(crate) fn orders_mut(&mut self) -> &mut [Order] {
+        &mut self.orders
+    }
+
+    /// Write all orders as CSV for accounting, one row per order with its ID, creation date,
+    /// customer, state, total and payment method.
+    ///
+    /// Totals are plain numbers with two decimals, ignoring the money format, so spreadsheets
+    /// read them as numbers. The date and payment are empty when unknown or not paid yet.
+    pub(crate) fn to_csv(&self) -> String {
+        let mut csv = String::from("id,date,username,state,total,payment\n");
+        for order in &self.orders {
+            let fields = [
+                order.order_id.to_string(),
+                order
+                    .created_at
+                    .map(|created_at| created_at.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default(),
+                order.username.clone(),
+                order.state.to_string(),
+                format!("{:.2}", order.totals().total),
+                order.payment().map(ToString::to_string).unwrap_or_default(),
+            ];
+            let row: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub(crate) fn returns(&self) -> &[ReturnRequest] {
+        &self.returns
+    }
+
+    /// The quantity of an item of a delivered order of the user that can still be returned.
+    ///
+    /// This is what was bought minus what was already asked to be returned (rejected requests
+    /// do not count).
+    pub(crate) fn returnable_quantity(
+        &self,
+        username: &str,
+        order_id: u64,
+        code: &str,
+    ) -> Result<f64, ReturnError> {
+        let order = self
+            .orders
+            .iter()
+            .find(|order| order.order_id == order_id && order.username == username)
+            .ok_or(ReturnError::OrderNotFound)?;
+        if !matches!(order.state, OrderState::Delivered { .. }) {
+            return Err(ReturnError::NotDelivered);
+        }
+        let bought = order.quantity_of(code).ok_or(ReturnError::ItemNotInOrder)?;
+
+        let already_returned: f64 = self
+            .returns
+            .iter()
+            .filter(|r| r.order_id == order_id && r.code == code)
+            .filter(|r| r.status != ReturnStatus::Rejected)
+            .map(|r| r.quantity)
+            .sum();
+        Ok(bought - already_returned)
+    }
+
+    /// Create a return request for an item of a delivered order of the user.
+    pub(crate) fn request_return(
+        &mut self,
+        username: &str,
+        order_id: u64,
+        code: String,
+        quantity: f64,
+        reason: String,
+    ) -> Result<&ReturnRequest, ReturnError> {
+        let returnable = self.returnable_quantity(username, order_id, &code)?;
+        if !quantity.is_finite() || quantity <= 0.0 || quantity > returnable {
+            return Err(ReturnError::InvalidQuantity { returnable });
+        }
+
+        let return_id = self.return_sequence_id;
+        self.return_sequence_id += 1;
+
+        self.returns.push(ReturnRequest {
+            return_id,
+            order_id,
+            username: username.to_owned(),
+            code,
+            quantity,
+            reason,
+            status: ReturnStatus::Requested,
+        });
+
+        Ok(self.returns.last().unwrap())
+    }
+
+    /// Find a return request by its ID
+    pub(crate) fn return_request(&self, return_id: u64) -> Option<&ReturnRequest> {
+        self.returns.iter().find(|r| r.return_id == return_id)
+    }
+
+    /// Find an order by its ID
+    pub(crate) fn order(&self, order_id: u64) -> Option<&Order> {
+        self.orders.iter().find(|order| order.order_id == order_id)
+    }
+
+    /// The money to give back to the customer for a return request.
+    ///
+    /// The discounts of the order, like loyalty points and promo codes, are spread over its items
+    /// and gift wrap by their price, so the returned items are refunded what was actually paid for
+    /// them. The tip is never refunded.
+    pub(crate) fn refund_amount(&self, return_id: u64) -> Option<f64> {
+        let request = self.return_request(return_id)?;
+        let order = self.order(request.order_id)?;
+        let unit_price = order.unit_price_of(&request.code)?;
+        let totals = order.totals();
+        let before_discounts = totals.items + totals.gift_wrap;
+        if before_discounts <= 0.0 {
+            return Some(0.0);
+        }
+        let paid_share = (totals.total - totals.tip) / before_discounts;
+        Some(round_money(unit_price * request.quantity * paid_share))
+    }
+
+    /// How many units of stock a refunded return puts back, called right after it was refunded.
+    ///
+    /// Checkout takes whole units, see [`Catalog::take_stock`], so a return gives back the units
+    /// taken for what the order kept before it minus those for what the order keeps after it. All
+    /// the refunded returns of an item together give back exactly what its checkout took.
+    pub(crate) fn restock_quantity(&self, return_id: u64) -> Option<i64> {
+        let request = self.return_request(return_id)?;
+        let bought = self.order(request.order_id)?.quantity_of(&request.code)?;
+        let returned_before: f64 = self
+            .returns
+            .iter()
+            .filter(|r| r.order_id == request.order_id && r.code == request.code)
+            .filter(|r| r.status == ReturnStatus::Refunded && r.return_id != return_id)
+            .map(|r| r.quantity)
+            .sum();
+        // Quantities are not exact, a kept quantity a hair above a whole unit is that unit
+        let units = |kept: f64| (kept - 1e-9).ceil().max(0.0) as i64;
+        let kept = bought - returned_before;
+        Some(units(kept) - units(kept - request.quantity))
+    }
+
+    /// The money taken by paid orders and given back for refunded returns
+    pub(crate) fn revenue(&self) -> RevenueReport {
+        let sales: f64 = self
+            .orders
+            .iter()
+            .filter(|order| order.is_paid())
+            .map(|order| order.total_price() - order.tip)
+            .sum();
+        let refunds: f64 = self
+            .returns
+            .iter()
+            .filter(|request| request.status == ReturnStatus::Refunded)
+            .filter_map(|request| self.refund_amount(request.return_id))
+            .sum();
+        RevenueReport {
+            sales: round_money(sales),
+            refunds: round_money(refunds),
+            net: round_money(sales - refunds),
+        }
+    }
+
+    /// Move a return request to a new status.
+    ///
+    /// Requested returns can be approved or rejected and approved returns can be refunded.
+    pub(crate) fn update_return(
+        &mut self,
+        return_id: u64,
+        status: ReturnStatus,
+    ) -> Result<&ReturnRequest, ReturnError> {
+        let request = self
+            .returns
+            .iter_mut()
+            .find(|r| r.return_id == return_id)
+            .ok_or(ReturnError::ReturnNotFound)?;
+
+        let allowed = matches!(
+            (request.status, status),
+            (ReturnStatus::Requested, ReturnStatus::Approved)
+                | (ReturnStatus::Requested, ReturnStatus::Rejected)
+                | (ReturnStatus::Approved, ReturnStatus::Refunded)
+        );
+        if !allowed {
+            return Err(ReturnError::InvalidTransition {
+                from: request.status,
+                to: status,
+            });
+        }
+
+        request.status = status;
+        Ok(request)
+    }
+}
+
+/// A line of a receipt with what was paid for and its amount
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ReceiptLine {
+    description: String,
+    amount: f64,
+}
+
+impl ReceiptLine {
+    pub(crate) fn description(&self) -> &str {
+        self.description.as_ref()
+    }
+
+    pub(crate) fn amount(&self) -> f64 {
+        self.amount
+    }
+}
+
+/// The proof of a payment or a refund.
+///
+/// Receipts copy everything they show so they never change after being issued, even if the order
+/// changes later. Refunds get their own receipt with a negative total.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Receipt {
+    receipt_number: u64,
+    issued_at: chrono::DateTime<chrono::Local>,
+    order_id: u64,
+    username: String,
+    lines: Vec<ReceiptLine>,
+    total: f64,
+    /// How the money was paid or where the refund goes
+    payment: String,
+    /// The receipt of the payment that this receipt refunds
+    #[serde(default)]
+    refund_of: Option<u64>,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    delivery_window: Option<DeliveryWindow>,
+    #[serde(default)]
+    gift_message: Option<String>,
+}
+
+impl Receipt {
+    pub(crate) fn receipt_number(&self) -> u64 {
+        self.receipt_number
+    }
+
+    pub(crate) fn issued_at(&self) -> chrono::DateTime<chrono::Local> {
+        self.issued_at
+    }
+
+    pub(crate) fn order_id(&self) -> u64 {
+        self.order_id
+    }
+
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn lines(&self) -> &[ReceiptLine] {
+        self.lines.as_ref()
+    }
+
+    pub(crate) fn total(&self) -> f64 {
+        self.total
+    }
+
+    pub(crate) fn payment(&self) -> &str {
+        self.payment.as_ref()
+    }
+
+    pub(crate) fn refund_of(&self) -> Option<u64> {
+        self.refund_of
+    }
+
+    pub(crate) fn notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
+    pub(crate) fn delivery_window(&self) -> Option<DeliveryWindow> {
+        self.delivery_window
+    }
+
+    pub(crate) fn gift_message(&self) -> Option<&str> {
+        self.gift_message.as_deref()
+    }
+}
+
+/// Receipt manager issues and keeps all receipts.
+///
+/// Receipts have their own sequence of numbers, separate from the order IDs.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct ReceiptManager {
+    #[serde(default)]
+    receipts: Vec<Receipt>,
+    #[serde(default)]
+    receipt_sequence_id: u64,
+}
+
+impl ReceiptManager {
+    fn next_receipt_number(&mut self) -> u64 {
+        self.receipt_sequence_id += 1;
+        self.receipt_sequence_id
+    }
+
+    /// Issue the receipt of a paid order
+    pub(crate) fn issue_for_order(&mut self, order: &Order) -> &Receipt {
+        let mut lines: Vec<ReceiptLine> = order
+            .items
+            .iter()
+            .map(|item| ReceiptLine {
+                description: format!("{}x {} [{}]", item.quantity, item.name(), item.code()),
+                amount: item.total_price(),
+            })
+            .collect();
+        if order.gift_wrap {
+            lines.push(ReceiptLine {
+                description: "gift wrap".to_owned(),
+                amount: Order::GIFT_WRAP_FEE,
+            });
+        }
+        if order.points_redeemed > 0 {
+            lines.push(ReceiptLine {
+                description: format!("{} loyalty points", order.points_redeemed),
+                amount: -order.points_discount(),
+            });
+        }
+        if let Some(promo) = &order.promo {
+            lines.push(ReceiptLine {
+                description: format!("promo code {}", promo.code),
+                amount: -promo.discount,
+            });
+        }
+        if order.tip > 0.0 {
+            lines.push(ReceiptLine {
+                description: "tip".to_owned(),
+                amount: order.tip,
+            });
+        }
+
+        let mut payment = order.payment().map(ToString::to_string).unwrap_or_default();
+        if let Some(gift_card) = &order.gift_card {
+            payment = format!(
+                "gift card {} ({}) + {payment}",
+                gift_card.code,
+                format_price(gift_card.amount)
+            );
+        }
+
+        let receipt_number = self.next_receipt_number();
+        self.receipts.push(Receipt {
+            receipt_number,
+            issued_at: chrono::Local::now(),
+            order_id: order.order_id,
+            username: order.username.clone(),
+            lines,
+            total: order.total_price(),
+            payment,
+            refund_of: None,
+            notes: order.notes.clone(),
+            delivery_window: order.delivery_window,
+            gift_message: order.gift_message.clone(),
+        });
+        self.receipts.last().unwrap()
+    }
+
+    /// Issue a negative receipt for the refund of a returned item.
+    ///
+    /// The refund references the payment receipt of the order.
+    pub(crate) fn issue_for_refund(&mut self, request: &ReturnRequest, amount: f64) -> &Receipt {
+        let original =
+            self.receipts.iter().rev().find(|receipt| {
+                receipt.order_id == request.order_id && receipt.refund_of.is_none()
+            });
+        let refund_of = original.map(|receipt| receipt.receipt_number);
+        let payment = original
+            .map(|receipt| format!("refund to {}", receipt.payment))
+            .unwrap_or_else(|| "refund".to_owned());
+
+        let receipt_number = self.next_receipt_number();
+        self.receipts.push(Receipt {
+            receipt_number,
+            issued_at: chrono::Local::now(),
+            order_id: request.order_id,
+            username: request.username.clone(),
+            lines: vec![ReceiptLine {
+                description: format!("returned {}x [{}]", request.quantity, request.code),
+                amount: -amount,
+            }],
+            total: -amount,
+            payment,
+            refund_of,
+            notes: None,
+            delivery_window: None,
+            gift_message: None,
+        });
+        self.receipts.last().unwrap()
+    }
+
+    pub(crate) fn receipts(&self) -> &[Receipt] {
+        &self.receipts
+    }
+
+    /// Find a receipt by its number
+    pub(crate) fn receipt(&self, receipt_number: u64) -> Option<&Receipt> {
+        self.receipts
+            .iter()
+            .find(|receipt| receipt.receipt_number == receipt_number)
+    }
+}
+
+/// The kind of a movement of money in the transaction log
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TransactionKind {
+    Payment,
+    Refund,
+    WalletTopUp,
+    GiftCardRedemption,
+}
+
+impl TransactionKind {
+    /// Every kind, in the order they are listed in the balance
+    pub(crate) const ALL: [TransactionKind; 4] = [
+        Self::Payment,
+        Self::Refund,
+        Self::WalletTopUp,
+        Self::GiftCardRedemption,
+    ];
+}
+
+impl std::fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Payment => f.write_str("payment"),
+            Self::Refund => f.write_str("refund"),
+            Self::WalletTopUp => f.write_str("wallet top-up"),
+            Self::GiftCardRedemption => f.write_str("gift card redemption"),
+        }
+    }
+}
+
+/// A movement of money through one payment method in the books of the shop
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Transaction {
+    transaction_id: u64,
+    at: chrono::DateTime<chrono::Local>,
+    /// Unknown for transactions recorded before it was, see `kind()`
+    #[serde(default)]
+    kind: Option<TransactionKind>,
+    /// The order the money was for, if any
+    #[serde(default)]
+    order_id: Option<u64>,
+    username: String,
+    /// How the money moved, like "cash" or "gift card ABCD-EFGH-JKLM"
+    #[serde(default, alias = "description")]
+    method: String,
+    /// Positive for money received and negative for money given back
+    amount: f64,
+}
+
+impl Transaction {
+    pub(crate) fn transaction_id(&self) -> u64 {
+        self.transaction_id
+    }
+
+    pub(crate) fn at(&self) -> chrono::DateTime<chrono::Local> {
+        self.at
+    }
+
+    /// The kind of the transaction, a payment or a refund by its sign if it was not recorded
+    pub(crate) fn kind(&self) -> TransactionKind {
+        self.kind.unwrap_or(if self.amount < 0.0 {
+            TransactionKind::Refund
+        } else {
+            TransactionKind::Payment
+        })
+    }
+
+    pub(crate) fn order_id(&self) -> Option<u64> {
+        self.order_id
+    }
+
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn method(&self) -> &str {
+        self.method.as_ref()
+    }
+
+    pub(crate) fn amount(&self) -> f64 {
+        self.amount
+    }
+}
+
+/// The log of every movement of money, one transaction per payment method, so the books balance.
+///
+/// A payment split across several methods has a transaction for each of them. Transactions are
+/// only ever appended, never edited or removed.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct TransactionLog {
+    #[serde(default)]
+    transactions: Vec<Transaction>,
+    #[serde(default)]
+    transaction_sequence_id: u64,
+}
+
+impl TransactionLog {
+    /// Append a transaction to the log
+    pub(crate) fn record(
+        &mut self,
+        kind: TransactionKind,
+        order_id: Option<u64>,
+        username: &str,
+        method: String,
+        amount: f64,
+    ) {
+        self.transaction_sequence_id += 1;
+        self.transactions.push(Transaction {
+            transaction_id: self.transaction_sequence_id,
+            at: chrono::Local::now(),
+            kind: Some(kind),
+            order_id,
+            username: username.to_owned(),
+            method,
+            amount: round_money(amount),
+        });
+    }
+
+    /// Record the payment of an order, with a transaction for the gift card and for every
+    /// payment method that paid part of it
+    pub(crate) fn record_order_payment(&mut self, order: &Order) {
+        let order_id = Some(order.order_id);
+        let mut paid = 0.0;
+        if let Some(gift_card) = &order.gift_card {
+            self.record(
+                TransactionKind::GiftCardRedemption,
+                order_id,
+                &order.username,
+                format!("gift card {}", gift_card.code),
+                gift_card.amount,
+            );
+            paid = gift_card.amount;
+        }
+        match order.payment() {
+            None | Some(OrderPayment::GiftCard) => {}
+            Some(OrderPayment::Split { legs }) => {
+                for leg in legs {
+                    self.record(
+                        TransactionKind::Payment,
+                        order_id,
+                        &order.username,
+                        leg.payment.to_string(),
+                        leg.amount,
+                    );
+                }
+            }
+            Some(payment) => self.record(
+                TransactionKind::Payment,
+                order_id,
+                &order.username,
+                payment.to_string(),
+                order.total_price() - paid,
+            ),
+        }
+    }
+
+    /// Record the money given back for a returned item
+    pub(crate) fn record_refund(
+        &mut self,
+        order_id: u64,
+        username: &str,
+        instruction: &RefundInstruction,
+    ) {
+        let method = match instruction {
+            RefundInstruction::Cash { .. } => "cash".to_owned(),
+            RefundInstruction::CreditCard { masked_card, .. } => {
+                format!("credit card {masked_card}")
+            }
+            RefundInstruction::Wallet { .. } => "wallet".to_owned(),
+            RefundInstruction::GiftCard { code, .. } => format!("gift card {code}"),
+        };
+        self.record(
+            TransactionKind::Refund,
+            Some(order_id),
+            username,
+            method,
+            -instruction.amount(),
+        );
+    }
+
+    pub(crate) fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// The last `count` transactions, oldest first
+    pub(crate) fn last(&self, count: usize) -> &[Transaction] {
+        &self.transactions[self.transactions.len().saturating_sub(count)..]
+    }
+
+    /// The money received for orders minus the money given back.
+    ///
+    /// Wallet top-ups are left out, the money is counted when the wallet pays for an order.
+    pub(crate) fn balance(&self) -> f64 {
+        let total = self
+            .transactions
+            .iter()
+            .filter(|transaction| transaction.kind() != TransactionKind::WalletTopUp)
+            .map(Transaction::amount)
+            .sum();
+        round_money(total)
+    }
+
+    /// The sum of the amounts of every kind of transaction
+    pub(crate) fn balance_by_kind(&self) -> Vec<(TransactionKind, f64)> {
+        TransactionKind::ALL
+            .iter()
+            .map(|&kind| {
+                let sum = self
+                    .transactions
+                    .iter()
+                    .filter(|transaction| transaction.kind() == kind)
+                    .map(Transaction::amount)
+                    .sum();
+                (kind, round_money(sum))
+            })
+            .collect()
+    }
+}
+
+/// The revenue of the shop over all orders
+pub(crate) struct RevenueReport {
+    /// The totals of the paid orders, without tips
+    pub(crate) sales: f64,
+    /// The money given back for refunded returns
+    pub(crate) refunds: f64,
+    /// The sales minus the refunds
+    pub(crate) net: f64,
+}
+
+/// How much one customer spent on their paid orders
+pub(crate) struct CustomerSummary {
+    username: String,
+    /// The money spent on the orders, without tips
+    total_spend: f64,
+    total_tips: f64,
+    order_count: usize,
+}
+
+impl CustomerSummary {
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn total_spend(&self) -> f64 {
+        self.total_spend
+    }
+
+    pub(crate) fn total_tips(&self) -> f64 {
+        self.total_tips
+    }
+
+    pub(crate) fn order_count(&self) -> usize {
+        self.order_count
+    }
+}
+
+/// The customers sorted by how much they spent, the biggest spender first
+pub(crate) struct CustomerReport {
+    customers: Vec<CustomerSummary>,
+}
+
+impl CustomerReport {
+    /// Sum the spend and count the orders of every customer over the paid orders.
+    ///
+    /// Tips go to the courier, so they are summed apart and do not count in the spend. Customers
+    /// who spent the same are sorted by username.
+    pub(crate) fn from_orders(orders: &[Order]) -> Self {
+        let mut customers: Vec<CustomerSummary> = Vec::new();
+        for order in orders.iter().filter(|order| order.payment().is_some()) {
+            let spend = round_money(order.total_price() - order.tip);
+            match customers
+                .iter_mut()
+                .find(|customer| customer.username == order.username)
+            {
+                Some(customer) => {
+                    customer.total_spend = round_money(customer.total_spend + spend);
+                    customer.total_tips = round_money(customer.total_tips + order.tip);
+                    customer.order_count += 1;
+                }
+                None => customers.push(CustomerSummary {
+                    username: order.username.clone(),
+                    total_spend: spend,
+                    total_tips: order.tip,
+                    order_count: 1,
+                }),
+            }
+        }
+        customers.sort_by(|a, b| {
+            to_cents(b.total_spend)
+                .cmp(&to_cents(a.total_spend))
+                .then_with(|| a.username.cmp(&b.username))
+        });
+        Self { customers }
+    }
+
+    /// Keep only the `count` biggest spenders
+    pub(crate) fn truncate(&mut self, count: usize) {
+        self.customers.truncate(count);
+    }
+
+    pub(crate) fn customers(&self) -> &[CustomerSummary] {
+        &self.customers
+    }
+}
+
+/// What kind of thing a user did, for the activity log
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ActivityKind {
+    Login,
+    Checkout,
+    Payment,
+    Cancellation,
+    /// A command run by an admin, staff member or courier
+    Command,
+}
+
+impl std::fmt::Display for ActivityKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Login => f.write_str("login"),
+            Self::Checkout => f.write_str("checkout"),
+            Self::Payment => f.write_str("payment"),
+            Self::Cancellation => f.write_str("cancellation"),
+            Self::Command => f.write_str("command"),
+        }
+    }
+}
+
+/// Something a user did in the shop
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ActivityEntry {
+    at: chrono::DateTime<chrono::Local>,
+    username: String,
+    kind: ActivityKind,
+    detail: String,
+}
+
+impl ActivityEntry {
+    pub(crate) fn at(&self) -> chrono::DateTime<chrono::Local> {
+        self.at
+    }
+
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn kind(&self) -> ActivityKind {
+        self.kind
+    }
+
+    pub(crate) fn detail(&self) -> &str {
+        self.detail.as_ref()
+    }
+}
+
+/// Who did what in the shop, for admins.
+///
+/// Unlike the audit log file, the activity log is saved with the shop and only keeps the latest
+/// entries, the oldest are dropped when it is full.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ActivityLog {
+    #[serde(default)]
+    activity: std::collections::VecDeque<ActivityEntry>,
+    /// How many entries are kept
+    #[serde(default = "ActivityLog::default_max_activity_entries")]
+    max_activity_entries: usize,
+}
+
+impl Default for ActivityLog {
+    fn default() -> Self {
+        Self {
+            activity: Default::default(),
+            max_activity_entries: Self::default_max_activity_entries(),
+        }
+    }
+}
+
+impl ActivityLog {
+    fn default_max_activity_entries() -> usize {
+        1000
+    }
+
+    /// Record that a user did something now, dropping the oldest entries beyond the maximum
+    pub(crate) fn log_activity(&mut self, username: &str, kind: ActivityKind, detail: String) {
+        self.activity.push_back(ActivityEntry {
+            at: chrono::Local::now(),
+            username: username.to_owned(),
+            kind,
+            detail,
+        });
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.activity.len() > self.max_activity_entries {
+            self.activity.pop_front();
+        }
+    }
+
+    /// Change how many entries are kept, at least one. Entries beyond it are dropped now.
+    pub(crate) fn set_max_activity_entries(&mut self, max: usize) -> bool {
+        if max == 0 {
+            return false;
+        }
+        self.max_activity_entries = max;
+        self.truncate();
+        true
+    }
+
+    /// The latest `count` entries, of one user if given, oldest first
+    pub(crate) fn latest(&self, username: Option<&str>, count: usize) -> Vec<&ActivityEntry> {
+        let mut entries: Vec<&ActivityEntry> = self
+            .activity
+            .iter()
+            .rev()
+            .filter(|entry| username.is_none_or(|username| entry.username == username))
+            .take(count)
+            .collect();
+        entries.reverse();
+        entries
+    }
+}
+
+/// Why an alias could not be defined
+pub(crate) enum AliasError {
+    /// The alias has the name of a command, which would hide that command
+    ShadowsCommand,
+    UnknownCommand,
+    /// Following the aliases of the command would lead back to the alias itself
+    Loop,
+}
+
+impl std::fmt::Display for AliasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::ShadowsCommand => f.write_str("there is already a command with that name"),
+            Self::UnknownCommand => f.write_str("there is no such command"),
+            Self::Loop => f.write_str("the alias would refer to itself"),
+        }
+    }
+}
+
+/// The shortcuts each user defined for their commands.
+///
+/// An alias replaces the first word of a command line, the other words are kept after it. Aliases
+/// can refer to other aliases.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct CommandAliases {
+    /// The aliases of each user by username, each alias maps a word to a command
+    #[serde(default)]
+    aliases: std::collections::BTreeMap<String, std::collections::BTreeMap<String, String>>,
+}
+
+impl CommandAliases {
+    /// Make `name` a shortcut for `command` for the given user, replacing an alias with the same
+    /// name.
+    ///
+    /// `commands` are the first words of all commands. The name cannot be one of them, and the
+    /// command must start with one of them once its aliases are followed.
+    pub(crate) fn define(
+        &mut self,
+        username: &str,
+        name: &str,
+        command: &str,
+        commands: &[&str],
+    ) -> Result<(), AliasError> {
+        if commands.contains(&name) {
+            return Err(AliasError::ShadowsCommand);
+        }
+        let aliases = self.aliases.entry(username.to_owned()).or_default();
+        let mut word = command.split_whitespace().next().unwrap_or_default();
+        let mut seen = vec![name];
+        while let Some(target) = aliases.get(word) {
+            if seen.contains(&word) {
+                return Err(AliasError::Loop);
+            }
+            seen.push(word);
+            word = target.split_whitespace().next().unwrap_or_default();
+        }
+        if seen.contains(&word) {
+            return Err(AliasError::Loop);
+        }
+        if !commands.contains(&word) {
+            return Err(AliasError::UnknownCommand);
+        }
+        aliases.insert(name.to_owned(), command.trim().to_owned());
+        Ok(())
+    }
+
+    /// Remove an alias of the user, return whether it existed
+    pub(crate) fn remove(&mut self, username: &str, name: &str) -> bool {
+        self.aliases
+            .get_mut(username)
+            .is_some_and(|aliases| aliases.remove(name).is_some())
+    }
+
+    /// Remove all aliases of the user
+    pub(crate) fn remove_user(&mut self, username: &str) {
+        self.aliases.remove(username);
+    }
+
+    /// The aliases of the user ordered by name
+    pub(crate) fn of(&self, username: &str) -> impl Iterator<Item = (&String, &String)> {
+        self.aliases.get(username).into_iter().flatten()
+    }
+
+    /// Replace the aliases at the start of a command line of the user by their commands
+    pub(crate) fn resolve(&self, username: &str, line: &str) -> String {
+        let mut line = line.trim().to_owned();
+        let Some(aliases) = self.aliases.get(username) else {
+            return line;
+        };
+        // Loops are refused when aliases are defined, the limit only guards against edited files
+        for _ in 0..=aliases.len() {
+            let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((&line, ""));
+            let Some(command) = aliases.get(word) else {
+                break;
+            };
+            line = format!("{command} {rest}").trim_end().to_owned();
+        }
+        line
+    }
+}
+
+/// The name of the store used by [`store_name`], set from the saved application on load
+static STORE_NAME: std::sync::RwLock<String> = std::sync::RwLock::new(String::new());
+
+/// The name of the store, shown in the banner at startup and on receipts
+pub(crate) fn store_name() -> String {
+    STORE_NAME
+        .read()
+        .map(|name| name.clone())
+        .unwrap_or_default()
+}
+
+/// Make this the name returned by [`store_name`]
+pub(crate) fn set_store_name(name: &str) {
+    if let Ok(mut current) = STORE_NAME.write() {
+        *current = name.to_owned();
+    }
+}
+
+/// A file format the state of the application can be saved in
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The format a file name stands for by its extension, if it is a known one
+    pub(crate) fn from_path(path: &str) -> Option<Format> {
+        match std::path::Path::new(path)
+            .extension()?
+            .to_str()?
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of saved contents, a JSON state is an object while a TOML document
+    /// cannot start with a brace
+    fn sniff(contents: &str) -> Format {
+        if contents.trim_start().starts_with('{') {
+            Format::Json
+        } else {
+            Format::Toml
+        }
+    }
+
+    /// The extension of files in this format
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+        }
+    }
+
+    /// Write a value in this format
+    fn write(self, value: &impl Serialize) -> Result<String, SaveError> {
+        match self {
+            Format::Toml => {
+                toml::to_string(value).map_err(|err| SaveError::Serialize(err.to_string()))
+            }
+            Format::Json => serde_json::to_string_pretty(value)
+                .map_err(|err| SaveError::Serialize(err.to_string())),
+        }
+    }
+}
+
+/// The file the state is loaded from and saved to, see [`data_file`]
+static DATA_FILE: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// The file the state is loaded from and saved to, [`CoronaApplication::PATH`] unless
+/// [`set_data_file`] was called
+pub(crate) fn data_file() -> &'static str {
+    DATA_FILE
+        .get()
+        .map_or(CoronaApplication::PATH, String::as_str)
+}
+
+/// Load and save the state in the given file instead of [`CoronaApplication::PATH`].
+///
+/// Only the first call has an effect, it must happen before the application is loaded. Returns
+/// false if the file was already set.
+pub(crate) fn set_data_file(path: String) -> bool {
+    DATA_FILE.set(path).is_ok()
+}
+
+/// The names of the files the users, the catalog and the orders are saved in
+const DOMAINS: [&str; 3] = ["users", "catalog", "orders"];
+
+/// The files the users, the catalog and the orders of the given data file are saved in, like
+/// `corona.users.toml` for `corona.toml`. They are kept next to the data file, in its format, and
+/// named after it so several data files can share a directory.
+fn domain_files(path: &str) -> Vec<String> {
+    let path = std::path::Path::new(path);
+    let dir = path.parent().unwrap_or(std::path::Path::new(""));
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = CoronaApplication::file_format(&path.to_string_lossy()).extension();
+    DOMAINS
+        .iter()
+        .map(|name| {
+            dir.join(format!("{stem}.{name}.{extension}"))
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+/// Whether any of the files the state is saved in exists, see [`CoronaApplication::load`]
+pub(crate) fn data_exists() -> bool {
+    std::iter::once(data_file().to_owned())
+        .chain(domain_files(data_file()))
+        .any(|path| std::path::Path::new(&path).exists())
+}
+
+/// A value written as a table of fields
+fn to_table(value: &impl Serialize) -> Result<toml::Table, SaveError> {
+    match toml::Value::try_from(value) {
+        Ok(toml::Value::Table(table)) => Ok(table),
+        Ok(_) => Err(SaveError::Serialize("the state is not a table".to_owned())),
+        Err(err) => Err(SaveError::Serialize(err.to_string())),
+    }
+}
+
+/// Why the state of the application could not be saved
+pub(crate) enum SaveError {
+    /// The state could not be written in the file format
+    Serialize(String),
+    /// The directory of the file could not be created
+    CreateDir(std::io::Error),
+    /// The new contents could not be written to the temporary file
+    WriteTemp(std::io::Error),
+    /// The previous file could not be copied to its backup
+    Backup(std::io::Error),
+    /// The temporary file could not replace the file
+    Replace(std::io::Error),
+    /// No state was remembered by a checkpoint yet
+    NoCheckpoint,
+    /// The file already exists and is not replaced
+    Exists,
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Serialize(err) => write!(f, "the state could not be written out: {err}"),
+            Self::CreateDir(err) => write!(f, "the directory could not be created: {err}"),
+            Self::WriteTemp(err) => write!(f, "the temporary file could not be written: {err}"),
+            Self::Backup(err) => write!(f, "the backup could not be made: {err}"),
+            Self::Replace(err) => write!(f, "the file could not be replaced: {err}"),
+            Self::NoCheckpoint => f.write_str("there is no checkpoint to save"),
+            Self::Exists => f.write_str("the file already exists"),
+        }
+    }
+}
+
+/// Why the state of the application could not be loaded
+pub(crate) enum LoadError {
+    /// There is no file yet, a new store can be started
+    NotFound,
+    /// The file exists but could not be read
+    Read(std::io::Error),
+    /// The file was read but is not a valid state, with the message of the parser
+    Parse(String),
+    /// The state in the file could not be upgraded to the current schema version
+    Migration(MigrationError),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("the file does not exist"),
+            Self::Read(err) => write!(f, "the file could not be read: {err}"),
+            Self::Parse(err) => write!(f, "the file is not a valid store: {err}"),
+            Self::Migration(err) => write!(f, "the file cannot be upgraded, {err}"),
+        }
+    }
+}
+
+/// A copy of the entire state in a single file, kept in the `backups` directory next to the data
+/// file
+pub(crate) struct Backup {
+    path: std::path::PathBuf,
+    /// When the backup was made
+    created_at: chrono::DateTime<chrono::Local>,
+    /// The size of the backup in bytes
+    size: u64,
+}
+
+impl Backup {
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub(crate) fn created_at(&self) -> chrono::DateTime<chrono::Local> {
+        self.created_at
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The directory the backups of a file are kept in
+    fn dir(path: &str) -> std::path::PathBuf {
+        std::path::Path::new(path)
+            .parent()
+            .unwrap_or(std::path::Path::new(""))
+            .join("backups")
+    }
+
+    /// The start and the end of the names of the backups of a file, like `corona-` and `.toml`
+    fn name_parts(path: &str) -> (String, String) {
+        let path = std::path::Path::new(path);
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("");
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        (
+            format!("{stem}-"),
+            extension.map_or(String::new(), |ext| format!(".{ext}")),
+        )
+    }
+
+    /// The backups of a file, newest first
+    pub(crate) fn list(path: &str) -> Vec<Backup> {
+        let (prefix, suffix) = Self::name_parts(path);
+        let Ok(entries) = std::fs::read_dir(Self::dir(path)) else {
+            return Vec::new();
+        };
+        let mut backups: Vec<Backup> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().into_string().ok()?;
+                if !name.starts_with(&prefix) || !name.ends_with(&suffix) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some(Backup {
+                    path: entry.path(),
+                    created_at: metadata.modified().ok()?.into(),
+                    size: metadata.len(),
+                })
+            })
+            .collect();
+        // The names hold the time they were made at, so they sort by age
+        backups.sort_by(|a, b| b.path.cmp(&a.path));
+        backups
+    }
+
+    /// Add a state that was just saved to the backups of a file, keeping at most `max_backups`
+    /// of them.
+    ///
+    /// Nothing is added if the newest backup has the same contents, so saving without changes
+    /// does not push older backups out.
+    fn rotate(path: &str, contents: &str, max_backups: usize) -> std::io::Result<()> {
+        if max_backups == 0 {
+            return Ok(());
+        }
+        let backups = Self::list(path);
+        if backups.first().is_some_and(|newest| {
+            std::fs::read(&newest.path).is_ok_and(|c| c == contents.as_bytes())
+        }) {
+            return Ok(());
+        }
+        let (prefix, suffix) = Self::name_parts(path);
+        let dir = Self::dir(path);
+        std::fs::create_dir_all(&dir)?;
+        let time = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f");
+        std::fs::write(dir.join(format!("{prefix}{time}{suffix}")), contents)?;
+        for old in Self::list(path).into_iter().skip(max_backups) {
+            std::fs::remove_file(old.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the contents of a file without ever leaving it half written.
+///
+/// The contents are written to a temporary file next to it and flushed to the disk, then the old
+/// file is copied to `<path>.bak` and the temporary file is renamed over it. The directories of
+/// the file are created if they do not exist yet. If anything fails the file is left as it was.
+fn write_file(path: &str, contents: &str) -> Result<(), SaveError> {
+    use std::io::Write;
+
+    let target = std::path::Path::new(path);
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent).map_err(SaveError::CreateDir)?;
+    }
+    let temp = format!("{path}.tmp");
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+    let replaced = written.map_err(SaveError::WriteTemp).and_then(|()| {
+        if target.exists() {
+            std::fs::copy(target, format!("{path}.bak")).map_err(SaveError::Backup)?;
+        }
+        std::fs::rename(&temp, target).map_err(SaveError::Replace)
+    });
+    if replaced.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    replaced
+}
+
+/// The files of the state as they were last saved or loaded, to tell if there are changes to
+/// autosave
+static LAST_SAVED: std::sync::Mutex<Option<Vec<(String, String)>>> = std::sync::Mutex::new(None);
+
+/// Remember the files of the state that are on the disk now
+fn remember_saved(files: Vec<(String, String)>) {
+    if let Ok(mut last_saved) = LAST_SAVED.lock() {
+        *last_saved = Some(files);
+    }
+}
+
+/// Write the state to a file and log how it went
+fn write_state(path: &str, state: &str) -> Result<(), SaveError> {
+    let saved = write_file(path, state);
+    match &saved {
+        Ok(()) => log::info!("saved the state to {path}"),
+        Err(err) => log::error!("could not save the state to {path}: {err}"),
+    }
+    saved
+}
+
+/// Write a value to a file in the given format.
+///
+/// The value is written out before the file is touched, so the file is left as it was if that
+/// fails.
+fn save_value(path: &str, format: Format, value: &impl Serialize) -> Result<(), SaveError> {
+    write_state(path, &format.write(value)?)
+}
+
+/// Write the files of the state, in order, stopping at the first one that fails
+fn write_files(files: &[(String, String)]) -> Result<(), SaveError> {
+    files
+        .iter()
+        .try_for_each(|(path, contents)| write_state(path, contents))
+}
+
+/// The files of the saved state of the application after the last completed command
+static CHECKPOINT: std::sync::Mutex<Option<Vec<(String, String)>>> = std::sync::Mutex::new(None);
+
+/// The main Corona Application manager class
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CoronaApplication {
+    /// The version of the layout of the saved state, always [`migrations::SCHEMA_VERSION`] once
+    /// loaded
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub user_manager: UserManager,
+    #[serde(flatten)]
+    pub catalog: Catalog,
+    #[serde(flatten)]
+    pub order_manager: OrderManager,
+    #[serde(flatten)]
+    pub card_registry: CardRegistry,
+    #[serde(flatten)]
+    pub gift_card_manager: GiftCardManager,
+    #[serde(flatten)]
+    pub promo_code_manager: PromoCodeManager,
+    #[serde(flatten)]
+    pub receipt_manager: ReceiptManager,
+    #[serde(flatten)]
+    pub transaction_log: TransactionLog,
+    #[serde(flatten)]
+    pub command_aliases: CommandAliases,
+    #[serde(flatten)]
+    pub activity_log: ActivityLog,
+    /// How amounts of money are shown
+    #[serde(default)]
+    pub money_format: MoneyFormat,
+    /// The name of the store, shown at startup and on receipts
+    #[serde(default = "CoronaApplication::default_store_name")]
+    pub store_name: String,
+    /// The currency symbol amounts of money are written with
+    #[serde(default = "CoronaApplication::default_currency_symbol")]
+    pub currency_symbol: String,
+    /// The bcrypt cost of new password hashes, the `CORONA_BCRYPT_COST` environment variable
+    /// overrides it so tests can use a cheap cost
+    #[serde(default = "CoronaApplication::default_bcrypt_cost")]
+    pub bcrypt_cost: u32,
+    /// After how many seconds without a command a logged in user is logged out, 0 to never
+    #[serde(default)]
+    pub idle_timeout_secs: u64,
+    /// Whether the state is saved after every command that changed it
+    #[serde(default = "CoronaApplication::default_autosave")]
+    pub autosave: bool,
+    /// How many backups of the data file are kept in the `backups` directory next to it, 0 to
+    /// keep none
+    #[serde(default = "CoronaApplication::default_max_backups")]
+    pub max_backups: usize,
+    /// Who authorizes payments, this is not saved
+    #[serde(skip, default = "CoronaApplication::default_payment_processor")]
+    pub payment_processor: Box<dyn PaymentProcessor>,
+    /// Who sends emails to users, this is not saved
+    #[serde(skip, default = "CoronaApplication::default_mailer")]
+    pub mailer: Box<dyn Mailer>,
+}
+
+impl Default for CoronaApplication {
+    fn default() -> Self {
+        Self {
+            schema_version: migrations::SCHEMA_VERSION,
+            user_manager: Default::default(),
+            catalog: Default::default(),
+            order_manager: Default::default(),
+            card_registry: Default::default(),
+            gift_card_manager: Default::default(),
+            promo_code_manager: Default::default(),
+            receipt_manager: Default::default(),
+            transaction_log: Default::default(),
+            money_format: Default::default(),
+            store_name: Self::default_store_name(),
+            currency_symbol: Self::default_currency_symbol(),
+            bcrypt_cost: Self::default_bcrypt_cost(),
+            idle_timeout_secs: 0,
+            autosave: Self::default_autosave(),
+            max_backups: Self::default_max_backups(),
+            command_aliases: Default::default(),
+            activity_log: Default::default(),
+            payment_processor: Self::default_payment_processor(),
+            mailer: Self::default_mailer(),
+        }
+    }
+}
+
+impl CoronaApplication {
+    /// The name of the file that stores the state when no other one is given
+    const PATH: &str = "corona.toml";
+
+    fn default_bcrypt_cost() -> u32 {
+        DEFAULT_BCRYPT_COST
+    }
+
+    fn default_store_name() -> String {
+        "Corona Store".to_owned()
+    }
+
+    fn default_autosave() -> bool {
+        true
+    }
+
+    fn default_max_backups() -> usize {
+        5
+    }
+
+    fn default_currency_symbol() -> String {
+        "EGP".to_owned()
+    }
+
+    /// The payment processor used when none is given
+    fn default_payment_processor() -> Box<dyn PaymentProcessor> {
+        Box::<SimulatedProcessor>::default()
+    }
+
+    /// The mailer used when none is given
+    fn default_mailer() -> Box<dyn Mailer> {
+        Box::<OutboxMailer>::default()
+    }
+
+    /// Save the entire state of the application, split into the [`data_file`] and the
+    /// [`domain_files`], as JSON if the extension of the data file says so and as TOML otherwise.
+    ///
+    /// The entire state is then added to the [`Backup`]s as a single file. Failing to make the
+    /// backup is logged but does not fail the save.
+    pub fn save(&self) -> Result<(), SaveError> {
+        let path = data_file();
+        let files = self.to_files(path)?;
+        write_files(&files)?;
+        remember_saved(files);
+        let backup = self
+            .to_format(Self::file_format(path))
+            .map_err(|err| err.to_string())
+            .and_then(|state| {
+                Backup::rotate(path, &state, self.max_backups).map_err(|err| err.to_string())
+            });
+        if let Err(err) = backup {
+            log::warn!("could not back up {path}: {err}");
+        }
+        Ok(())
+    }
+
+    /// Make the state saved in a backup the current state, and save it to the [`data_file`]
+    pub(crate) fn restore(&mut self, backup: &Backup) -> Result<(), LoadError> {
+        let path = backup.path().to_string_lossy();
+        *self = Self::start(Self::load_from(&path)?);
+        if let Err(err) = self.save() {
+            log::error!("could not save the restored state: {err}");
+        }
+        Ok(())
+    }
+
+    /// Save the entire state of the application to a new file in the given format.
+    ///
+    /// A file that already exists is never replaced, so this cannot overwrite the data file or
+    /// any other file.
+    pub(crate) fn save_as(&self, path: &str, format: Format) -> Result<(), SaveError> {
+        if std::path::Path::new(path).exists() {
+            return Err(SaveError::Exists);
+        }
+        save_value(path, format, self)
+    }
+
+    /// The format of a data file, JSON if its extension says so and TOML otherwise
+    fn file_format(path: &str) -> Format {
+        Format::from_path(path).unwrap_or(Format::Toml)
+    }
+
+    /// The entire state of the application written in the given format
+    fn to_format(&self, format: Format) -> Result<String, SaveError> {
+        format.write(self)
+    }
+
+    /// The state of the application split into the files it is saved in with the given data
+    /// file, with their paths.
+    ///
+    /// The users, the catalog and the orders each go to one of the [`domain_files`], with the
+    /// saved cards, and the receipts and transactions of the orders. Everything else stays in the
+    /// data file, which comes last so a save that fails halfway through never leaves it without
+    /// the parts that were not split off yet. Every file has the schema version, so each of them
+    /// can be migrated on its own.
+    fn to_files(&self, path: &str) -> Result<Vec<(String, String)>, SaveError> {
+        let format = Self::file_format(path);
+        let mut rest = to_table(self)?;
+        let domains = [
+            vec![
+                to_table(&self.user_manager)?,
+                to_table(&self.card_registry)?,
+            ],
+            vec![to_table(&self.catalog)?],
+            vec![
+                to_table(&self.order_manager)?,
+                to_table(&self.receipt_manager)?,
+                to_table(&self.transaction_log)?,
+            ],
+        ];
+        let mut files = Vec::new();
+        for (file, parts) in domain_files(path).into_iter().zip(domains) {
+            let mut table = toml::Table::new();
+            table.insert(
+                "schema_version".to_owned(),
+                toml::Value::Integer(self.schema_version.into()),
+            );
+            for (key, value) in parts.into_iter().flatten() {
+                rest.remove(&key);
+                table.insert(key, value);
+            }
+            files.push((file, format.write(&table)?));
+        }
+        files.push((path.to_owned(), format.write(&rest)?));
+        Ok(files)
+    }
+
+    /// Read the state of the application from a single file, like a [`Backup`] or a file
+    /// written by `save as`.
+    pub(crate) fn load_from(path: &str) -> Result<CoronaApplication, LoadError> {
+        Self::from_state(path, Self::read_state(path)?)
+    }
+
+    /// Read the saved state in the given file as a plain value, upgraded to the current schema
+    /// version.
+    ///
+    /// The format is taken from the extension of the file, or guessed from its contents when the
+    /// extension is not a known one. States of older schema versions are upgraded with
+    /// [`migrations::migrate`], each step is written to the audit log.
+    fn read_state(path: &str) -> Result<serde_json::Value, LoadError> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            log::info!("could not read {path}: {err}");
+            match err.kind() {
+                std::io::ErrorKind::NotFound => LoadError::NotFound,
+                _ => LoadError::Read(err),
+            }
+        })?;
+        let parsed = match Format::from_path(path).unwrap_or_else(|| Format::sniff(&contents)) {
+            Format::Toml => toml::from_str(&contents).map_err(|err| err.to_string()),
+            Format::Json => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+        };
+        // The state is migrated as a plain value first, since older layouts may not fit the
+        // current types. The TOML parser shows the failing line in a block ending with a newline.
+        let mut state: serde_json::Value = parsed.map_err(|err| {
+            let err = err.trim_end().to_owned();
+            log::warn!("could not parse {path}: {err}");
+            LoadError::Parse(err)
+        })?;
+        for (version, description) in migrations::migrate(&mut state).map_err(|err| {
+            log::warn!("could not migrate {path}: {err}");
+            LoadError::Migration(err)
+        })? {
+            log::info!("migrated {path} to schema version {version}: {description}");
+            audit(
+                "system",
+                &format!("{path} migrated to schema version {version}: {description}"),
+            );
+        }
+        Ok(state)
+    }
+
+    /// Turn a saved state read from `path` into the application
+    fn from_state(path: &str, state: serde_json::Value) -> Result<CoronaApplication, LoadError> {
+        match serde_json::from_value(state) {
+            Ok(app) => {
+                log::info!("loaded the state from {path}");
+                Ok(app)
+            }
+            Err(err) => {
+                log::warn!("could not read the state in {path}: {err}");
+                Err(LoadError::Parse(err.to_string()))
+            }
+        }
+    }
+
+    /// Remember the current state so it can be saved if the application is interrupted, and
+    /// save it to the [`data_file`] right away if autosave is on and it changed since the last
+    /// save.
+    ///
+    /// Only the files that changed are written. The menus call this before every command, so
+    /// after every command that changed something.
+    /// A Ctrl-C can come in the middle of a command, so [`CoronaApplication::save_checkpoint`]
+    /// saves this state and not a half-done one. Autosaves make no [`Backup`]s, those would
+    /// otherwise only keep the last few commands.
+    pub(crate) fn checkpoint(&self) -> Result<(), SaveError> {
+        let files = self.to_files(data_file())?;
+        let last_saved = LAST_SAVED
+            .lock()
+            .ok()
+            .and_then(|last_saved| last_saved.clone())
+            .unwrap_or_default();
+        let changed: Vec<_> = files
+            .iter()
+            .filter(|file| !last_saved.contains(file))
+            .cloned()
+            .collect();
+        if self.autosave && !changed.is_empty() {
+            write_files(&changed)?;
+            remember_saved(files.clone());
+        }
+        if let Ok(mut checkpoint) = CHECKPOINT.lock() {
+            *checkpoint = Some(files);
+        }
+        Ok(())
+    }
+
+    /// Save the state remembered by the last call to [`CoronaApplication::checkpoint`]
+    pub(crate) fn save_checkpoint() -> Result<(), SaveError> {
+        let checkpoint = CHECKPOINT.lock().map_err(|_| SaveError::NoCheckpoint)?;
+        let files = checkpoint.as_ref().ok_or(SaveError::NoCheckpoint)?;
+        let saved = files
+            .iter()
+            .try_for_each(|(path, contents)| write_file(path, contents));
+        match &saved {
+            Ok(()) => log::info!("saved the checkpoint to {}", data_file()),
+            Err(err) => log::error!("could not save the checkpoint to {}: {err}", data_file()),
+        }
+        saved
+    }
+
+    /// Load the entire state of the application, or start a new store if nothing was saved yet.
+    ///
+    /// The state is read from the [`data_file`], see [`CoronaApplication::read_files`]. Installs
+    /// from before the split keep everything in the data file, it is split into the separate
+    /// files once after loading.
+    pub fn load() -> Result<CoronaApplication, LoadError> {
+        let path = data_file();
+        let Some((app, split)) = Self::read_files(path)? else {
+            return Ok(Self::new_store());
+        };
+        let app = Self::start(app);
+        if !split {
+            let files = domain_files(path).join(", ");
+            log::info!("splitting {path} into {files}");
+            audit("system", &format!("{path} split into {files}"));
+            if let Err(err) = app.save() {
+                log::error!("could not split {path}: {err}");
+            }
+        }
+        Ok(app)
+    }
+
+    /// Read the state saved with the given data file, and whether it was split into the
+    /// [`domain_files`]. None if none of the files exist.
+    ///
+    /// The users, the catalog and the orders are read from the domain files and everything else
+    /// from the data file, whichever of them exist. A file that exists but cannot be read or
+    /// parsed is an error, starting a new store then would replace all of its data on the next
+    /// save. Older files are migrated to the current schema version after loading.
+    fn read_files(path: &str) -> Result<Option<(CoronaApplication, bool)>, LoadError> {
+        let mut state = match Self::read_state(path) {
+            Ok(state) => Some(state),
+            Err(LoadError::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+        let mut split = false;
+        for file in domain_files(path) {
+            let part = match Self::read_state(&file) {
+                Ok(part) => part,
+                Err(LoadError::NotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            split = true;
+            let state = state.get_or_insert_with(|| serde_json::Value::Object(Default::default()));
+            if let (Some(state), serde_json::Value::Object(part)) = (state.as_object_mut(), part) {
+                state.extend(part);
+            }
+        }
+        match state {
+            Some(state) => Ok(Some((Self::from_state(path, state)?, split))),
+            None => Ok(None),
+        }
+    }
+
+    /// Start a new store with nothing in it
+    pub fn new_store() -> CoronaApplication {
+        Self::start(CoronaApplication::default())
+    }
+
+    /// Get a freshly loaded or created state ready to be used
+    fn start(mut app: CoronaApplication) -> CoronaApplication {
+        for usernames in app.user_manager.colliding_usernames() {
+            audit(
+                "system",
+                &format!(
+                    "warning: the usernames {} only differ in case",
+                    usernames.join(", ")
+                ),
+            );
+        }
+        app.money_format.apply();
+        set_store_name(&app.store_name);
+        set_currency_symbol(&app.currency_symbol);
+        app.bcrypt_cost = app
+            .bcrypt_cost
+            .clamp(*BCRYPT_COSTS.start(), *BCRYPT_COSTS.end());
+        let bcrypt_cost = std::env::var("CORONA_BCRYPT_COST")
+            .ok()
+            .and_then(|cost| cost.parse().ok())
+            .unwrap_or(app.bcrypt_cost);
+        if !set_bcrypt_cost(bcrypt_cost) {
+            let clamped = bcrypt_cost.clamp(*BCRYPT_COSTS.start(), *BCRYPT_COSTS.end());
+            log::warn!("the bcrypt cost {bcrypt_cost} is out of range, using {clamped}");
+            set_bcrypt_cost(clamped);
+        }
+        app.expire_unpaid_orders();
+        // Only changes made from now on are autosaved, not a file merely written another way
+        if let Ok(files) = app.to_files(data_file()) {
+            remember_saved(files);
+        }
+        app
+    }
+
+    /// Cancel the orders that were not paid before the payment deadline.
+    ///
+    /// The stock and loyalty points taken by the cancelled orders are given back and their users
+    /// are notified.
+    pub(crate) fn expire_unpaid_orders(&mut self) {
+        let hours = self.order_manager.payment_deadline_hours();
+        if hours == 0 {
+            return;
+        }
+        let expired = self
+            .order_manager
+            .expire_unpaid(chrono::Local::now(), chrono::Duration::hours(hours.into()));
+        for order_id in expired {
+            self.give_back_cancelled_order(
+                order_id,
+                format!(
+                    "Order #{order_id} was cancelled because it was not paid within {hours} hours."
+                ),
+            );
+            audit(
+                "system",
+                &format!("order #{order_id} cancelled: payment timeout"),
+            );
+            self.activity_log.log_activity(
+                "system",
+                ActivityKind::Cancellation,
+                format!("order #{order_id}: payment timeout"),
+            );
+        }
+    }
+
+    /// Cancel an open order, give back its stock and points and notify its user.
+    ///
+    /// Return whether the order was open.
+    pub(crate) fn cancel_order(&mut self, order_id: u64, reason: &str) -> bool {
+        let Some(order) = self
+            .order_manager
+            .orders
+            .iter_mut()
+            .find(|order| order.order_id == order_id)
+        else {
+            return false;
+        };
+        if !order.cancel(reason) {
+            return false;
+        }
+        self.give_back_cancelled_order(
+            order_id,
+            format!("Order #{order_id} was cancelled ({reason})."),
+        );
+        true
+    }
+
+    /// Give back the stock and loyalty points taken by a cancelled order and notify its user
+    fn give_back_cancelled_order(&mut self, order_id: u64, message: String) {
+        let Some(order) = self.order_manager.order(order_id) else {
+            return;
+        };
+        self.catalog.release_stock(order.items());
+        if let Some(user) = self.user_manager.find_mut(order.username()) {
+            user.add_points(order.points_redeemed());
+            user.notify(message);
+        }
+    }
+
+    /// Delete a user.
+    ///
+    /// Their open orders are cancelled and the stock is given back. Their other orders, return
+    /// requests and receipts are kept for the books under the name `<username> (deleted)`, which
+    /// no new user can register, so the username is free again.
+    pub(crate) fn delete_user(&mut self, username: &str) -> Result<(), RoleError> {
+        let user = self.user_manager.remove_user(username)?;
+        let username = user.username();
+        self.command_aliases.remove_user(username);
+        let deleted = format!("{username} (deleted)");
+        for order in &mut self.order_manager.orders {
+            if order.username != username {
+                continue;
+            }
+            if order.cancel("account deleted") {
+                self.catalog.release_stock(&order.items);
+            }
+            order.username = deleted.clone();
+        }
+        for order in &mut self.order_manager.orders {
+            if order.courier.as_deref() == Some(username) {
+                order.courier = None;
+            }
+        }
+        for request in &mut self.order_manager.returns {
+            if request.username == username {
+                request.username = deleted.clone();
+            }
+        }
+        for receipt in &mut self.receipt_manager.receipts {
+            if receipt.username == username {
+                receipt.username = deleted.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `quantity` units of a product sold at `unit_price`
+    fn item(code: &str, unit_price: f64, quantity: f64) -> OrderItem {
+        OrderItem {
+            product: Product::new(code.to_owned(), code.to_owned(), unit_price),
+            quantity,
+        }
+    }
+
+    /// An order of alice placed now
+    fn order(order_id: u64, items: Vec<OrderItem>, state: OrderState) -> Order {
+        Order {
+            order_id,
+            username: "alice".to_owned(),
+            items,
+            delivery_address: "Street 1".to_owned(),
+            state,
+            notes: None,
+            delivery_window: None,
+            gift_wrap: false,
+            gift_message: None,
+            points_redeemed: 0,
+            gift_card: None,
+            tip: 0.0,
+            promo: None,
+            created_at: Some(chrono::Local::now()),
+            paid_at: None,
+            history: Vec::new(),
+            courier: None,
+        }
+    }
+
+    fn delivered(payment: OrderPayment) -> OrderState {
+        OrderState::Delivered { payment }
+    }
+
+    /// A customer with an empty cart and no password
+    fn user(username: &str) -> User {
+        serde_json::from_value(serde_json::json!({
+            "username": username,
+            "password_hash": "",
+            "email": format!("{username}@example.com"),
+            "cart": [],
+        }))
+        .unwrap()
+    }
+
+    fn order_manager(orders: Vec<Order>) -> OrderManager {
+        OrderManager {
+            sequence_id: orders.len() as u64,
+            orders,
+            ..Default::default()
+        }
+    }
+
+    /// Ask for and refund a return of alice
+    fn refund(order_manager: &mut OrderManager, order_id: u64, code: &str, quantity: f64) -> f64 {
+        let return_id = order_manager
+            .request_return("alice", order_id, code.to_owned(), quantity, String::new())
+            .ok()
+            .unwrap()
+            .return_id();
+        assert!(order_manager
+            .update_return(return_id, ReturnStatus::Approved)
+            .is_ok());
+        assert!(order_manager
+            .update_return(return_id, ReturnStatus::Refunded)
+            .is_ok());
+        order_manager.refund_amount(return_id).unwrap()
+    }
+
+    #[test]
+    fn returns_need_a_delivered_order_and_a_valid_quantity() {
+        let mut orders = order_manager(vec![
+            order(0, vec![item("A1", 10.0, 2.0)], OrderState::Open),
+            order(
+                1,
+                vec![item("A1", 10.0, 2.0)],
+                delivered(OrderPayment::Cash),
+            ),
+        ]);
+        let mut request = |order_id, code: &str, quantity| {
+            orders
+                .request_return("alice", order_id, code.to_owned(), quantity, String::new())
+                .map(|_| ())
+        };
+        assert!(matches!(
+            request(0, "A1", 1.0),
+            Err(ReturnError::NotDelivered)
+        ));
+        assert!(matches!(
+            request(1, "B2", 1.0),
+            Err(ReturnError::ItemNotInOrder)
+        ));
+        for quantity in [0.0, -1.0, 2.5, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                request(1, "A1", quantity),
+                Err(ReturnError::InvalidQuantity { .. })
+            ));
+        }
+        assert!(request(1, "A1", 1.5).is_ok());
+        assert!(matches!(
+            request(1, "A1", 1.0),
+            Err(ReturnError::InvalidQuantity { returnable }) if returnable == 0.5
+        ));
+    }
+
+    #[test]
+    fn refunds_are_reduced_by_order_discounts() {
+        let mut promo = order(
+            0,
+            vec![item("A1", 100.0, 1.0), item("B2", 50.0, 2.0)],
+            delivered(OrderPayment::Cash),
+        );
+        promo.promo = Some(AppliedPromo {
+            code: "CORONA10".to_owned(),
+            percent_off: 10,
+            discount: 20.0,
+        });
+        promo.tip = 5.0;
+        let mut points = order(
+            1,
+            vec![item("A1", 40.0, 1.0)],
+            delivered(OrderPayment::Cash),
+        );
+        points.points_redeemed = 1000;
+        let mut orders = order_manager(vec![promo, points]);
+
+        // 180 of 200 was paid for the items, the tip is not refunded
+        assert_eq!(refund(&mut orders, 0, "B2", 1.0), 45.0);
+        // 10 EGP of points were redeemed on 40 EGP
+        assert_eq!(refund(&mut orders, 1, "A1", 1.0), 30.0);
+    }
+
+    #[test]
+    fn refunded_returns_are_taken_off_the_revenue() {
+        let mut tipped = order(
+            0,
+            vec![item("A1", 10.0, 3.0)],
+            delivered(OrderPayment::Cash),
+        );
+        tipped.tip = 2.0;
+        let mut orders = order_manager(vec![
+            tipped,
+            order(1, vec![item("B2", 5.0, 1.0)], OrderState::Open),
+        ]);
+        let revenue = orders.revenue();
+        assert_eq!(
+            (revenue.sales, revenue.refunds, revenue.net),
+            (30.0, 0.0, 30.0)
+        );
+
+        let return_id = orders
+            .request_return("alice", 0, "A1".to_owned(), 1.0, String::new())
+            .ok()
+            .unwrap()
+            .return_id();
+        assert!(orders
+            .update_return(return_id, ReturnStatus::Approved)
+            .is_ok());
+        assert_eq!(orders.revenue().refunds, 0.0);
+        assert!(orders
+            .update_return(return_id, ReturnStatus::Refunded)
+            .is_ok());
+        let revenue = orders.revenue();
+        assert_eq!(
+            (revenue.sales, revenue.refunds, revenue.net),
+            (30.0, 10.0, 20.0)
+        );
+    }
+
+    #[test]
+    fn money_is_rounded_to_cents() {
+        assert_eq!(round_money(0.1 + 0.2), 0.3);
+        assert_eq!(to_cents(0.1 + 0.2), 30);
+        assert_eq!(round_money(19.99 * 3.0), 59.97);
+        assert_eq!(round_money(1.005), 1.01);
+        assert_eq!(round_money(-1.005), -1.01);
+    }
+
+    #[test]
+    fn classic_float_traps_are_paid_exactly() {
+        let mut cart = Cart::default();
+        let dime = Product::new("D".to_owned(), "Dime".to_owned(), 0.1);
+        for _ in 0..3 {
+            assert!(cart.add_item(&dime, 1.0, 0).is_ok());
+        }
+        assert_eq!(cart.total_price(), 0.3);
+        assert_eq!(cart.quote(false, 0).total, 0.3);
+
+        let mut order = order(
+            0,
+            vec![item("A1", 19.99, 3.0)],
+            OrderState::AwaitingDelivery,
+        );
+        let total = order.total_price();
+        assert_eq!(total, 59.97);
+        assert_eq!(format_money(total), "59.97");
+        assert_eq!(order.collect(59.97).ok(), Some(0.0));
+    }
+
+    #[test]
+    fn amounts_must_be_positive_numbers() {
+        assert!(is_valid_amount(0.01));
+        for amount in [0.0, -5.0, f64::NAN, f64::INFINITY] {
+            assert!(!is_valid_amount(amount));
+        }
+    }
+
+    #[test]
+    fn card_numbers_are_checked_with_luhn() {
+        for good in [
+            "4111111111111111",
+            "4111 1111 1111 1111",
+            "4111-1111-1111-1111",
+            "378282246310005",
+            "5555555555554444",
+            "4222222222222",
+        ] {
+            assert!(validate_card_number(good).is_ok(), "{good}");
+        }
+        assert!(matches!(
+            validate_card_number("4111111111111112"),
+            Err(CardError::ChecksumFailed)
+        ));
+        assert!(matches!(
+            validate_card_number("4111 1111 1111 111a"),
+            Err(CardError::NonDigits)
+        ));
+        assert!(matches!(
+            validate_card_number("411111111111"),
+            Err(CardError::InvalidLength)
+        ));
+        assert!(matches!(
+            validate_card_number("41111111111111111111"),
+            Err(CardError::InvalidLength)
+        ));
+        assert!(matches!(
+            validate_card_number(""),
+            Err(CardError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn card_numbers_are_masked_to_their_last_digits() {
+        assert_eq!(mask_card_number("4111111111111111"), "**** **** **** 1111");
+        assert_eq!(mask_card_number("378282246310005"), "**** **** **** 0005");
+        for short in ["", "1", "123", "1234"] {
+            assert_eq!(mask_card_number(short), format!("**** **** **** {short}"));
+        }
+        assert_eq!(mask_card_number("12345"), "**** **** **** 2345");
+        assert_eq!(mask_card_number("٤١١١٢"), "**** **** **** ١١١٢");
+
+        let payment = OrderPayment::CreditCard {
+            card_number: "4111111111111111".to_owned(),
+            expiry_month: 1,
+            expiry_year: 2030,
+        };
+        assert_eq!(payment.masked().unwrap(), "**** **** **** 1111");
+        assert!(!payment.to_string().contains("41111111"));
+        assert_eq!(OrderPayment::Cash.masked(), None);
+    }
+
+    #[test]
+    fn refunds_go_back_to_each_payment_method() {
+        let card = OrderPayment::CreditCard {
+            card_number: "4111111111111111".to_owned(),
+            expiry_month: 1,
+            expiry_year: 2030,
+        };
+        let refund = |payment| {
+            order(0, vec![item("A1", 10.0, 1.0)], delivered(payment)).refund_payment(10.004)
+        };
+        assert_eq!(
+            refund(OrderPayment::Cash),
+            [RefundInstruction::Cash { amount: 10.0 }]
+        );
+        assert_eq!(
+            refund(card),
+            [RefundInstruction::CreditCard {
+                masked_card: "**** **** **** 1111".to_owned(),
+                amount: 10.0
+            }]
+        );
+        assert_eq!(
+            refund(OrderPayment::Wallet),
+            [RefundInstruction::Wallet { amount: 10.0 }]
+        );
+        assert_eq!(
+            refund(OrderPayment::Split {
+                legs: vec![
+                    PaymentLeg::new(OrderPayment::Wallet, 4.0),
+                    PaymentLeg::new(OrderPayment::Cash, 6.0),
+                ],
+            }),
+            [
+                RefundInstruction::Wallet { amount: 4.0 },
+                RefundInstruction::Cash { amount: 6.0 }
+            ]
+        );
+
+        let mut gift = order(
+            0,
+            vec![item("A1", 10.0, 1.0)],
+            delivered(OrderPayment::GiftCard),
+        );
+        assert_eq!(gift.refund_payment(10.0), []);
+        gift.gift_card = Some(GiftCardPayment {
+            code: "GIFT".to_owned(),
+            amount: 10.0,
+        });
+        assert_eq!(
+            gift.refund_payment(10.0),
+            [RefundInstruction::GiftCard {
+                code: "GIFT".to_owned(),
+                amount: 10.0
+            }]
+        );
+
+        let open = order(0, vec![item("A1", 10.0, 1.0)], OrderState::Open);
+        assert_eq!(open.refund_payment(10.0), []);
+    }
+
+    #[test]
+    fn partial_refunds_are_shared_between_the_payment_methods() {
+        let split = order(
+            0,
+            vec![item("A1", 10.0, 1.0)],
+            delivered(OrderPayment::Split {
+                legs: vec![
+                    PaymentLeg::new(OrderPayment::Wallet, 4.0),
+                    PaymentLeg::new(OrderPayment::Cash, 6.0),
+                ],
+            }),
+        );
+        assert_eq!(
+            split.refund_payment(5.0),
+            [
+                RefundInstruction::Wallet { amount: 2.0 },
+                RefundInstruction::Cash { amount: 3.0 }
+            ]
+        );
+        // The cents that do not split evenly go to the last method
+        let refunds = split.refund_payment(3.33);
+        assert_eq!(
+            refunds,
+            [
+                RefundInstruction::Wallet { amount: 1.33 },
+                RefundInstruction::Cash { amount: 2.0 }
+            ]
+        );
+
+        let mut gift = order(0, vec![item("A1", 10.0, 1.0)], OrderState::Open);
+        let mut gift_card = GiftCard {
+            code: "GIFT".to_owned(),
+            initial_value: 2.5,
+            balance: 2.5,
+        };
+        assert!(gift.close_with_gift_card(OrderPayment::Cash, &mut gift_card, 2.5));
+        assert_eq!(
+            gift.refund_payment(10.0),
+            [
+                RefundInstruction::GiftCard {
+                    code: "GIFT".to_owned(),
+                    amount: 2.5
+                },
+                RefundInstruction::Cash { amount: 7.5 }
+            ]
+        );
+    }
+
+    #[test]
+    fn wallet_refunds_are_credited_and_logged() {
+        let mut alice = user("alice");
+        let instruction = RefundInstruction::Wallet { amount: 7.5 };
+        alice.credit_wallet(instruction.amount());
+        assert_eq!(alice.wallet_balance(), 7.5);
+
+        let mut log = TransactionLog::default();
+        let paid = order(
+            0,
+            vec![item("A1", 20.0, 1.0)],
+            delivered(OrderPayment::Wallet),
+        );
+        log.record_order_payment(&paid);
+        log.record_refund(0, "alice", &instruction);
+        assert_eq!(log.transactions().len(), 2);
+        assert_eq!(log.transactions()[1].kind(), TransactionKind::Refund);
+        assert_eq!(log.transactions()[1].method(), "wallet");
+        assert_eq!(log.balance(), 12.5);
+    }
+
+    #[test]
+    fn the_balance_is_the_payments_minus_the_refunds() {
+        let mut log = TransactionLog::default();
+        let mut split = order(0, vec![item("A1", 30.0, 1.0)], OrderState::Open);
+        assert!(split.close(OrderPayment::Split {
+            legs: vec![
+                PaymentLeg::new(OrderPayment::Wallet, 10.0),
+                PaymentLeg::new(OrderPayment::Cash, 20.0),
+            ],
+        }));
+        log.record_order_payment(&split);
+        let mut gift = order(1, vec![item("A1", 15.0, 1.0)], OrderState::Open);
+        let mut gift_card = GiftCard {
+            code: "ABCD-EFGH-JKLM".to_owned(),
+            initial_value: 5.0,
+            balance: 5.0,
+        };
+        assert!(gift.close_with_gift_card(OrderPayment::Cash, &mut gift_card, 5.0));
+        log.record_order_payment(&gift);
+        log.record_refund(0, "alice", &RefundInstruction::Cash { amount: 4.0 });
+        log.record(
+            TransactionKind::WalletTopUp,
+            None,
+            "alice",
+            "wallet".to_owned(),
+            50.0,
+        );
+
+        // One transaction per payment method, and the gift card on its own
+        assert_eq!(log.transactions().len(), 6);
+        assert_eq!(
+            log.balance_by_kind(),
+            vec![
+                (TransactionKind::Payment, 40.0),
+                (TransactionKind::Refund, -4.0),
+                (TransactionKind::WalletTopUp, 50.0),
+                (TransactionKind::GiftCardRedemption, 5.0),
+            ]
+        );
+        // The top-up is paid for an order later, so it does not count twice
+        assert_eq!(log.balance(), 41.0);
+        assert_eq!(log.last(2).len(), 2);
+        assert_eq!(log.last(2)[0].kind(), TransactionKind::Refund);
+    }
+
+    #[test]
+    fn closing_and_refunding_an_order_nets_to_zero() {
+        let mut log = TransactionLog::default();
+        let mut order = order(0, vec![item("A1", 12.34, 2.0)], OrderState::Open);
+        assert!(order.close(OrderPayment::Cash));
+        log.record_order_payment(&order);
+        for instruction in order.refund_payment(order.total_price()) {
+            log.record_refund(0, "alice", &instruction);
+        }
+        assert_eq!(log.balance(), 0.0);
+    }
+
+    #[test]
+    fn top_customers_are_sorted_by_spend() {
+        let mut bob = order(
+            1,
+            vec![item("A1", 50.0, 1.0)],
+            delivered(OrderPayment::Cash),
+        );
+        bob.username = "bob".to_owned();
+        bob.tip = 5.0;
+        let mut carol = order(
+            3,
+            vec![item("A1", 30.0, 1.0)],
+            delivered(OrderPayment::Wallet),
+        );
+        carol.username = "carol".to_owned();
+        let orders = [
+            order(
+                0,
+                vec![item("A1", 10.0, 2.0)],
+                delivered(OrderPayment::Cash),
+            ),
+            bob,
+            order(
+                2,
+                vec![item("A1", 10.0, 1.0)],
+                delivered(OrderPayment::Wallet),
+            ),
+            carol,
+            // Unpaid orders do not count
+            order(4, vec![item("A1", 100.0, 1.0)], OrderState::Open),
+        ];
+
+        let mut report = CustomerReport::from_orders(&orders);
+        let summary = |report: &CustomerReport| {
+            report
+                .customers()
+                .iter()
+                .map(|c| (c.username().to_owned(), c.total_spend(), c.order_count()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary(&report),
+            [
+                ("bob".to_owned(), 50.0, 1),
+                ("alice".to_owned(), 30.0, 2),
+                ("carol".to_owned(), 30.0, 1),
+            ]
+        );
+        assert_eq!(report.customers()[0].total_tips(), 5.0);
+        report.truncate(1);
+        assert_eq!(summary(&report), [("bob".to_owned(), 50.0, 1)]);
+    }
+
+    #[test]
+    fn cash_on_delivery_is_collected_once() {
+        let mut order = order(0, vec![item("A1", 10.0, 3.0)], OrderState::Open);
+        assert!(matches!(
+            order.collect(30.0),
+            Err(CollectError::NotPayOnDelivery)
+        ));
+        assert!(order.await_delivery());
+        assert!(!order.await_delivery());
+        assert!(!order.is_paid());
+        assert!(order.payment().is_none());
+        // An order to be paid on delivery can not be paid again at the pay prompt
+        assert!(!order.close(OrderPayment::Cash));
+        assert!(!order.cancel("changed my mind"));
+
+        assert!(matches!(
+            order.collect(25.0),
+            Err(CollectError::NotEnough { missing }) if missing == 5.0
+        ));
+        assert_eq!(order.collect(50.0).ok(), Some(20.0));
+        assert!(order.is_paid());
+        assert!(matches!(
+            order.state(),
+            OrderState::Delivered {
+                payment: OrderPayment::Cash
+            }
+        ));
+        assert!(matches!(
+            order.collect(30.0),
+            Err(CollectError::NotPayOnDelivery)
+        ));
+    }
+
+    #[test]
+    fn card_orders_can_not_be_collected() {
+        let mut order = order(0, vec![item("A1", 10.0, 1.0)], OrderState::Open);
+        assert!(order.close(OrderPayment::CreditCard {
+            card_number: "4111111111111111".to_owned(),
+            expiry_month: 1,
+            expiry_year: 2030,
+        }));
+        assert!(!order.await_delivery());
+        assert!(matches!(
+            order.collect(10.0),
+            Err(CollectError::NotPayOnDelivery)
+        ));
+        assert!(order.deliver());
+        assert!(matches!(
+            order.collect(10.0),
+            Err(CollectError::NotPayOnDelivery)
+        ));
+    }
+
+    #[test]
+    fn money_is_grouped_and_rounded_to_the_decimal_places() {
+        let mut format = MoneyFormat::default();
+        assert_eq!(format.format(1234567.5), "1,234,567.50");
+        assert_eq!(format.format(999.995), "1,000.00");
+        assert_eq!(format.format(999.994), "999.99");
+        assert_eq!(format.format(-1234.5), "-1,234.50");
+        assert_eq!(format.format(0.0), "0.00");
+
+        assert!(format.set_decimal_places(0));
+        assert_eq!(format.format(1234.5), "1,235");
+        assert_eq!(format.format(1234.49), "1,234");
+        assert!(format.set_decimal_places(MoneyFormat::MAX_DECIMAL_PLACES));
+        assert_eq!(format.format(0.12345), "0.1235");
+        assert!(!format.set_decimal_places(MoneyFormat::MAX_DECIMAL_PLACES + 1));
+
+        assert!(format.set_decimal_places(2));
+        assert!(format.set_thousands_separator(Some(' ')));
+        assert_eq!(format.format(1234567.5), "1 234 567.50");
+        assert!(format.set_thousands_separator(None));
+        assert_eq!(format.format(1234567.5), "1234567.50");
+        assert!(!format.set_thousands_separator(Some('.')));
+        assert!(!format.set_thousands_separator(Some('5')));
+    }
+
+    #[test]
+    fn unpaid_orders_expire_after_the_deadline() {
+        let now = chrono::Local::now();
+        let deadline = chrono::Duration::hours(48);
+        let placed = |order_id, hours: Option<i64>, state| {
+            let mut order = order(order_id, vec![item("A1", 10.0, 1.0)], state);
+            order.created_at = hours.map(|hours| now - chrono::Duration::hours(hours));
+            order
+        };
+        let mut orders = order_manager(vec![
+            placed(0, Some(49), OrderState::Open),
+            // Exactly at the deadline the order still has time to be paid
+            placed(1, Some(48), OrderState::Open),
+            placed(2, Some(1), OrderState::Open),
+            placed(3, None, OrderState::Open),
+            placed(4, Some(72), OrderState::AwaitingDelivery),
+            placed(5, Some(72), delivered(OrderPayment::Cash)),
+        ]);
+
+        assert_eq!(orders.expire_unpaid(now, deadline), vec![0]);
+        assert!(matches!(
+            orders.orders()[0].state(),
+            OrderState::Cancelled { reason } if reason == "payment timeout"
+        ));
+        assert!(orders.orders()[1..4].iter().all(Order::is_open));
+
+        // Cancelled orders are not expired again
+        let later = now + chrono::Duration::hours(1);
+        assert_eq!(orders.expire_unpaid(later, deadline), vec![1]);
+    }
+
+    #[test]
+    fn passwords_must_follow_the_policy() {
+        let check = |password| validate_password("alice99", password);
+        assert!(check("abcdef12").is_ok());
+        assert!(matches!(check("abcdef1"), Err(PasswordError::TooShort)));
+        // The length is counted in characters, not bytes
+        assert!(check("äöüßéè12").is_ok());
+        assert!(matches!(check("äöüßé12"), Err(PasswordError::TooShort)));
+        assert!(matches!(check("abcdefgh"), Err(PasswordError::NoDigit)));
+        assert!(matches!(check("12345679"), Err(PasswordError::NoLetter)));
+        assert!(matches!(
+            validate_password("alice1234", "ALICE1234"),
+            Err(PasswordError::SameAsUsername)
+        ));
+        assert!(matches!(check("Password1"), Err(PasswordError::TooCommon)));
+        assert!(matches!(check("12345678"), Err(PasswordError::TooCommon)));
+
+        let mut user_manager = UserManager::default();
+        let registered = user_manager.add_user(
+            "alice99".to_owned(),
+            "abc1".to_owned(),
+            "alice@example.com".to_owned(),
+            "01234567890".to_owned(),
+        );
+        assert!(matches!(
+            registered,
+            Err(RegisterError::WeakPassword(PasswordError::TooShort))
+        ));
+        assert!(user_manager.users().is_empty());
+    }
+
+    #[test]
+    fn usernames_stay_taken_after_a_restart() {
+        let register = |user_manager: &mut UserManager, username: &str, email: &str| {
+            user_manager.add_user(
+                username.to_owned(),
+                "abcdef12".to_owned(),
+                email.to_owned(),
+                "01234567890".to_owned(),
+            )
+        };
+        let mut user_manager = UserManager::default();
+        assert!(register(&mut user_manager, "alice", "alice@example.com").is_ok());
+
+        let saved = serde_json::to_string(&user_manager).unwrap();
+        let mut user_manager: UserManager = serde_json::from_str(&saved).unwrap();
+        assert!(matches!(
+            register(&mut user_manager, "Alice", "other@example.com"),
+            Err(RegisterError::UsernameTaken)
+        ));
+        assert!(matches!(
+            register(&mut user_manager, "bob", "ALICE@example.com"),
+            Err(RegisterError::EmailTaken)
+        ));
+        assert_eq!(user_manager.users().len(), 1);
+    }
+
+    #[test]
+    fn logins_with_a_corrupted_hash_fail_without_panicking() {
+        let mut alice = user("alice");
+        alice.password_hash = format!("$2b$04${}", "!".repeat(53));
+        let mut user_manager = UserManager {
+            users: vec![alice],
+            ..Default::default()
+        };
+        let login = user_manager.user_login_mut("alice".to_owned(), "abcdef12".to_owned());
+        assert!(matches!(login, Err(LoginError::Unauthorized)));
+        assert_eq!(user_manager.users()[0].failed_logins, 1);
+    }
+
+    #[test]
+    fn usernames_ignore_case_and_surrounding_whitespace() {
+        let mut user_manager = UserManager::default();
+        let registered = user_manager.add_user(
+            " Alice ".to_owned(),
+            "abcdef12".to_owned(),
+            "alice@example.com".to_owned(),
+            "01234567890".to_owned(),
+        );
+        assert!(registered.is_ok());
+        // The casing typed at registration is kept for display
+        assert_eq!(user_manager.users()[0].username(), "Alice");
+
+        for username in ["alice", "ALICE", "  aLiCe"] {
+            let login = user_manager.user_login_mut(username.to_owned(), "abcdef12".to_owned());
+            assert_eq!(
+                login.ok().map(|user| user.username().to_owned()).as_deref(),
+                Some("Alice")
+            );
+        }
+        let login = user_manager.user_login_mut("alice".to_owned(), "ABCDEF12".to_owned());
+        assert!(matches!(login, Err(LoginError::Unauthorized)));
+    }
+
+    #[test]
+    fn usernames_that_only_differ_in_case_are_reported() {
+        let mut user_manager = UserManager {
+            users: vec![user("Bob"), user("alice"), user("bob "), user("carol")],
+            ..Default::default()
+        };
+        assert_eq!(
+            user_manager.colliding_usernames(),
+            vec![vec!["Bob", "bob "]]
+        );
+        // An exact match still finds each of the accounts
+        let found = |user_manager: &mut UserManager, username: &str| {
+            user_manager.find_mut(username).map(|u| u.username.clone())
+        };
+        assert_eq!(found(&mut user_manager, "bob ").as_deref(), Some("bob "));
+        assert_eq!(found(&mut user_manager, "Bob").as_deref(), Some("Bob"));
+        assert_eq!(found(&mut user_manager, " ALICE").as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn each_rounding_mode_rounds_the_boundary_its_own_way() {
+        let cents = |rounding: RoundingMode, amount: f64| rounding.round(amount * 100.0);
+        assert_eq!(cents(RoundingMode::HalfUp, 2.345), 235);
+        assert_eq!(cents(RoundingMode::HalfEven, 2.345), 234);
+        assert_eq!(cents(RoundingMode::Truncate, 2.345), 234);
+
+        assert_eq!(cents(RoundingMode::HalfUp, 2.355), 236);
+        assert_eq!(cents(RoundingMode::HalfEven, 2.355), 236);
+        assert_eq!(cents(RoundingMode::Truncate, 2.349), 234);
+
+        // Negative amounts are rounded like positive ones, away from or towards zero
+        assert_eq!(cents(RoundingMode::HalfUp, -2.345), -235);
+        assert_eq!(cents(RoundingMode::HalfEven, -2.345), -234);
+        assert_eq!(cents(RoundingMode::Truncate, -2.349), -234);
+
+        // Whole cents are left alone whatever the mode
+        for rounding in RoundingMode::ALL {
+            assert_eq!(cents(rounding, 2.34), 234);
+            assert_eq!(cents(rounding, 0.0), 0);
+        }
+
+        let format = |rounding| MoneyFormat {
+            rounding,
+            ..MoneyFormat::default()
+        };
+        assert_eq!(format(RoundingMode::HalfUp).format(2.345), "2.35");
+        assert_eq!(format(RoundingMode::HalfEven).format(2.345), "2.34");
+        assert_eq!(format(RoundingMode::Truncate).format(2.349), "2.34");
+    }
+
+    #[test]
+    fn weak_hashes_are_upgraded_on_the_next_login_only() {
+        let cost = |user_manager: &UserManager| {
+            let hash = &user_manager.users()[0].password_hash;
+            hash.parse::<bcrypt::HashParts>().unwrap().get_cost()
+        };
+        let mut alice = user("alice");
+        alice.password_hash = bcrypt::hash("abcdef12", 4).unwrap();
+        let mut user_manager = UserManager {
+            users: vec![alice],
+            ..Default::default()
+        };
+
+        // A wrong password does not upgrade the hash
+        let login = user_manager.user_login_mut("alice".to_owned(), "abcdef13".to_owned());
+        assert!(login.is_err());
+        assert_eq!(cost(&user_manager), 4);
+
+        assert!(user_manager
+            .user_login_mut("alice".to_owned(), "abcdef12".to_owned())
+            .is_ok());
+        assert_eq!(cost(&user_manager), bcrypt_cost());
+        let upgraded = user_manager.users()[0].password_hash.clone();
+
+        assert!(user_manager
+            .user_login_mut("alice".to_owned(), "abcdef12".to_owned())
+            .is_ok());
+        assert_eq!(user_manager.users()[0].password_hash, upgraded);
+    }
+
+    #[test]
+    fn orders_are_exported_as_quoted_csv() {
+        assert_eq!(csv_field("cash"), "cash");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+
+        let mut open = order(0, vec![item("A1", 10.0, 1.0)], OrderState::Open);
+        open.created_at = None;
+        let mut paid = order(
+            1,
+            vec![item("A1", 12.5, 1.0)],
+            delivered(OrderPayment::Cash),
+        );
+        paid.created_at =
+            chrono::TimeZone::with_ymd_and_hms(&chrono::Local, 2024, 3, 1, 9, 5, 0).single();
+        let cancelled = OrderState::Cancelled {
+            reason: "out of \"A1\", sorry".to_owned(),
+        };
+        let mut cancelled = order(2, vec![item("A1", 10.0, 1.0)], cancelled);
+        cancelled.created_at = paid.created_at;
+        let csv = order_manager(vec![open, paid, cancelled]).to_csv();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "id,date,username,state,total,payment",
+                "0,,alice,open,10.00,",
+                "1,2024-03-01 09:05,alice,delivered,12.50,cash",
+                "2,2024-03-01 09:05,alice,\"cancelled (out of \"\"A1\"\", sorry)\",10.00,",
+            ]
+        );
+    }
+
+    #[test]
+    fn usernames_must_follow_the_format() {
+        assert!(validate_username("bob").is_ok());
+        assert!(validate_username("alice.smith_2").is_ok());
+        assert!(matches!(
+            validate_username(""),
+            Err(UsernameError::TooShort)
+        ));
+        assert!(matches!(
+            validate_username("bo"),
+            Err(UsernameError::TooShort)
+        ));
+        assert!(validate_username(&"a".repeat(User::MAX_USERNAME_LENGTH)).is_ok());
+        assert!(matches!(
+            validate_username(&"a".repeat(User::MAX_USERNAME_LENGTH + 1)),
+            Err(UsernameError::TooLong)
+        ));
+        assert!(matches!(
+            validate_username("2bob"),
+            Err(UsernameError::NoLeadingLetter)
+        ));
+        assert!(matches!(
+            validate_username(">>>"),
+            Err(UsernameError::NoLeadingLetter)
+        ));
+        assert!(matches!(
+            validate_username("bob smith"),
+            Err(UsernameError::InvalidCharacter(' '))
+        ));
+        assert!(matches!(
+            validate_username("bobé"),
+            Err(UsernameError::InvalidCharacter('é'))
+        ));
+        assert!(matches!(
+            validate_username("System"),
+            Err(UsernameError::Reserved)
+        ));
+        assert!(validate_username("systems").is_ok());
+    }
+
+    #[test]
+    fn products_are_filtered_by_inclusive_price_bounds() {
+        let product =
+            |code: &str, unit_price| Product::new(code.to_owned(), code.to_owned(), unit_price);
+        let mut discontinued = product("D1", 7.0);
+        discontinued.active = false;
+        let catalog = Catalog {
+            products: vec![
+                product("A1", 5.0),
+                product("B1", 10.0),
+                product("C1", 12.5),
+                discontinued,
+            ],
+            ..Default::default()
+        };
+        let codes = |min, max| -> Vec<String> {
+            catalog
+                .products_by_price(min, max)
+                .into_iter()
+                .map(|product| product.code.clone())
+                .collect()
+        };
+        assert_eq!(codes(None, Some(10.0)), ["A1", "B1"]);
+        assert_eq!(codes(Some(10.0), None), ["B1", "C1"]);
+        assert_eq!(codes(Some(6.0), Some(12.0)), ["B1"]);
+        assert_eq!(codes(None, None), ["A1", "B1", "C1"]);
+        assert!(codes(Some(12.0), Some(6.0)).is_empty());
+    }
+
+    #[test]
+    fn the_activity_log_keeps_the_latest_entries() {
+        let mut activity_log = ActivityLog::default();
+        assert!(activity_log.set_max_activity_entries(3));
+        assert!(!activity_log.set_max_activity_entries(0));
+        for (i, username) in ["alice", "bob", "alice", "bob", "alice"].iter().enumerate() {
+            activity_log.log_activity(username, ActivityKind::Login, i.to_string());
+        }
+        let details = |entries: Vec<&ActivityEntry>| -> Vec<String> {
+            entries
+                .iter()
+                .map(|entry| entry.detail().to_owned())
+                .collect()
+        };
+        // The two oldest entries were dropped
+        assert_eq!(details(activity_log.latest(None, 10)), ["2", "3", "4"]);
+        assert_eq!(details(activity_log.latest(None, 2)), ["3", "4"]);
+        assert_eq!(details(activity_log.latest(Some("alice"), 10)), ["2", "4"]);
+        assert_eq!(details(activity_log.latest(Some("alice"), 1)), ["4"]);
+        assert!(activity_log.latest(Some("carol"), 10).is_empty());
+
+        // Lowering the maximum drops the oldest entries right away
+        assert!(activity_log.set_max_activity_entries(1));
+        assert_eq!(details(activity_log.latest(None, 10)), ["4"]);
+    }
+
+    #[test]
+    fn plain_text_passwords_are_hashed_on_their_first_login() {
+        let mut legacy = user("alice");
+        legacy.password_hash = "abcdef12".to_owned();
+        let mut hashed = user("bob");
+        hashed.password_hash = bcrypt::hash("abcdef34", bcrypt_cost()).unwrap();
+        let mut corrupted = user("carol");
+        corrupted.password_hash = "$2b$10$cut short".to_owned();
+        let mut user_manager = UserManager {
+            users: vec![legacy, hashed, corrupted],
+            ..Default::default()
+        };
+        let mut login = |username: &str, password: &str| {
+            user_manager
+                .user_login_mut(username.to_owned(), password.to_owned())
+                .map(|user| user.password_hash.clone())
+                .ok()
+        };
+
+        let upgraded = login("alice", "abcdef12").unwrap();
+        assert!(upgraded.starts_with("$2"));
+        assert!(bcrypt::verify("abcdef12", &upgraded).unwrap());
+        assert_eq!(login("alice", "abcdef12"), Some(upgraded.clone()));
+        // The hash itself is not a password
+        assert_eq!(login("alice", &upgraded), None);
+
+        let bob = login("bob", "abcdef34").unwrap();
+        assert_eq!(login("bob", "abcdef34"), Some(bob));
+
+        // A corrupted hash is not compared as a plain password
+        assert_eq!(login("carol", "$2b$10$cut short"), None);
+    }
+
+    /// A new empty directory for one test to write files in
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("corona-{name}-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn json_and_toml_saves_load_the_same_state() {
+        let card = OrderPayment::CreditCard {
+            card_number: "4111111111111111".to_owned(),
+            expiry_month: 1,
+            expiry_year: 2030,
+        };
+        let split = OrderPayment::Split {
+            legs: vec![
+                PaymentLeg::new(OrderPayment::Wallet, 4.0),
+                PaymentLeg::new(OrderPayment::Cash, 6.0),
+            ],
+        };
+        let cancelled = OrderState::Cancelled {
+            reason: "payment timeout".to_owned(),
+        };
+        let mut app = CoronaApplication {
+            order_manager: order_manager(vec![
+                order(0, vec![item("A1", 10.0, 1.0)], OrderState::Open),
+                order(
+                    1,
+                    vec![item("A1", 10.0, 1.0)],
+                    OrderState::Closed { payment: card },
+                ),
+                order(2, vec![item("A1", 10.0, 1.0)], delivered(split)),
+                order(3, vec![item("A1", 10.0, 1.0)], cancelled),
+                order(4, vec![item("A1", 10.0, 1.0)], OrderState::AwaitingDelivery),
+            ]),
+            ..Default::default()
+        };
+        app.user_manager.users.push(user("alice"));
+        app.catalog
+            .products
+            .push(Product::new("A1".to_owned(), "Apple".to_owned(), 10.0));
+
+        let dir = test_dir("formats");
+        let state = |format: Format| {
+            let path = dir.join(format!("corona.{}", format.extension()));
+            let path = path.to_string_lossy();
+            assert!(app.save_as(&path, format).is_ok());
+            let loaded =
+                CoronaApplication::load_from(&path).unwrap_or_else(|error| panic!("{error}"));
+            serde_json::to_value(&loaded).unwrap()
+        };
+        let toml = state(Format::Toml);
+        let json = state(Format::Json);
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(toml, json);
+        assert_eq!(json, serde_json::to_value(&app).unwrap());
+        let states: Vec<_> = json["orders"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|order| order["state"]["order_state"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            states,
+            [
+                "open",
+                "closed",
+                "delivered",
+                "cancelled",
+                "awaiting_delivery"
+            ]
+        );
+    }
+
+    /// A value that can never be written out
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("this value can not be saved"))
+        }
+    }
+
+    #[test]
+    fn failed_saves_leave_the_file_as_it_was() {
+        let dir = test_dir("atomic-save");
+        let path = dir.join("corona.toml").to_string_lossy().into_owned();
+        let read = |path: &str| std::fs::read_to_string(path).ok();
+
+        assert!(write_file(&path, "first").is_ok());
+        assert_eq!(read(&format!("{path}.bak")), None);
+        assert!(write_file(&path, "second").is_ok());
+        assert_eq!(read(&path).as_deref(), Some("second"));
+        // The previous contents are kept next to the file
+        assert_eq!(read(&format!("{path}.bak")).as_deref(), Some("first"));
+
+        for format in [Format::Toml, Format::Json] {
+            let saved = save_value(&path, format, &Unserializable);
+            assert!(matches!(saved, Err(SaveError::Serialize(_))));
+        }
+        // A temporary file that can not be written fails the save before the backup is made
+        std::fs::create_dir(format!("{path}.tmp")).unwrap();
+        assert!(matches!(
+            write_file(&path, "third"),
+            Err(SaveError::WriteTemp(_))
+        ));
+
+        assert_eq!(read(&path).as_deref(), Some("second"));
+        assert_eq!(read(&format!("{path}.bak")).as_deref(), Some("first"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn missing_and_corrupted_files_are_told_apart() {
+        let dir = test_dir("load-errors");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("corona.toml"), "users = [\n").unwrap();
+        std::fs::write(path("corona.json"), "{\"users\": ").unwrap();
+
+        let missing = CoronaApplication::load_from(&path("missing.toml"));
+        assert!(matches!(missing, Err(LoadError::NotFound)));
+        let toml = CoronaApplication::load_from(&path("corona.toml"));
+        assert!(matches!(toml, Err(LoadError::Parse(err)) if err.contains("line 1")));
+        let json = CoronaApplication::load_from(&path("corona.json"));
+        assert!(matches!(json, Err(LoadError::Parse(_))));
+        assert_eq!(
+            std::fs::read_to_string(path("corona.toml")).unwrap(),
+            "users = [\n"
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn the_currency_symbol_goes_on_the_configured_side() {
+        let format = |symbol_position| MoneyFormat {
+            symbol_position,
+            ..MoneyFormat::default()
+        };
+        let after = format(SymbolPosition::After);
+        assert_eq!(after.format_price(1234.5, "EGP"), "1,234.50 EGP");
+        assert_eq!(after.format_price(-5.0, "$"), "-5.00 $");
+
+        let before = format(SymbolPosition::Before);
+        assert_eq!(before.format_price(5.0, "$"), "$5.00");
+        assert_eq!(before.format_price(-5.0, "$"), "-$5.00");
+        assert_eq!(before.format_price(1234.5, "EGP"), "EGP 1,234.50");
+
+        // Without a symbol only the amount is written
+        assert_eq!(after.format_price(5.0, ""), "5.00");
+        assert_eq!(before.format_price(5.0, ""), "5.00");
+    }
+
+    #[test]
+    fn orders_are_listed_by_inclusive_date_range() {
+        let at = |day: u32, hour: u32, minute: u32| {
+            chrono::TimeZone::with_ymd_and_hms(&chrono::Local, 2024, 3, day, hour, minute, 0)
+                .single()
+        };
+        let placed = |order_id, created_at| {
+            let mut order = order(order_id, vec![item("A1", 10.0, 1.0)], OrderState::Open);
+            order.created_at = created_at;
+            order
+        };
+        let orders = order_manager(vec![
+            placed(0, at(1, 23, 59)),
+            placed(1, at(2, 0, 0)),
+            placed(2, at(3, 12, 0)),
+            placed(3, at(4, 23, 59)),
+            placed(4, at(5, 0, 0)),
+            placed(5, None),
+        ]);
+        let day = |day| chrono::NaiveDate::from_ymd_opt(2024, 3, day).unwrap();
+        let ids = |from, to| -> Vec<u64> {
+            orders
+                .orders_between(day(from), day(to))
+                .iter()
+                .map(|order| order.order_id())
+                .collect()
+        };
+        // Both days are included from midnight to the last minute
+        assert_eq!(ids(2, 4), [1, 2, 3]);
+        assert_eq!(ids(3, 3), [2]);
+        assert_eq!(ids(1, 1), [0]);
+        assert!(ids(6, 30).is_empty());
+        assert!(ids(4, 2).is_empty());
+    }
+
+    /// Load a saved state from the fixtures of older versions of the application
+    fn load_fixture(name: &str) -> CoronaApplication {
+        let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+        CoronaApplication::load_from(&path).unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    #[test]
+    fn v1_fixture_is_migrated_to_the_current_version() {
+        let app = load_fixture("corona-v1.toml");
+        assert_eq!(app.schema_version, migrations::SCHEMA_VERSION);
+        let roles: Vec<_> = app
+            .user_manager
+            .users()
+            .iter()
+            .map(|user| (user.username(), user.role()))
+            .collect();
+        assert!(roles == [("admin", Role::Admin), ("alice", Role::Customer)]);
+        assert!(app.user_manager.users()[1].verify_password("alice123"));
+
+        let orders = app.order_manager.orders();
+        assert_eq!(orders.len(), 2);
+        assert!(matches!(
+            orders[0].state(),
+            OrderState::Closed {
+                payment: OrderPayment::Cash
+            }
+        ));
+        assert_eq!(orders[0].total_price(), 20.0);
+        assert!(orders[1].is_open());
+        assert_eq!(app.catalog.products()[0].code, "A1");
+    }
+
+    #[test]
+    fn v2_fixture_is_migrated_to_the_current_version() {
+        let app = load_fixture("corona-v2.toml");
+        assert_eq!(app.schema_version, migrations::SCHEMA_VERSION);
+        let roles: Vec<_> = app
+            .user_manager
+            .users()
+            .iter()
+            .map(|user| (user.username(), user.role()))
+            .collect();
+        assert!(roles == [("boss", Role::Admin), ("alice", Role::Customer)]);
+        assert!(app.user_manager.users()[0].verify_password("secret12"));
+    }
+
+    #[test]
+    fn data_files_in_one_directory_keep_their_own_split_files() {
+        let shop = |username: &str, code: &str| {
+            let mut app = CoronaApplication {
+                order_manager: order_manager(vec![order(
+                    0,
+                    vec![item(code, 10.0, 1.0)],
+                    OrderState::Open,
+                )]),
+                ..Default::default()
+            };
+            app.user_manager.users.push(user(username));
+            app.catalog
+                .products
+                .push(Product::new(code.to_owned(), "Apple".to_owned(), 10.0));
+            app
+        };
+        let dir = test_dir("split-files");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let shops = [
+            (path("first.toml"), shop("alice", "A1")),
+            (path("second.toml"), shop("bob", "B2")),
+        ];
+        for (path, app) in &shops {
+            assert!(write_files(&app.to_files(path).ok().unwrap()).is_ok());
+        }
+        assert!(dir.join("first.users.toml").exists());
+        assert!(dir.join("second.orders.toml").exists());
+        for (path, app) in &shops {
+            let (loaded, split) = CoronaApplication::read_files(path)
+                .unwrap_or_else(|error| panic!("{error}"))
+                .unwrap();
+            assert!(split);
+            assert_eq!(
+                serde_json::to_value(&loaded).unwrap(),
+                serde_json::to_value(app).unwrap()
+            );
+        }
+
+        // A data file from before the split has everything in it
+        let legacy = path("legacy.toml");
+        let (_, app) = &shops[0];
+        assert!(app.save_as(&legacy, Format::Toml).is_ok());
+        let (loaded, split) = CoronaApplication::read_files(&legacy)
+            .unwrap_or_else(|error| panic!("{error}"))
+            .unwrap();
+        assert!(!split);
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(app).unwrap()
+        );
+        assert!(CoronaApplication::read_files(&path("missing.toml"))
+            .unwrap_or_else(|error| panic!("{error}"))
+            .is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn save_as_never_replaces_a_file() {
+        let dir = test_dir("save-as");
+        let path = dir.join("corona.toml").to_string_lossy().into_owned();
+        std::fs::write(&path, "kept").unwrap();
+        let app = CoronaApplication::default();
+        let saved = app.save_as(&path, Format::Toml);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(matches!(saved, Err(SaveError::Exists)));
+        assert_eq!(contents, "kept");
+    }
+
+    #[test]
+    fn logins_are_locked_after_too_many_wrong_passwords() {
+        let mut alice = user("alice");
+        alice.password_hash = bcrypt::hash("secret12", 4).unwrap();
+        let mut user_manager = UserManager {
+            users: vec![alice],
+            ..Default::default()
+        };
+        user_manager.set_login_lock_minutes(15);
+        let mut login = |password: &str| {
+            user_manager
+                .user_login_mut("alice".to_owned(), password.to_owned())
+                .map(|_| ())
+        };
+        for _ in 0..UserManager::MAX_FAILED_LOGINS {
+            assert!(matches!(login("wrong"), Err(LoginError::Unauthorized)));
+        }
+        // Even the right password is refused while the login is locked
+        assert!(matches!(
+            login("secret12"),
+            Err(LoginError::TooManyAttempts)
+        ));
+
+        // The counter survives a restart
+        let saved = toml::to_string(&user_manager).unwrap();
+        let mut user_manager: UserManager = toml::from_str(&saved).unwrap();
+        assert_eq!(
+            user_manager.users()[0].failed_logins,
+            UserManager::MAX_FAILED_LOGINS
+        );
+        assert!(matches!(
+            user_manager.user_login_mut("alice".to_owned(), "secret12".to_owned()),
+            Err(LoginError::TooManyAttempts)
+        ));
+
+        // Once the lock expired, one wrong password does not lock the login again
+        user_manager.find_mut("alice").unwrap().last_failed_login =
+            Some(chrono::Local::now() - chrono::Duration::minutes(16));
+        assert!(matches!(
+            user_manager.user_login_mut("alice".to_owned(), "wrong".to_owned()),
+            Err(LoginError::Unauthorized)
+        ));
+        assert_eq!(user_manager.users()[0].failed_logins, 1);
+        assert!(user_manager
+            .user_login_mut("alice".to_owned(), "secret12".to_owned())
+            .is_ok());
+        assert_eq!(user_manager.users()[0].failed_logins, 0);
+    }
+
+    #[test]
+    fn admins_can_unlock_a_locked_login() {
+        let mut alice = user("alice");
+        alice.password_hash = bcrypt::hash("secret12", 4).unwrap();
+        alice.failed_logins = UserManager::MAX_FAILED_LOGINS;
+        alice.last_failed_login = Some(chrono::Local::now());
+        let mut user_manager = UserManager {
+            users: vec![alice],
+            ..Default::default()
+        };
+        // Without lock minutes the login stays locked until an admin unlocks it
+        user_manager.set_login_lock_minutes(0);
+        assert!(matches!(
+            user_manager.user_login_mut("alice".to_owned(), "secret12".to_owned()),
+            Err(LoginError::TooManyAttempts)
+        ));
+        assert!(user_manager.unlock("alice"));
+        assert!(!user_manager.unlock("bob"));
+        assert!(user_manager
+            .user_login_mut("alice".to_owned(), "secret12".to_owned())
+            .is_ok());
+    }
+
+    #[test]
+    fn reset_codes_are_checked_and_used_once() {
+        let mut user_manager = UserManager {
+            users: vec![user("alice")],
+            ..Default::default()
+        };
+        let code = user_manager.issue_reset_code("alice").unwrap();
+        let mut reset = |username: &str, code: &str, password: &str| {
+            user_manager.reset_password(username, code, password)
+        };
+        assert!(matches!(
+            reset("alice", "wrong", "new-secret-34"),
+            Err(ResetError::InvalidCode)
+        ));
+        assert!(matches!(
+            reset("bob", &code, "new-secret-34"),
+            Err(ResetError::InvalidCode)
+        ));
+        // A weak password is refused without using the code
+        assert!(matches!(
+            reset("alice", &code, "password"),
+            Err(ResetError::Password(PasswordError::TooCommon))
+        ));
+        assert!(reset("alice", &code, "new-secret-34").is_ok());
+        assert!(matches!(
+            reset("alice", &code, "other-secret-56"),
+            Err(ResetError::InvalidCode)
+        ));
+        let alice = user_manager.find("alice").unwrap();
+        assert!(alice.verify_password("new-secret-34"));
+        assert!(!alice.must_change_password());
+    }
+
+    #[test]
+    fn expired_reset_codes_are_refused() {
+        let mut user_manager = UserManager {
+            users: vec![user("alice")],
+            ..Default::default()
+        };
+        let code = user_manager.issue_reset_code("alice").unwrap();
+        user_manager
+            .find_mut("alice")
+            .unwrap()
+            .password_reset_expires_at = Some(chrono::Local::now() - chrono::Duration::minutes(1));
+        assert!(matches!(
+            user_manager.reset_password("alice", &code, "new-secret-34"),
+            Err(ResetError::InvalidCode)
+        ));
+        assert!(!user_manager
+            .find("alice")
+            .unwrap()
+            .verify_password("new-secret-34"));
+    }
+
+    #[test]
+    fn returns_put_back_the_stock_their_checkout_took() {
+        let mut order_manager = order_manager(vec![order(
+            0,
+            vec![item("A1", 10.0, 2.5)],
+            delivered(OrderPayment::Cash),
+        )]);
+        // Checkout took 3 units for 2.5, five returns of 0.5 give back those 3 and no more
+        let restocked: Vec<i64> = (0..5)
+            .map(|_| {
+                refund(&mut order_manager, 0, "A1", 0.5);
+                let return_id = order_manager.returns.last().unwrap().return_id();
+                order_manager.restock_quantity(return_id).unwrap()
+            })
+            .collect();
+        assert_eq!(restocked, [1, 0, 1, 0, 1]);
+
+        order_manager = self::order_manager(vec![order(
+            0,
+            vec![item("A1", 10.0, 0.75)],
+            delivered(OrderPayment::Cash),
+        )]);
+        let restocked: Vec<i64> = (0..3)
+            .map(|_| {
+                refund(&mut order_manager, 0, "A1", 0.25);
+                let return_id = order_manager.returns.last().unwrap().return_id();
+                order_manager.restock_quantity(return_id).unwrap()
+            })
+            .collect();
+        // The one unit taken for 0.75 only goes back with the last of it
+        assert_eq!(restocked, [0, 0, 1]);
+    }
+
+    #[test]
+    fn bcrypt_costs_that_would_hang_are_refused() {
+        // Only costs that are refused are tried, other tests hash with the current cost
+        assert!(!set_bcrypt_cost(*BCRYPT_COSTS.start() - 1));
+        assert!(!set_bcrypt_cost(*BCRYPT_COSTS.end() + 1));
+        assert!(!set_bcrypt_cost(31));
+        assert!(BCRYPT_COSTS.contains(&bcrypt_cost()));
     }
 }
@@ -0,0 +1,225 @@
+use crate::model::*;
+
+/// The spending and habits of one user over all their orders
+pub(crate) struct UserSummary {
+    username: String,
+    /// The money spent on paid orders, without tips
+    total_spend: f64,
+    paid_orders: usize,
+    /// How many orders are in each state, every state is listed even without orders
+    orders_by_state: [(&'static str, usize); 5],
+    /// The codes, names and bought quantities of the most bought products, most first
+    top_products: Vec<(String, String, f64)>,
+}
+
+impl UserSummary {
+    /// How many of the most bought products are kept
+    pub(crate) const TOP_PRODUCTS: usize = 5;
+
+    pub(crate) fn username(&self) -> &str {
+        self.username.as_ref()
+    }
+
+    pub(crate) fn total_spend(&self) -> f64 {
+        self.total_spend
+    }
+
+    pub(crate) fn paid_orders(&self) -> usize {
+        self.paid_orders
+    }
+
+    /// The average spend of a paid order, `None` without paid orders
+    pub(crate) fn average_order_value(&self) -> Option<f64> {
+        (self.paid_orders > 0).then(|| round_money(self.total_spend / self.paid_orders as f64))
+    }
+
+    pub(crate) fn orders_by_state(&self) -> &[(&'static str, usize)] {
+        &self.orders_by_state
+    }
+
+    pub(crate) fn top_products(&self) -> &[(String, String, f64)] {
+        &self.top_products
+    }
+}
+
+/// Summarize the orders of the user.
+///
+/// Like the [`CustomerReport`], only paid orders count for the spend and the most bought
+/// products, and tips are left out of the spend. Products bought as much as each other are
+/// sorted by code.
+pub(crate) fn user_summary(order_manager: &OrderManager, user: &User) -> UserSummary {
+    let mut orders_by_state = [
+        ("open", 0),
+        ("awaiting-delivery", 0),
+        ("closed", 0),
+        ("delivered", 0),
+        ("cancelled", 0),
+    ];
+    let mut total_spend = 0.0;
+    let mut paid_orders = 0;
+    let mut top_products: Vec<(String, String, f64)> = Vec::new();
+    for order in order_manager
+        .orders()
+        .iter()
+        .filter(|order| order.username() == user.username())
+    {
+        let state = match order.state() {
+            OrderState::Open => 0,
+            OrderState::AwaitingDelivery => 1,
+            OrderState::Closed { .. } => 2,
+            OrderState::Delivered { .. } => 3,
+            OrderState::Cancelled { .. } => 4,
+        };
+        orders_by_state[state].1 += 1;
+        if order.payment().is_none() {
+            continue;
+        }
+        paid_orders += 1;
+        total_spend = round_money(total_spend + order.total_price() - order.tip());
+        for item in order.items() {
+            match top_products
+                .iter_mut()
+                .find(|(code, _, _)| code == item.code())
+            {
+                Some((_, _, quantity)) => *quantity += item.quantity(),
+                None => top_products.push((
+                    item.code().to_owned(),
+                    item.name().to_owned(),
+                    item.quantity(),
+                )),
+            }
+        }
+    }
+    top_products.sort_by(|a, b| b.2.total_cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    top_products.truncate(UserSummary::TOP_PRODUCTS);
+    UserSummary {
+        username: user.username().to_owned(),
+        total_spend,
+        paid_orders,
+        orders_by_state,
+        top_products,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// An order of alice for the given codes and quantities, at 10 per unit
+    fn order(items: &[(&str, f64)], state: Value, tip: f64) -> Value {
+        let items: Vec<Value> = items
+            .iter()
+            .map(|(code, quantity)| {
+                json!({"code": code, "name": code, "price": 10.0, "quantity": quantity})
+            })
+            .collect();
+        json!({
+            "order_id": 0,
+            "username": "alice",
+            "delivery_address": "Street 1",
+            "items": items,
+            "state": state,
+            "tip": tip,
+        })
+    }
+
+    fn paid() -> Value {
+        json!({"order_state": "delivered", "state": {"payment": {"payment_method": "cash"}}})
+    }
+
+    fn summary(orders: Vec<Value>) -> UserSummary {
+        let order_manager: OrderManager =
+            serde_json::from_value(json!({"orders": orders, "sequence_id": orders.len()})).unwrap();
+        let alice: User = serde_json::from_value(json!({
+            "username": "alice",
+            "password_hash": "",
+            "email": "alice@example.com",
+            "cart": [],
+        }))
+        .unwrap();
+        user_summary(&order_manager, &alice)
+    }
+
+    #[test]
+    fn the_spend_leaves_out_tips_and_unpaid_orders() {
+        let summary = summary(vec![
+            order(&[("A1", 2.0)], paid(), 3.0),
+            order(&[("A1", 1.0)], paid(), 0.0),
+            order(&[("A1", 5.0)], json!({"order_state": "open"}), 0.0),
+        ]);
+        assert_eq!(summary.total_spend(), 30.0);
+        assert_eq!(summary.paid_orders(), 2);
+        assert_eq!(summary.average_order_value(), Some(15.0));
+        assert_eq!(summary.top_products()[0].2, 3.0);
+    }
+
+    #[test]
+    fn orders_are_counted_by_state() {
+        let mut bob = order(&[("A1", 1.0)], paid(), 0.0);
+        bob["username"] = json!("bob");
+        let summary = summary(vec![
+            order(&[("A1", 1.0)], json!({"order_state": "open"}), 0.0),
+            order(&[("A1", 1.0)], json!({"order_state": "open"}), 0.0),
+            order(
+                &[("A1", 1.0)],
+                json!({"order_state": "awaiting_delivery"}),
+                0.0,
+            ),
+            order(&[("A1", 1.0)], paid(), 0.0),
+            order(
+                &[("A1", 1.0)],
+                json!({"order_state": "cancelled", "state": {"reason": "changed my mind"}}),
+                0.0,
+            ),
+            bob,
+        ]);
+        assert_eq!(
+            summary.orders_by_state(),
+            [
+                ("open", 2),
+                ("awaiting-delivery", 1),
+                ("closed", 0),
+                ("delivered", 1),
+                ("cancelled", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_five_most_bought_products_are_kept() {
+        let summary = summary(vec![
+            order(&[("F6", 1.0), ("B2", 2.0), ("A1", 2.0)], paid(), 0.0),
+            order(&[("C3", 4.0), ("D4", 1.0), ("E5", 3.0)], paid(), 0.0),
+            order(&[("F6", 2.0)], paid(), 0.0),
+        ]);
+        let top: Vec<(&str, f64)> = summary
+            .top_products()
+            .iter()
+            .map(|(code, _, quantity)| (code.as_str(), *quantity))
+            .collect();
+        // Ties are sorted by code, D4 is left out
+        assert_eq!(
+            top,
+            [
+                ("C3", 4.0),
+                ("E5", 3.0),
+                ("F6", 3.0),
+                ("A1", 2.0),
+                ("B2", 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn there_is_no_average_without_paid_orders() {
+        let summary = summary(vec![order(
+            &[("A1", 1.0)],
+            json!({"order_state": "open"}),
+            0.0,
+        )]);
+        assert_eq!(summary.total_spend(), 0.0);
+        assert_eq!(summary.average_order_value(), None);
+        assert!(summary.top_products().is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::model::*;
+use crate::{model::*, reports::UserSummary};
 
 /// Prettry print the class to the user.
 pub(crate) trait View {
@@ -6,16 +6,148 @@ pub(crate) trait View {
     fn view(&self);
 }
 
+/// Print the name of the store in a banner, at startup and at the top of receipts.
+pub(crate) fn print_banner() {
+    let name = store_name();
+    let line = "=".repeat(name.chars().count() + 8);
+    println!("{line}");
+    println!("    {name}");
+    println!("{line}");
+}
+
+/// Print a product as a line of the catalog with its index, which `cart add` accepts
+fn print_catalog_entry(idx: usize, product: &Product) {
+    let code = product.code();
+    let name = product.name();
+    let unit_price = product.unit_price();
+    if product.is_out_of_stock() {
+        println!(
+            "{idx:>3}. [{code}] {name} - {unit_price} (out of stock)",
+            unit_price = format_price(unit_price)
+        );
+    } else {
+        println!(
+            "{idx:>3}. [{code}] {name} - {unit_price}",
+            unit_price = format_price(unit_price)
+        );
+    }
+    if let Some(image_url) = product.image_url() {
+        println!("     image: {image_url}");
+    }
+}
+
 impl View for Catalog {
+    /// Print the featured products first, then every product
     fn view(&self) {
+        let products = self.products();
+        if products.iter().any(|product| product.is_featured()) {
+            println!("Featured:");
+            products
+                .iter()
+                .enumerate()
+                .filter(|(_, product)| product.is_featured())
+                .for_each(|(i, product)| print_catalog_entry(i + 1, product));
+        }
         println!("Catalog:");
-        self.products().iter().enumerate().for_each(|(i, product)| {
-            let idx = i + 1;
-            let code = product.code();
-            let name = product.name();
-            let unit_price = product.unit_price();
-            println!("{idx:>3}. [{code}] {name} - {unit_price:.2} EGP")
-        });
+        products
+            .iter()
+            .enumerate()
+            .for_each(|(i, product)| print_catalog_entry(i + 1, product));
+    }
+}
+
+/// Print some products of the catalog like the catalog does, with their index in the catalog
+pub(crate) fn print_catalog_products(catalog: &Catalog, products: &[&Product]) {
+    let all = catalog.products();
+    for product in products {
+        if let Some(i) = all.iter().position(|other| other.code() == product.code()) {
+            print_catalog_entry(i + 1, product);
+        }
+    }
+}
+
+/// The details of a product as labels and values, `None` for the ones the product does not have
+fn product_details(product: &Product) -> [(&'static str, Option<String>); 9] {
+    [
+        ("name", Some(product.name().to_owned())),
+        ("price", Some(format_price(product.unit_price()))),
+        ("unit", Some(product.unit().to_owned())),
+        ("barcode", product.barcode().map(str::to_owned)),
+        ("stock", product.stock().map(|stock| stock.to_string())),
+        (
+            "weight",
+            product.weight_grams().map(|weight| format!("{weight} g")),
+        ),
+        ("image", product.image_url().map(str::to_owned)),
+        (
+            "reorder at",
+            product.reorder_point().map(|point| point.to_string()),
+        ),
+        (
+            "tax",
+            Some(
+                if product.is_tax_exempt() {
+                    "exempt"
+                } else {
+                    "taxable"
+                }
+                .to_owned(),
+            ),
+        ),
+    ]
+}
+
+impl View for Product {
+    fn view(&self) {
+        println!("Product [{}]", self.code());
+        for (label, value) in product_details(self) {
+            if let Some(value) = value {
+                println!("  {label}: {value}");
+            }
+        }
+        if !self.price_history().is_empty() {
+            println!("  price history:");
+            for change in self.price_history() {
+                println!(
+                    "  - {} {} -> {}",
+                    change.timestamp().format("%Y-%m-%d %H:%M"),
+                    format_price(change.old_price()),
+                    format_price(change.new_price())
+                );
+            }
+        }
+    }
+}
+
+/// Print the details of two products next to each other, `-` for details a product does not have
+pub(crate) fn print_product_comparison(first: &Product, second: &Product) {
+    let header = (
+        "",
+        format!("[{}]", first.code()),
+        format!("[{}]", second.code()),
+    );
+    let rows: Vec<_> = product_details(first)
+        .into_iter()
+        .zip(product_details(second))
+        .map(|((label, first), (_, second))| {
+            let first = first.unwrap_or_else(|| "-".to_owned());
+            let second = second.unwrap_or_else(|| "-".to_owned());
+            (label, first, second)
+        })
+        .collect();
+    let label_width = rows
+        .iter()
+        .map(|(label, ..)| label.len())
+        .max()
+        .unwrap_or(0);
+    let first_width = rows
+        .iter()
+        .chain([&header])
+        .map(|(_, first, _)| first.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (label, first, second) in [&header].into_iter().chain(&rows) {
+        println!("  {label:<label_width$}  {first:<first_width$}  {second}");
     }
 }
 
@@ -26,9 +158,29 @@ impl View for Cart {
             println!("{}x {}", item.quantity(), item.name())
         }
 
-        let total_cost: f64 = self.iter().map(|item| item.total_price()).sum();
+        println!("Total cost: {}", format_price(self.total_price()));
+    }
+}
 
-        println!("Total cost: {total_cost:.2}");
+impl View for User {
+    fn view(&self) {
+        println!("User {}", self.username());
+        match self.registered_at() {
+            Some(registered_at) => println!("  registered: {}", registered_at.format("%Y-%m-%d")),
+            None => println!("  registered: unknown"),
+        }
+        match self.last_login() {
+            Some(last_login) => println!("  last login: {}", last_login.format("%Y-%m-%d %H:%M")),
+            None => println!("  last login: never"),
+        }
+        println!("  email: {}", self.email());
+        if self.phone().is_empty() {
+            println!("  phone: (not set)");
+        } else {
+            println!("  phone: {}", self.phone());
+        }
+        println!("  loyalty points: {}", self.points());
+        println!("  wallet: {}", format_price(self.wallet_balance()));
     }
 }
 
@@ -36,23 +188,232 @@ impl View for Order {
     fn view(&self) {
         println!("Order #{}", self.order_id());
         println!("  for user: {}", self.username());
+        if let Some(created_at) = self.created_at() {
+            println!("  placed: {}", created_at.format("%Y-%m-%d %H:%M"));
+        }
         println!("  deliver to: {}", self.delivery_address());
-        println!("  costs: {:.2} EGP", self.total_price());
+        if let Some(notes) = self.notes() {
+            println!("  notes: {notes}");
+        }
+        if let Some(window) = self.delivery_window() {
+            println!("  delivery window: {} ({})", window.date(), window.slot());
+        }
+        if self.gift_wrap() {
+            println!("  gift wrap: {}", format_price(Order::GIFT_WRAP_FEE));
+        }
+        if let Some(message) = self.gift_message() {
+            println!("  gift message: {message}");
+        }
+        if self.points_redeemed() > 0 {
+            println!(
+                "  points redeemed: {} (-{})",
+                self.points_redeemed(),
+                format_price(self.points_discount())
+            );
+        }
+        if let Some(promo) = self.promo() {
+            println!(
+                "  promo code {}: -{}",
+                promo.code(),
+                format_price(promo.discount())
+            );
+        }
+        if self.tip() > 0.0 {
+            println!("  tip: {}", format_price(self.tip()));
+        }
+        println!("  costs: {}", format_price(self.total_price()));
+        if self.total_weight_grams() > 0.0 {
+            println!("  weight: {:.3} kg", self.total_weight_grams() / 1000.0);
+        }
+        if let Some(gift_card) = self.gift_card() {
+            println!(
+                "  gift card {}: -{}",
+                gift_card.code(),
+                format_price(gift_card.amount())
+            );
+        }
         println!("  state: {}", self.state());
-        if let OrderState::Closed { payment } = self.state() {
-            println!("  pay by: {}", payment);
+        if let Some(courier) = self.courier() {
+            println!("  courier: {courier}");
+        }
+        if let OrderState::Closed { payment } | OrderState::Delivered { payment } = self.state() {
+            if let OrderPayment::Split { legs } = payment {
+                println!("  pay by:");
+                for leg in legs {
+                    println!("  - {}: {}", leg.payment(), format_price(leg.amount()));
+                }
+            } else {
+                println!("  pay by: {}", payment);
+            }
         }
         println!("  items:");
         for item in self.items() {
             println!(
-                "  - {}x {} [{}] = {:.2} EGP",
+                "  - {}x {} [{}] = {}",
+                item.quantity(),
+                item.name(),
+                item.code(),
+                format_price(item.total_price())
+            );
+        }
+        if !self.history().is_empty() {
+            println!("  history:");
+        }
+        for event in self.history() {
+            println!(
+                "  - {} {}",
+                event.timestamp().format("%Y-%m-%d %H:%M"),
+                event.description()
+            );
+        }
+    }
+}
+
+/// Print the packing slip that is put in the box of an order.
+///
+/// Gift orders show the gift message and hide all prices from the receiver.
+pub(crate) fn print_packing_slip(order: &Order) {
+    println!("Packing slip for order #{}", order.order_id());
+    println!("  deliver to: {}", order.delivery_address());
+    if let Some(message) = order.gift_message() {
+        println!("  gift message: {message}");
+    }
+    println!("  items:");
+    for item in order.items() {
+        if order.is_gift() {
+            println!("  - {}x {} [{}]", item.quantity(), item.name(), item.code());
+        } else {
+            println!(
+                "  - {}x {} [{}] = {}",
                 item.quantity(),
                 item.name(),
                 item.code(),
-                item.total_price(),
+                format_price(item.total_price())
             );
         }
     }
+    if !order.is_gift() {
+        println!("  total: {}", format_price(order.total_price()));
+    }
+}
+
+/// Escape the characters that have a meaning in HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write the invoice of a paid order as an HTML page.
+///
+/// The invoice has the store, the customer, every item and the parts of the total, like the
+/// receipt but meant to be kept as a file or printed.
+pub(crate) fn invoice_html(order: &Order, customer: Option<&User>, store_name: &str) -> String {
+    use std::fmt::Write;
+
+    let order_id = order.order_id();
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>").ok();
+    writeln!(html, "<html>").ok();
+    writeln!(html, "<head>").ok();
+    writeln!(html, "<meta charset=\"utf-8\">").ok();
+    writeln!(html, "<title>Invoice for order #{order_id}</title>").ok();
+    writeln!(html, "</head>").ok();
+    writeln!(html, "<body>").ok();
+    writeln!(html, "<h1>{}</h1>", escape_html(store_name)).ok();
+    writeln!(html, "<h2>Invoice for order #{order_id}</h2>").ok();
+    if let Some(created_at) = order.created_at() {
+        writeln!(html, "<p>Date: {}</p>", created_at.format("%Y-%m-%d")).ok();
+    }
+
+    write!(html, "<p>Customer: {}", escape_html(order.username())).ok();
+    if let Some(customer) = customer {
+        write!(html, " &lt;{}&gt;", escape_html(customer.email())).ok();
+    }
+    writeln!(html, "<br>").ok();
+    writeln!(
+        html,
+        "Deliver to: {}</p>",
+        escape_html(order.delivery_address())
+    )
+    .ok();
+
+    writeln!(html, "<table>").ok();
+    writeln!(
+        html,
+        "<tr><th>Item</th><th>Code</th><th>Quantity</th><th>Unit price</th><th>Amount</th></tr>"
+    )
+    .ok();
+    for item in order.items() {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape_html(item.name()),
+            escape_html(item.code()),
+            item.quantity(),
+            escape_html(&format_price(item.unit_price())),
+            escape_html(&format_price(item.total_price())),
+        )
+        .ok();
+    }
+    writeln!(html, "</table>").ok();
+
+    let totals = order.totals();
+    let mut rows = vec![("Subtotal".to_owned(), totals.items)];
+    if totals.gift_wrap > 0.0 {
+        rows.push(("Gift wrap".to_owned(), totals.gift_wrap));
+    }
+    if totals.points_discount > 0.0 {
+        rows.push(("Loyalty points".to_owned(), -totals.points_discount));
+    }
+    if let Some(promo) = order.promo() {
+        rows.push((
+            format!("Promo code {}", promo.code()),
+            -totals.promo_discount,
+        ));
+    }
+    if totals.tip > 0.0 {
+        rows.push(("Tip".to_owned(), totals.tip));
+    }
+    writeln!(html, "<table>").ok();
+    for (label, amount) in rows {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            escape_html(&label),
+            escape_html(&format_price(amount))
+        )
+        .ok();
+    }
+    writeln!(
+        html,
+        "<tr><th>Total</th><th>{}</th></tr>",
+        escape_html(&format_price(totals.total))
+    )
+    .ok();
+    writeln!(html, "</table>").ok();
+
+    if let Some(gift_card) = order.gift_card() {
+        writeln!(
+            html,
+            "<p>Gift card {}: {}</p>",
+            escape_html(gift_card.code()),
+            escape_html(&format_price(gift_card.amount()))
+        )
+        .ok();
+    }
+    if let Some(payment) = order.payment() {
+        writeln!(
+            html,
+            "<p>Payment: {}</p>",
+            escape_html(&payment.to_string())
+        )
+        .ok();
+    }
+    writeln!(html, "</body>").ok();
+    writeln!(html, "</html>").ok();
+    html
 }
 
 impl View for OrderManager {
@@ -60,3 +421,196 @@ impl View for OrderManager {
         self.orders().iter().for_each(View::view);
     }
 }
+
+impl View for ReturnRequest {
+    fn view(&self) {
+        println!(
+            "Return #{} for order #{}: {}x [{}] - {}",
+            self.return_id(),
+            self.order_id(),
+            self.quantity(),
+            self.code(),
+            self.status(),
+        );
+        println!("  by user: {}", self.username());
+        println!("  reason: {}", self.reason());
+    }
+}
+
+impl View for CardRegistry {
+    fn view(&self) {
+        println!("Cards:");
+        for (card_number, status) in self.cards() {
+            println!("  {}: {status}", mask_card_number(card_number));
+        }
+        if self.accept_unknown_cards() {
+            println!("Unknown cards are accepted.");
+        } else {
+            println!("Unknown cards are rejected.");
+        }
+    }
+}
+
+impl View for GiftCardManager {
+    fn view(&self) {
+        println!("Gift cards:");
+        for gift_card in self.gift_cards() {
+            print!(
+                "  {}: {} of {} left",
+                gift_card.code(),
+                format_money(gift_card.balance()),
+                format_price(gift_card.initial_value())
+            );
+            if gift_card.is_used_up() {
+                print!(" (used up)");
+            }
+            println!();
+        }
+    }
+}
+
+impl View for PromoCodeManager {
+    fn view(&self) {
+        println!("Promo codes:");
+        for promo_code in self.promo_codes() {
+            print!("  {}: {}% off", promo_code.code(), promo_code.percent_off());
+            if let Some(expires_on) = promo_code.expires_on() {
+                print!(", until {expires_on}");
+            }
+            match promo_code.max_uses() {
+                Some(max_uses) => print!(", used {} of {max_uses} times", promo_code.uses()),
+                None => print!(", used {} times", promo_code.uses()),
+            }
+            println!();
+        }
+    }
+}
+
+impl View for Receipt {
+    fn view(&self) {
+        print_banner();
+        println!(
+            "Receipt #{} - {}",
+            self.receipt_number(),
+            self.issued_at().format("%Y-%m-%d %H:%M")
+        );
+        if let Some(refund_of) = self.refund_of() {
+            println!("  refund of receipt #{refund_of}");
+        }
+        println!("  order #{} for user {}", self.order_id(), self.username());
+        if let Some(notes) = self.notes() {
+            println!("  notes: {notes}");
+        }
+        if let Some(window) = self.delivery_window() {
+            println!("  delivery window: {} ({})", window.date(), window.slot());
+        }
+        if let Some(message) = self.gift_message() {
+            println!("  gift message: {message}");
+        }
+        for line in self.lines() {
+            println!(
+                "  - {} = {}",
+                line.description(),
+                format_price(line.amount())
+            );
+        }
+        println!("  total: {}", format_price(self.total()));
+        println!("  payment: {}", self.payment());
+    }
+}
+
+impl View for TransactionLog {
+    fn view(&self) {
+        println!("Transactions:");
+        self.transactions().iter().for_each(View::view);
+        println!("Balance: {}", format_price(self.balance()));
+    }
+}
+
+impl View for Transaction {
+    fn view(&self) {
+        let sign = if self.amount() < 0.0 { "" } else { "+" };
+        print!(
+            "  #{} {} {} {}: {sign}{}",
+            self.transaction_id(),
+            self.at().format("%Y-%m-%d %H:%M"),
+            self.kind(),
+            self.username(),
+            format_price(self.amount()),
+        );
+        if !self.method().is_empty() {
+            print!(" by {}", self.method());
+        }
+        if let Some(order_id) = self.order_id() {
+            print!(" for order #{order_id}");
+        }
+        println!();
+    }
+}
+
+impl View for ActivityEntry {
+    fn view(&self) {
+        print!(
+            "  {} {} {}",
+            self.at().format("%Y-%m-%d %H:%M"),
+            self.username(),
+            self.kind()
+        );
+        if !self.detail().is_empty() {
+            print!(": {}", self.detail());
+        }
+        println!();
+    }
+}
+
+impl View for UserSummary {
+    fn view(&self) {
+        println!("Stats of {}", self.username());
+        println!(
+            "  spent: {} in {} paid order(s)",
+            format_price(self.total_spend()),
+            self.paid_orders()
+        );
+        if let Some(average) = self.average_order_value() {
+            println!("  average order: {}", format_price(average));
+        }
+        println!("  orders:");
+        for (state, count) in self.orders_by_state() {
+            println!("    {state}: {count}");
+        }
+        if !self.top_products().is_empty() {
+            println!("  most bought:");
+            for (code, name, quantity) in self.top_products() {
+                println!("    {quantity}x [{code}] {name}");
+            }
+        }
+    }
+}
+
+impl View for RevenueReport {
+    fn view(&self) {
+        println!("Revenue:");
+        println!("  sales: {}", format_price(self.sales));
+        println!("  refunds: -{}", format_price(self.refunds));
+        println!("  net: {}", format_price(self.net));
+    }
+}
+
+impl View for CustomerReport {
+    fn view(&self) {
+        println!("Top customers:");
+        for (i, customer) in self.customers().iter().enumerate() {
+            print!(
+                "{:>3}. {} - {} in {} order(s)",
+                i + 1,
+                customer.username(),
+                format_price(customer.total_spend()),
+                customer.order_count()
+            );
+            if customer.total_tips() > 0.0 {
+                print!(", {} in tips", format_price(customer.total_tips()));
+            }
+            println!();
+        }
+    }
+}
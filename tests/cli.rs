@@ -0,0 +1,165 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A new empty directory for one test to run the application in
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("corona-{name}-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run the application in `dir` with the given arguments and input piped to stdin
+fn run_with(dir: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_corona"))
+        .args(args)
+        .current_dir(dir)
+        .env("CORONA_AUDIT_LOG", dir.join("audit.log"))
+        .env("CORONA_BCRYPT_COST", "4")
+        .env("CORONA_OUTBOX", dir.join("outbox.txt"))
+        .env_remove("CORONA_FILE")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The application may stop before it reads the input, like when the data file is invalid
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+/// Run the application in `dir` with the given input piped to stdin.
+///
+/// Returns what it printed to stdout and to stderr.
+fn run_in(dir: &Path, input: &str) -> (String, String) {
+    let output = run_with(dir, &[], input);
+    (
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+/// The input that registers a user with the password "secret12"
+fn register(username: &str) -> String {
+    format!("register\n{username}\nsecret12\n{username}@example.com\n01234567890\n")
+}
+
+#[test]
+fn piped_passwords_are_read_with_a_single_warning() {
+    let dir = test_dir("piped-passwords");
+    let input = format!("{}login\nalice\nsecret12\nq\nq\n", register("alice"));
+    let (stdout, stderr) = run_in(&dir, &input);
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(stdout.contains("(alice) >>>"), "{stdout}");
+    assert_eq!(
+        stderr
+            .matches("Warning: input is not a terminal, passwords will not be hidden.")
+            .count(),
+        1,
+        "{stderr}"
+    );
+}
+
+#[test]
+fn privileged_commands_are_limited_to_their_roles() {
+    // The roles that may use each command, in the order admin, staff, courier, customer
+    let table: &[(&str, [bool; 4])] = &[
+        ("cart", [true, true, true, true]),
+        ("deliveries", [true, true, true, false]),
+        ("report stock", [true, true, false, false]),
+        ("order assign 0 cody", [true, true, false, false]),
+        ("users", [true, false, false, false]),
+        ("transactions", [true, false, false, false]),
+        ("product price A1 5", [true, false, false, false]),
+        ("save as export.toml", [true, false, false, false]),
+    ];
+    let users = ["boss", "sam", "cody", "cat"];
+
+    let dir = test_dir("permissions");
+    let mut setup: String = users.iter().map(|username| register(username)).collect();
+    setup.push_str("login\nboss\nsecret12\nuser role sam staff\nuser role cody courier\nq\nq\n");
+    run_in(&dir, &setup);
+    for (i, username) in users.iter().enumerate() {
+        let mut input = format!("login\n{username}\nsecret12\n");
+        for (command, _) in table {
+            input.push_str(command);
+            input.push('\n');
+        }
+        input.push_str("q\nq\n");
+        let (stdout, _) = run_in(&dir, &input);
+        // What each command printed is between the prompts of the logged in menu
+        let outputs: Vec<&str> = stdout
+            .split(&format!("({username}) >>> "))
+            .skip(1)
+            .collect();
+        assert!(
+            outputs.len() > table.len(),
+            "{username} could not log in: {stdout}"
+        );
+        for ((command, allowed), output) in table.iter().zip(outputs) {
+            let refused = output.contains("I don't understand what you are saying!!!");
+            assert_eq!(!refused, allowed[i], "{username} ran {command}: {output}");
+        }
+    }
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn a_corrupted_data_file_is_not_overwritten() {
+    let dir = test_dir("corrupted");
+    let corrupted = "schema_version = 4\nusers = [\n";
+    std::fs::write(dir.join("corona.toml"), corrupted).unwrap();
+
+    let output = run_with(&dir, &[], &format!("{}q\n", register("alice")));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    // The error of the parser points at the line
+    assert!(stderr.contains("could not load corona.toml"), "{stderr}");
+    assert!(stderr.contains("line 2"), "{stderr}");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("corona.toml")).unwrap(),
+        corrupted
+    );
+    assert!(!dir.join("corona.users.toml").exists());
+
+    // Starting over on purpose keeps the old file as a backup
+    let output = run_with(&dir, &["--force-new"], "q\n");
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("corona.toml.bak")).unwrap(),
+        corrupted
+    );
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn forgot_password_prints_the_same_whether_the_account_exists() {
+    let dir = test_dir("forgot-password");
+    run_in(&dir, &format!("{}q\n", register("alice")));
+    let forgot = |username: &str, email: &str, code: &str| {
+        let input = format!("forgot password\n{username}\n{email}\n{code}\nnew-secret-34\nq\n");
+        run_in(&dir, &input).0
+    };
+
+    let mismatch = forgot("alice", "bob@example.com", "wrongcode");
+    assert!(!dir.join("outbox.txt").exists());
+    let unknown = forgot("bob", "bob@example.com", "wrongcode");
+    let matched = forgot("alice", "alice@example.com", "wrongcode");
+    assert_eq!(matched, mismatch);
+    assert_eq!(matched, unknown);
+    assert!(!matched.contains("alice@example.com"), "{matched}");
+
+    // The code only goes to the outbox, and resets the password
+    let outbox = std::fs::read_to_string(dir.join("outbox.txt")).unwrap();
+    let code = outbox
+        .split("Your reset code is ")
+        .nth(1)
+        .and_then(|rest| rest.split('.').next())
+        .unwrap_or_else(|| panic!("{outbox}"));
+    assert!(!matched.contains(code), "{matched}");
+    let input = format!("reset\nalice\n{code}\nnew-secret-34\nlogin\nalice\nnew-secret-34\nq\nq\n");
+    let (stdout, _) = run_in(&dir, &input);
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(stdout.contains("(alice) >>>"), "{stdout}");
+}